@@ -0,0 +1,3 @@
+fn main() {
+    prost_build::compile_protos(&["proto/pbft.proto", "proto/pbft_v1.proto"], &["proto/"]).unwrap();
+}
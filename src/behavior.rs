@@ -1,45 +1,202 @@
-use crate::handler::{PbftHandler, PbftHandlerEvent, PbftHandlerIn};
-use crate::message::{ClientReply, ClientRequest, Commit, PrePrepare, PrePrepareSequence, Prepare};
+use crate::clock::{Clock, SystemClock};
+use crate::connectivity::{Backoff, PeerConnectivity};
+use crate::error::PbftError;
+use crate::execution_log::{ExecutionEntry, ExecutionLog};
+use crate::handler::{PbftHandler, PbftHandlerConfig, PbftHandlerEvent, PbftHandlerIn};
+use crate::message::{
+    Acknowledgement, ClientReply, ClientRequest, Commit, DecodeError, Message, NodeStatus, NonDeterministicInputs, PrePrepare,
+    PrePrepareSequence, Prepare, RejectReason, StateReply, StateRequest,
+};
+use crate::primary_selection::{PrimarySelection, RoundRobin};
+use crate::retransmission::Retransmission;
 use crate::state::State;
+use crate::state_machine::{KeyValueStore, StateMachine};
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::core::connection::ConnectionId;
 use libp2p::core::ConnectedPoint;
 use libp2p::identity::Keypair;
 use libp2p::multiaddr::Multiaddr;
-use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::{DialError, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters};
 use libp2p::PeerId;
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::error::Error;
 use std::sync::{Arc, RwLock};
-use tokio::prelude::{Async, AsyncRead, AsyncWrite};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-pub struct Pbft<TSubstream> {
+// Milliseconds to wait for a peer to acknowledge a consensus message before sending it again
+const RETRANSMISSION_TIMEOUT: u64 = 1_000;
+// Upper bound on the actions waiting to be handed to the swarm. Consensus messages beyond it are left to
+// the retransmission, responses to the peer resending the request, and dials to the backoff.
+const MAX_QUEUED_EVENTS: usize = 1_024;
+// Upper bound on the replies waiting for the client handler
+const MAX_CLIENT_REPLIES: usize = 1_024;
+// Requests the primary may have proposed but not executed yet before it answers clients with "busy"
+const MAX_IN_FLIGHT_REQUESTS: u64 = 64;
+// Messages are retransmitted until the request is executed this many sequence numbers behind. A peer that
+// still misses them catches up with a state transfer.
+const RETRANSMISSION_WINDOW: u64 = 128;
+// Upper bound on the requests sent in a single `StateReply`. A replica further behind asks again.
+const MAX_STATE_TRANSFER_ENTRIES: usize = 64;
+// How often the swarm polls the behaviour for due retransmissions and redials when nothing else wakes it
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Pbft {
     keypair: Keypair,
+    // The number of faulty replicas the cluster tolerates
+    f: usize,
+    // The configured members of the cluster, including this node, in the canonical order of the primary
+    // selection. Unlike the known addresses and connections, it never changes at runtime.
+    replicas: Vec<PeerId>,
     addresses: HashMap<PeerId, HashSet<Multiaddr>>,
     connected_peers: HashSet<PeerId>,
-    queued_events: VecDeque<NetworkBehaviourAction<PbftHandlerIn, PbftEvent>>,
+    connectivity: PeerConnectivity,
+    retransmission: Retransmission,
+    // Number of messages each peer rejected
+    rejections: HashMap<PeerId, u64>,
+    // Number of invalid messages received from each peer
+    invalid_messages: HashMap<PeerId, u64>,
+    handler_config: PbftHandlerConfig,
+    queued_events: VecDeque<NetworkBehaviourAction<PbftEvent, PbftHandler>>,
     state: State,
     pre_prepare_sequence: PrePrepareSequence,
     client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
-    _marker: std::marker::PhantomData<TSubstream>,
+    state_machine: Box<dyn StateMachine + Send>,
+    clock: Box<dyn Clock + Send>,
+    non_determinism_bounds: NonDeterminismBounds,
+    primary_selection: Box<dyn PrimarySelection + Send>,
+    max_in_flight_requests: u64,
+    execution_log: Option<ExecutionLog>,
+    // Set while this replica fetches the requests it missed from its peers
+    state_transfer: Option<StateTransfer>,
+    // Created on the first poll, so that a replica driven by the simulator never starts a timer
+    tick: Option<Delay>,
 }
 
-impl<TSubstream> Pbft<TSubstream> {
-    pub fn new(keypair: Keypair, client_replies: Arc<RwLock<VecDeque<ClientReply>>>) -> Self {
+impl Pbft {
+    pub fn new(keypair: Keypair, f: usize, client_replies: Arc<RwLock<VecDeque<ClientReply>>>) -> Self {
+        let local_peer_id = PeerId::from_public_key(&keypair.public());
         Self {
             keypair,
+            f,
+            replicas: vec![local_peer_id],
             addresses: HashMap::new(),
             connected_peers: HashSet::new(),
-            queued_events: VecDeque::with_capacity(100), // FIXME
+            connectivity: PeerConnectivity::new(Backoff::default()),
+            retransmission: Retransmission::new(RETRANSMISSION_TIMEOUT),
+            rejections: HashMap::new(),
+            invalid_messages: HashMap::new(),
+            handler_config: PbftHandlerConfig::default(),
+            queued_events: VecDeque::new(),
             state: State::new(),
             pre_prepare_sequence: PrePrepareSequence::new(),
             client_replies,
-            _marker: std::marker::PhantomData,
+            state_machine: Box::new(KeyValueStore::new()),
+            clock: Box::new(SystemClock),
+            non_determinism_bounds: NonDeterminismBounds::default(),
+            primary_selection: Box::new(RoundRobin),
+            max_in_flight_requests: MAX_IN_FLIGHT_REQUESTS,
+            execution_log: None,
+            state_transfer: None,
+            tick: None,
         }
     }
 
+    /// The other members of the cluster.
+    pub fn with_replicas(mut self, replicas: impl IntoIterator<Item = PeerId>) -> Self {
+        let mut replicas: Vec<PeerId> = replicas.into_iter().collect();
+        replicas.push(self.local_peer_id());
+        replicas.sort_by_key(|a| a.to_bytes());
+        replicas.dedup();
+        self.replicas = replicas;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Box<dyn Clock + Send>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_non_determinism_bounds(mut self, bounds: NonDeterminismBounds) -> Self {
+        self.non_determinism_bounds = bounds;
+        self
+    }
+
+    pub fn with_retransmission_timeout(mut self, timeout: u64) -> Self {
+        self.retransmission = Retransmission::new(timeout);
+        self
+    }
+
+    pub fn with_max_in_flight_requests(mut self, max_in_flight_requests: u64) -> Self {
+        self.max_in_flight_requests = max_in_flight_requests;
+        self
+    }
+
+    /// Every executed request is appended to `execution_log`.
+    pub fn with_execution_log(mut self, execution_log: ExecutionLog) -> Self {
+        self.execution_log = Some(execution_log);
+        self
+    }
+
+    pub fn with_handler_config(mut self, handler_config: PbftHandlerConfig) -> Self {
+        self.handler_config = handler_config;
+        self
+    }
+
+    pub fn with_primary_selection(mut self, primary_selection: Box<dyn PrimarySelection + Send>) -> Self {
+        self.primary_selection = primary_selection;
+        self
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.keypair.public())
+    }
+
+    #[cfg(test)]
+    pub fn current_view(&self) -> u64 {
+        self.state.current_view()
+    }
+
+    #[cfg(test)]
+    pub fn executed(&self) -> Vec<(u64, String)> {
+        self.state.executed()
+    }
+
+    pub fn status(&self) -> NodeStatus {
+        let mut connected_peers: Vec<String> = self.connected_peers.iter().map(|p| p.to_string()).collect();
+        connected_peers.sort();
+        NodeStatus {
+            peer_id: self.local_peer_id().to_string(),
+            view: self.state.current_view(),
+            primary: self.next_primary().to_string(),
+            connected_peers,
+            executed: self.state.executed_count(),
+            last_executed_sequence_number: self.state.last_executed(),
+            rejections: self.rejections.iter().map(|(peer_id, count)| (peer_id.to_string(), *count)).collect(),
+            invalid_messages: self
+                .invalid_messages
+                .iter()
+                .map(|(peer_id, count)| (peer_id.to_string(), *count))
+                .collect(),
+        }
+    }
+
+    pub fn primary(&self, view: u64, sequence_number: u64) -> PeerId {
+        self.primary_selection
+            .primary(view, sequence_number, &self.replicas)
+    }
+
+    /// The primary that should assign the next sequence number in the current view.
+    pub fn next_primary(&self) -> PeerId {
+        self.primary(self.state.current_view(), self.pre_prepare_sequence.value() + 1)
+    }
+
     pub fn has_peer(&self, peer_id: &PeerId) -> bool {
         self.connected_peers
             .iter()
-            .any(|connected_peer_id| connected_peer_id.clone() == peer_id.clone())
+            .any(|connected_peer_id| connected_peer_id == peer_id)
     }
 
     pub fn add_peer(&mut self, peer_id: &PeerId, address: &Multiaddr) {
@@ -51,13 +208,11 @@ impl<TSubstream> Pbft<TSubstream> {
             };
             addresses.insert(address.clone());
 
-            self.addresses.insert(peer_id.clone(), addresses.clone());
+            self.addresses.insert(*peer_id, addresses.clone());
         }
 
-        self.queued_events
-            .push_back(NetworkBehaviourAction::DialPeer {
-                peer_id: peer_id.clone(),
-            });
+        self.connectivity.dialing(peer_id);
+        self.dial(*peer_id);
     }
 
     pub fn add_client_request(&mut self, client_request: ClientRequest) {
@@ -66,97 +221,258 @@ impl<TSubstream> Pbft<TSubstream> {
             client_request
         );
 
+        let primary = self.next_primary();
+        if primary != self.local_peer_id() {
+            // The client sent the request to a backup, e.g. because it broadcast the request after a timeout.
+            // The primary replies to the client itself.
+            if !self.has_peer(&primary) {
+                eprintln!(
+                    "[Pbft::add_client_request] Can't forward the client request as the primary is not connected. primary: {:?}",
+                    primary
+                );
+                return;
+            }
+            println!("[Pbft::add_client_request] Forwarding the client request to the primary: {:?}", primary);
+            self.send_request(primary, Message::ClientRequest(client_request));
+            return;
+        }
+
+        self.propose(client_request);
+    }
+
+    // Assigns a sequence number to the request and starts the pre-prepare phase. Called on the primary only.
+    fn propose(&mut self, client_request: ClientRequest) {
+        // Every backup forwards the requests a client broadcasts
+        if client_request.timestamp() <= self.state.last_timestamp(&client_request.client())
+            || self.state.is_pending(&client_request)
+        {
+            println!(
+                "[Pbft::propose] The request has already been proposed. client: {}, timestamp: {}",
+                client_request.client(),
+                client_request.timestamp()
+            );
+            return;
+        }
+
+        if self.connected_peers.is_empty() {
+            eprintln!("[Pbft::propose] !!! connected_peers is empty !!! The request was dropped.");
+            return;
+        }
+
+        // Admission control: the client is told to come back later rather than queueing up behind the
+        // requests that are still being agreed on.
+        if self.in_flight_requests() >= self.max_in_flight_requests {
+            eprintln!(
+                "[Pbft::propose] the primary is saturated. in_flight_requests: {}",
+                self.in_flight_requests()
+            );
+            let reply = ClientReply::busy(self.local_peer_id(), &client_request, self.state.current_view());
+            if let Err(e) = self.push_client_reply(reply) {
+                eprintln!("[Pbft::propose] {}", e);
+            }
+            return;
+        }
+
         // In the pre-prepare phase, the primary assigns a sequence number, n, to the request
         self.pre_prepare_sequence.increment();
+        let non_deterministic = NonDeterministicInputs::propose(
+            self.clock.now_millis(),
+            self.state.current_view(),
+            self.pre_prepare_sequence.value(),
+            &client_request,
+        );
         let pre_prepare = PrePrepare::from(
             self.state.current_view(),
             self.pre_prepare_sequence.value(),
             client_request,
+            non_deterministic,
         );
 
         println!(
-            "[Pbft::add_client_request] [broadcasting the pre_prepare message] pre_prepare: {:?}",
+            "[Pbft::propose] [broadcasting the pre_prepare message] pre_prepare: {:?}",
             pre_prepare
         );
         println!(
-            "[Pbft::add_client_request] [broadcasting to the peers] connected_peers: {:?}",
+            "[Pbft::propose] [broadcasting to the peers] connected_peers: {:?}",
             self.connected_peers
         );
-        if self.connected_peers.is_empty() {
-            panic!("[Pbft::add_client_request] !!! connected_peers is empty !!!");
-        }
 
-        for peer_id in self.connected_peers.iter() {
-            self.queued_events
-                .push_back(NetworkBehaviourAction::SendEvent {
-                    peer_id: peer_id.clone(),
-                    event: PbftHandlerIn::PrePrepareRequest(pre_prepare.clone()),
-                });
-        }
+        self.broadcast(Message::PrePrepare(pre_prepare.clone()));
 
-        self.process_pre_prepare(pre_prepare).unwrap(); // TODO: error handling
+        let local_peer_id = self.local_peer_id();
+        if let Err(e) = self.process_pre_prepare(&local_peer_id, pre_prepare) {
+            eprintln!("[Pbft::propose] Failed to process the own PrePrepare. error: {}", e);
+        }
     }
 
-    fn process_pre_prepare(&mut self, pre_prepare: PrePrepare) -> Result<(), String> {
-        self.validate_pre_prepare(&pre_prepare)?;
+    fn process_pre_prepare(&mut self, sender: &PeerId, pre_prepare: PrePrepare) -> Result<(), PbftError> {
+        if self.already_executed(pre_prepare.sequence_number()) {
+            return Ok(());
+        }
+        self.validate_pre_prepare(sender, &pre_prepare)?;
         self.state.insert_pre_prepare(pre_prepare.clone());
+        self.pre_prepare_sequence.observe(pre_prepare.sequence_number());
+
+        // The PrePrepare stands in for the primary's vote, so the primary doesn't send a Prepare
+        let local_peer_id = self.local_peer_id();
+        if *sender == local_peer_id {
+            return Ok(());
+        }
 
         // If backup replica accepts the message, it enters the prepare phase by multicasting a PREPARE message to
         // all other replicas and adds both messages to its log.
         let prepare = Prepare::from(&pre_prepare);
-        self.state.insert_prepare(
-            PeerId::from_public_key(self.keypair.public()),
-            prepare.clone(),
-        );
+        self.state.insert_prepare(local_peer_id, prepare.clone());
 
         if self.connected_peers.is_empty() {
-            panic!("[Pbft::process_pre_prepare] !!! Peers not found !!!");
+            eprintln!("[Pbft::process_pre_prepare] !!! Peers not found !!!");
         }
 
-        for peer_id in self.connected_peers.iter() {
-            self.queued_events
-                .push_back(NetworkBehaviourAction::SendEvent {
-                    peer_id: peer_id.clone(),
-                    event: PbftHandlerIn::PrepareRequest(prepare.clone()),
-                })
-        }
+        self.broadcast(Message::Prepare(prepare));
         Ok(())
     }
 
-    fn validate_pre_prepare(&self, pre_prepare: &PrePrepare) -> Result<(), String> {
+    fn broadcast(&mut self, message: Message) {
+        let peers: Vec<PeerId> = self.connected_peers.iter().cloned().collect();
+        for peer_id in peers {
+            self.send_request(peer_id, message.clone());
+        }
+    }
+
+    // Sends a consensus message and keeps it until the peer acknowledges it.
+    fn send_request(&mut self, peer_id: PeerId, message: Message) {
+        self.retransmission
+            .sent(&peer_id, &message, self.clock.now_millis());
+        if self.queued_events.len() >= MAX_QUEUED_EVENTS {
+            // The message is resent once the retransmission timeout elapses
+            eprintln!("[Pbft::send_request] the event queue is full, deferred: {:?}", message);
+            return;
+        }
+        let event = match message {
+            Message::PrePrepare(m) => PbftHandlerIn::PrePrepareRequest(m),
+            Message::Prepare(m) => PbftHandlerIn::PrepareRequest(m),
+            Message::Commit(m) => PbftHandlerIn::CommitRequest(m),
+            Message::ClientRequest(m) => PbftHandlerIn::ForwardedRequest(m),
+            Message::StateRequest(m) => PbftHandlerIn::StateRequest(m),
+            Message::StateReply(m) => PbftHandlerIn::StateReply(m),
+        };
+        self.queued_events
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::Any,
+                event,
+            });
+    }
+
+    // A peer answered one of our messages with a Nack.
+    fn rejected(&mut self, peer_id: PeerId, request: Message, reason: RejectReason) {
+        eprintln!(
+            "[Pbft::rejected] peer_id: {:?}, reason: {}, request: {:?}",
+            peer_id, reason, request
+        );
+        *self.rejections.entry(peer_id).or_insert(0) += 1;
+
+        let current_view = self.state.current_view();
+        let event = match reason {
+            RejectReason::WrongView { current_view: view } if view > current_view => {
+                // Resending won't help until this replica catches up
+                self.retransmission.acked(&peer_id, &request);
+                Some(PbftEvent::StateTransferNeeded { peer_id, view })
+            }
+            RejectReason::WrongView { current_view: view } => {
+                // The peer may still move to our view, keep resending
+                Some(PbftEvent::PeerBehind { peer_id, view })
+            }
+            RejectReason::OutOfWindow { low, .. } if sequence_number(&request) < low => {
+                // The peer has already garbage collected this sequence number
+                self.retransmission.acked(&peer_id, &request);
+                Some(PbftEvent::StateTransferNeeded { peer_id, view: current_view })
+            }
+            RejectReason::OutOfWindow { .. } => Some(PbftEvent::PeerBehind { peer_id, view: current_view }),
+            // The PrePrepare may still be on its way, keep resending until the retransmission gives up. A peer
+            // that never gets it catches up with a state transfer once later requests commit.
+            RejectReason::UnknownSequence => None,
+            // The peer failed to process the message, keep resending
+            RejectReason::Unavailable => None,
+            // The peer's clock may be behind ours and catch up with the proposed timestamp, keep resending
+            RejectReason::BadNonDeterministicInputs => None,
+            // The peer will never accept this message
+            RejectReason::BadDigest | RejectReason::BadSignature | RejectReason::NotPrimary => {
+                self.retransmission.acked(&peer_id, &request);
+                None
+            }
+        };
+
+        if let Some(event) = event {
+            self.generate_event(event);
+        }
+    }
+
+    // Stops retransmitting messages up to `sequence_number`.
+    fn collect_garbage(&mut self, sequence_number: u64) {
+        println!("[Pbft::collect_garbage] sequence_number: {}", sequence_number);
+        self.retransmission.garbage_collect(sequence_number);
+    }
+
+    fn validate_pre_prepare(&self, sender: &PeerId, pre_prepare: &PrePrepare) -> Result<(), PbftError> {
         // TODO: the signatures in the request and the pre-prepare message are correct
 
+        // it is sent by the primary of view _v_ for sequence number _n_
+        let primary = self.primary(pre_prepare.view(), pre_prepare.sequence_number());
+        if sender != &primary {
+            eprintln!(
+                "[Pbft::validate_pre_prepare] The PrePrepare is not sent by the primary. sender: {:?}, primary: {:?}",
+                sender, primary
+            );
+            return Err(RejectReason::NotPrimary.into());
+        }
+
         // _d_ is the digest for _m_
-        pre_prepare.validate_digest()?;
+        if let Err(e) = pre_prepare.validate_digest() {
+            eprintln!("[Pbft::validate_pre_prepare] {}", e);
+            return Err(RejectReason::BadDigest.into());
+        }
 
         {
             // it is in view _v_
             let current_view = self.state.current_view();
             if pre_prepare.view() != current_view {
-                return Err(format!(
-                    "view number isn't matched. message: {}, state: {}",
+                eprintln!(
+                    "[Pbft::validate_pre_prepare] view number isn't matched. message: {}, state: {}",
                     pre_prepare.view(),
                     current_view
-                ));
+                );
+                return Err(RejectReason::WrongView { current_view }.into());
             }
 
             // it has not accepted a pre-prepare message for view _v_ and sequence number _n_ containing a different digest
-            match self.state.get_pre_prepare(pre_prepare) {
-                Some(stored_pre_prepare) => {
-                    if pre_prepare.digest() != stored_pre_prepare.digest() {
-                        return Err(format!("The pre-prepare key has already stored into logs and its digest dont match. message: {}, stored message: {}", pre_prepare, stored_pre_prepare));
-                    }
+            if let Some(stored_pre_prepare) = self.state.get_pre_prepare(pre_prepare) {
+                if pre_prepare.digest() != stored_pre_prepare.digest() {
+                    eprintln!("[Pbft::validate_pre_prepare] The pre-prepare key has already stored into logs and its digest dont match. message: {}, stored message: {}", pre_prepare, stored_pre_prepare);
+                    return Err(RejectReason::BadDigest.into());
                 }
-                None => {}
             }
         }
 
         // TODO: the sequence number in the pre-prepare message is between a low water mark, _h_, and a high water mark, _H_
 
+        // the non-deterministic values chosen by the primary are acceptable
+        let previous = pre_prepare
+            .sequence_number()
+            .checked_sub(1)
+            .and_then(|n| self.state.get_pre_prepare_by_key(pre_prepare.view(), n));
+        if let Err(e) = self
+            .non_determinism_bounds
+            .validate(pre_prepare, previous, self.clock.now_millis())
+        {
+            eprintln!("[Pbft::validate_pre_prepare] {}", e);
+            return Err(RejectReason::BadNonDeterministicInputs.into());
+        }
+
         Ok(())
     }
 
-    fn validate_prepare(&self, prepare: &Prepare) -> Result<(), String> {
+    fn validate_prepare(&self, prepare: &Prepare) -> Result<(), PbftError> {
         // The replicas verify whether the prepares match the pre-prepare by checking that they have the
         // same view, sequence number, and digest.
         if let Some(pre_prepare) = self
@@ -166,86 +482,200 @@ impl<TSubstream> Pbft<TSubstream> {
             if pre_prepare.digest() == prepare.digest() {
                 return Ok(());
             }
-            return Err(format!("the Prepare request doesn't match with the PrePrepare. prepare: {}, pre-prepare: {}", prepare, pre_prepare));
+            eprintln!("[Pbft::validate_prepare] the Prepare request doesn't match with the PrePrepare. prepare: {}, pre-prepare: {}", prepare, pre_prepare);
+            return Err(RejectReason::BadDigest.into());
         }
-        Err(format!(
-            "No PrePrepare that matches with the Prepare. prepare: {}",
+        eprintln!(
+            "[Pbft::validate_prepare] No PrePrepare that matches with the Prepare. prepare: {}",
             prepare
-        ))
+        );
+        Err(RejectReason::UnknownSequence.into())
     }
 
     fn prepared(&self, view: u64, sequence_number: u64) -> bool {
-        // 2f prepares from different backups that match the pre-prepare.
-        let len = self.state.prepare_len(view, sequence_number);
+        // 2f prepares from different backups that match the pre-prepare. Together with the pre-prepare they
+        // make up 2f + 1 replicas, so a Prepare from the primary doesn't count.
+        let len = self.state.prepare_len(view, sequence_number, &self.primary(view, sequence_number));
         println!("[Pbft::prepared] prepare_len: {}", len);
-        len >= 1 // TODO
+        len >= 2 * self.f
     }
 
-    fn validate_commit(&self, commit: &Commit) -> Result<(), String> {
+    fn validate_commit(&self, commit: &Commit) -> Result<(), PbftError> {
         // TODO: properly signed
 
         // the view number in the message is equal to the replica's current view
         if commit.view() != self.state.current_view() {
-            return Err(format!("The view number in the message is NOT equal to the replica's current view. Commit.view: {}, current_view: {}", commit.view(), self.state.current_view()));
+            eprintln!("[Pbft::validate_commit] The view number in the message is NOT equal to the replica's current view. Commit.view: {}, current_view: {}", commit.view(), self.state.current_view());
+            return Err(RejectReason::WrongView { current_view: self.state.current_view() }.into());
         }
 
         // TODO: the sequence number is between h and H
 
+        // the digest matches the accepted pre-prepare. A Commit that arrives before the PrePrepare is kept, but
+        // it is only counted once it matches.
+        if let Some(pre_prepare) = self
+            .state
+            .get_pre_prepare_by_key(commit.view(), commit.sequence_number())
+        {
+            if pre_prepare.digest() != commit.digest() {
+                eprintln!("[Pbft::validate_commit] the Commit request doesn't match with the PrePrepare. commit: {}, pre-prepare: {}", commit, pre_prepare);
+                return Err(RejectReason::BadDigest.into());
+            }
+        }
+
         Ok(())
     }
 
+    // The number of commits for (view, sequence_number) that match the accepted pre-prepare.
+    fn matching_commits(&self, view: u64, sequence_number: u64) -> usize {
+        match self.state.get_pre_prepare_by_key(view, sequence_number) {
+            Some(pre_prepare) => self.state.commit_len(view, sequence_number, pre_prepare.digest()),
+            None => 0,
+        }
+    }
+
     // `committed(m, v, n)` is true if and only if `prepared(m, v, n, i)` is true for all _i_ in
     // some set of `f + 1` non-faulty replicas.
     #[allow(dead_code)]
     fn committed(&self, view: u64, sequence_number: u64) -> bool {
-        let len = self.state.commit_len(view);
+        let len = self.matching_commits(view, sequence_number);
         let prepared = self.prepared(view, sequence_number);
 
         println!(
             "[Pbft::committed] commit_len: {}, prepared: {}",
             len, prepared
         );
-        prepared && len >= 1 // TODO: f + 1
+        prepared && len > self.f
     }
 
     // `committed-local(m, v, n, i)` is true if and only if `prepared(m, v, n, i)` is true and _i_
     // has accepted `2f + 1` commits (possibly including its own) from different replicas that match
     // the pre-prepare for _m_.
     fn committed_local(&self, view: u64, sequence_number: u64) -> bool {
-        let len = self.state.commit_len(view);
+        let len = self.matching_commits(view, sequence_number);
         let prepared = self.prepared(view, sequence_number);
 
         println!(
             "[Pbft::committed_local] commit_len: {}, prepared: {}",
             len, prepared
         );
-        prepared && len >= 1 // TODO: 2f + 1
+        prepared && len > 2 * self.f
     }
 }
 
-#[derive(Debug)]
-pub struct PbftFailure;
-impl Error for PbftFailure {}
+/// The range a backup accepts for the non-deterministic values proposed by the primary.
+#[derive(Clone, Debug)]
+pub struct NonDeterminismBounds {
+    // How far, in milliseconds, the proposed timestamp may be ahead of the backup's own clock. A PrePrepare
+    // can be held up by the network for any time, so a timestamp behind the clock is only required not to go
+    // back behind the previous request's.
+    pub max_clock_skew: u64,
+}
 
-impl std::fmt::Display for PbftFailure {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.write_str("pbft failure")
+impl Default for NonDeterminismBounds {
+    fn default() -> Self {
+        Self { max_clock_skew: 5_000 }
     }
 }
 
+impl NonDeterminismBounds {
+    fn validate(&self, pre_prepare: &PrePrepare, previous: Option<&PrePrepare>, now: u64) -> Result<(), String> {
+        let values = pre_prepare.non_deterministic();
+        if values.timestamp() > now.saturating_add(self.max_clock_skew) {
+            return Err(format!(
+                "The proposed timestamp is ahead of the local clock. proposed: {}, local: {}, max_clock_skew: {}",
+                values.timestamp(),
+                now,
+                self.max_clock_skew
+            ));
+        }
+        if let Some(previous) = previous {
+            if values.timestamp() < previous.non_deterministic().timestamp() {
+                return Err(format!(
+                    "The proposed timestamp is behind the previous request's. proposed: {}, previous: {}",
+                    values.timestamp(),
+                    previous.non_deterministic().timestamp()
+                ));
+            }
+        }
+
+        // The seed is derived from the request, its position in the log and the proposed timestamp, so the
+        // primary can't pick it freely
+        let expected = NonDeterministicInputs::propose(
+            values.timestamp(),
+            pre_prepare.view(),
+            pre_prepare.sequence_number(),
+            pre_prepare.client_reqeust(),
+        );
+        if values.seed() != expected.seed() {
+            return Err(format!(
+                "The proposed seed is not derived from the request. proposed: {}, expected: {}",
+                values.seed(),
+                expected.seed()
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn sequence_number(message: &Message) -> u64 {
+    match message {
+        Message::PrePrepare(m) => m.sequence_number(),
+        Message::Prepare(m) => m.sequence_number(),
+        Message::Commit(m) => m.sequence_number(),
+        Message::ClientRequest(_) | Message::StateRequest(_) | Message::StateReply(_) => 0,
+    }
+}
+
+// The entries received from the peers during a state transfer, by sequence number.
+struct StateTransfer {
+    started_at: u64,
+    entries: HashMap<u64, Vec<(PeerId, PrePrepare)>>,
+}
+
+impl StateTransfer {
+    fn new(started_at: u64) -> Self {
+        Self { started_at, entries: HashMap::new() }
+    }
+
+    fn insert(&mut self, peer_id: PeerId, entry: PrePrepare) {
+        let entries = self.entries.entry(entry.sequence_number()).or_default();
+        if !entries.iter().any(|(p, _)| *p == peer_id) {
+            entries.push((peer_id, entry));
+        }
+    }
+
+    // The entry for `sequence_number` that more than `f` peers sent.
+    fn agreed(&self, sequence_number: u64, f: usize) -> Option<PrePrepare> {
+        let entries = self.entries.get(&sequence_number)?;
+        entries
+            .iter()
+            .find(|(_, entry)| entries.iter().filter(|(_, other)| other.digest() == entry.digest()).count() > f)
+            .map(|(_, entry)| entry.clone())
+    }
+}
+
+/// Events reported to the swarm so that the rest of the node can react to them.
 #[derive(Debug)]
-pub struct PbftEvent;
+pub enum PbftEvent {
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    /// The peer sent something that could not be decoded. The offending substream has been closed.
+    InvalidMessage { peer_id: PeerId, error: DecodeError },
+    /// A peer rejected a message in a way that shows this replica is behind it, so it should fetch the
+    /// state it missed from that peer.
+    StateTransferNeeded { peer_id: PeerId, view: u64 },
+    /// A peer rejected a message in a way that shows it is behind this replica.
+    PeerBehind { peer_id: PeerId, view: u64 },
+}
 
-impl<TSubstream> NetworkBehaviour for Pbft<TSubstream>
-where
-    TSubstream: AsyncRead + AsyncWrite,
-{
-    type ProtocolsHandler = PbftHandler<TSubstream>;
+impl NetworkBehaviour for Pbft {
+    type ProtocolsHandler = PbftHandler;
     type OutEvent = PbftEvent;
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
         println!("Pbft::new_handler()");
-        PbftHandler::new()
+        PbftHandler::new(self.handler_config.clone())
     }
 
     fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -268,160 +698,472 @@ where
         }
     }
 
-    fn inject_connected(&mut self, peer_id: PeerId, connected_point: ConnectedPoint) {
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _connection_id: &ConnectionId,
+        connected_point: &ConnectedPoint,
+        _failed_addresses: Option<&Vec<Multiaddr>>,
+    ) {
         println!(
-            "[Pbft::inject_connected] peer_id: {:?}, connected_point: {:?}",
+            "[Pbft::inject_connection_established] peer_id: {:?}, connected_point: {:?}",
             peer_id, connected_point
         );
-        //        match connected_point {
-        //            ConnectedPoint::Dialer { address } => {
-        //            },
-        //            ConnectedPoint::Listener { .. } => {}
-        //        };
-        self.connected_peers.insert(peer_id);
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        println!("[Pbft::inject_connected] peer_id: {:?}", peer_id);
+        self.peer_connected(*peer_id);
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        println!("[Pbft::inject_disconnected] peer_id: {:?}", peer_id);
+        self.peer_disconnected(peer_id);
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: Option<PeerId>, _handler: Self::ProtocolsHandler, error: &DialError) {
+        println!("[Pbft::inject_dial_failure] peer_id: {:?}, error: {}", peer_id, error);
+        if let Some(peer_id) = peer_id {
+            self.peer_dial_failed(&peer_id);
+        }
+    }
+
+    fn inject_event(&mut self, peer_id: PeerId, _connection: ConnectionId, handler_event: PbftHandlerEvent) {
         println!(
-            "[Pbft::inject_connected] connected_peers: {:?}, addresses: {:?}",
-            self.connected_peers, self.addresses
+            "[Pbft::inject_event] handler_event: {:?}",
+            handler_event
         );
+        self.process_handler_event(peer_id, handler_event);
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<PbftEvent, PbftHandler>> {
+        if let Some(event) = self.pop_queued_event() {
+            println!("[Pbft::poll] event: {:?}", event);
+            return Poll::Ready(event);
+        }
+
+        // Come back once retransmissions or redials may be due
+        let tick = self.tick.get_or_insert_with(|| Delay::new(TICK_INTERVAL));
+        while tick.poll_unpin(cx).is_ready() {
+            tick.reset(TICK_INTERVAL);
+        }
+        Poll::Pending
     }
+}
 
-    fn inject_disconnected(&mut self, peer_id: &PeerId, connected_point: ConnectedPoint) {
+// The transport-independent half of the behaviour. `NetworkBehaviour` delegates to these methods so that
+// the simulator can drive a replica without a `Swarm`.
+impl Pbft {
+    pub fn peer_connected(&mut self, peer_id: PeerId) {
+        self.connected_peers.insert(peer_id);
+        self.connectivity.connected(&peer_id);
         println!(
-            "[Pbft::inject_disconnected] {:?}, {:?}",
-            peer_id, connected_point
+            "[Pbft::peer_connected] connected_peers: {:?}, addresses: {:?}",
+            self.connected_peers, self.addresses
         );
-        //        let address = match connected_point {
-        //            ConnectedPoint::Dialer { address } => address,
-        //            ConnectedPoint::Listener { local_addr: _, send_back_addr } => send_back_addr
-        //        };
+        self.generate_event(PbftEvent::PeerConnected(peer_id));
+
+        // Whatever the peer hasn't acknowledged may have been lost with the previous connection.
+        for message in self.retransmission.all(&peer_id) {
+            self.send_request(peer_id, message);
+        }
+    }
+
+    pub fn peer_disconnected(&mut self, peer_id: &PeerId) {
         self.connected_peers.remove(peer_id);
+        // The transport only lets the configured replicas connect, and their addresses are never removed,
+        // so every peer is redialled.
+        self.connectivity.disconnected(peer_id, self.clock.now_millis());
         println!(
-            "[Pbft::inject_disconnected] connected_peers: {:?}, addresses: {:?}",
+            "[Pbft::peer_disconnected] connected_peers: {:?}, addresses: {:?}",
             self.connected_peers, self.addresses
         );
+        self.generate_event(PbftEvent::PeerDisconnected(*peer_id));
     }
 
-    fn inject_node_event(&mut self, peer_id: PeerId, handler_event: PbftHandlerEvent) {
-        println!(
-            "[Pbft::inject_node_event] handler_event: {:?}",
-            handler_event
-        );
+    pub fn peer_dial_failed(&mut self, peer_id: &PeerId) {
+        self.connectivity.dial_failed(peer_id, self.clock.now_millis());
+    }
+
+    pub fn pop_queued_event(&mut self) -> Option<NetworkBehaviourAction<PbftEvent, PbftHandler>> {
+        // Redial the replicas whose backoff has elapsed
+        for peer_id in self.connectivity.due(self.clock.now_millis()) {
+            println!("[Pbft::pop_queued_event] redialling peer_id: {:?}", peer_id);
+            self.dial(peer_id);
+        }
+
+        // Resend the messages whose acknowledgement timed out
+        let now = self.clock.now_millis();
+        let peers: Vec<PeerId> = self.connected_peers.iter().cloned().collect();
+        for peer_id in peers {
+            for message in self.retransmission.due(&peer_id, now) {
+                self.send_request(peer_id, message);
+            }
+        }
+        self.queued_events.pop_front()
+    }
+
+    fn dial(&mut self, peer_id: PeerId) {
+        if self.queued_events.len() >= MAX_QUEUED_EVENTS {
+            // Redialled once the backoff elapses
+            eprintln!("[Pbft::dial] the event queue is full, deferred the dial: {:?}", peer_id);
+            self.peer_dial_failed(&peer_id);
+            return;
+        }
+        let handler = self.new_handler();
+        self.queued_events.push_back(NetworkBehaviourAction::Dial {
+            opts: DialOpts::peer_id(peer_id).build(),
+            handler,
+        });
+    }
+
+    fn generate_event(&mut self, event: PbftEvent) {
+        if self.queued_events.len() >= MAX_QUEUED_EVENTS {
+            eprintln!("[Pbft::generate_event] the event queue is full, dropped: {:?}", event);
+            return;
+        }
+        self.queued_events.push_back(NetworkBehaviourAction::GenerateEvent(event));
+    }
+
+    pub fn process_handler_event(&mut self, peer_id: PeerId, handler_event: PbftHandlerEvent) {
         match handler_event {
             PbftHandlerEvent::ProcessPrePrepareRequest {
                 request,
                 connection_id,
             } => {
                 println!(
-                    "[Pbft::inject_node_event] [PbftHandlerEvent::PrePrepareRequest] request: {:?}",
+                    "[Pbft::process_handler_event] [PbftHandlerEvent::PrePrepareRequest] request: {:?}",
                     request
                 );
-                self.process_pre_prepare(request.clone()).unwrap(); // TODO: error handling
-
-                self.queued_events
-                    .push_back(NetworkBehaviourAction::SendEvent {
-                        peer_id,
-                        event: PbftHandlerIn::PrePrepareResponse("OK".into(), connection_id),
-                    });
+                let result = self.process_pre_prepare(&peer_id, request);
+                self.respond(peer_id, result, |ack| PbftHandlerIn::PrePrepareResponse(ack, connection_id));
             }
-            PbftHandlerEvent::Response { response } => {
-                let response_message =
-                    String::from_utf8(response).expect("Failed to parse response");
+            PbftHandlerEvent::Response { request, response } => {
                 println!(
-                    "[Pbft::inject_node_event] [PbftHandlerEvent::Response] response_message: {:?}",
-                    response_message
+                    "[Pbft::process_handler_event] [PbftHandlerEvent::Response] response: {:?}",
+                    response
                 );
-                if response_message == "OK" {
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] the communications has done successfully")
-                } else {
-                    // TODO: retry?
-                    eprintln!("[Pbft::inject_node_event] [PbftHandlerEvent::Response] response_message: {:?}", response_message);
+                match response {
+                    Acknowledgement::Ack => {
+                        println!("[Pbft::process_handler_event] [PbftHandlerEvent::Response] the communications has done successfully");
+                        self.retransmission.acked(&peer_id, &request);
+                    }
+                    Acknowledgement::Nack(reason) => self.rejected(peer_id, request, reason),
+                }
+            }
+            PbftHandlerEvent::QueueFull { request } => {
+                // Not resent right away: the timeout gives the connection time to drain
+                eprintln!("[Pbft::process_handler_event] [PbftHandlerEvent::QueueFull] peer_id: {:?}, request: {:?}", peer_id, request);
+            }
+            PbftHandlerEvent::SendFailed { request } => {
+                eprintln!("[Pbft::process_handler_event] [PbftHandlerEvent::SendFailed] request: {:?}", request);
+                self.retransmission.failed(&peer_id, &request);
+            }
+            PbftHandlerEvent::InvalidMessage { error } => {
+                eprintln!("[Pbft::process_handler_event] [PbftHandlerEvent::InvalidMessage] peer_id: {:?}, error: {}", peer_id, error);
+                self.count_invalid_message(&peer_id);
+                self.generate_event(PbftEvent::InvalidMessage { peer_id, error });
+            }
+            PbftHandlerEvent::Timeout { request } => {
+                eprintln!("[Pbft::process_handler_event] [PbftHandlerEvent::Timeout] peer_id: {:?}, request: {:?}", peer_id, request);
+                if let Some(request) = request {
+                    self.retransmission.failed(&peer_id, &request);
                 }
             }
             PbftHandlerEvent::ProcessPrepareRequest {
                 request,
                 connection_id,
             } => {
-                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessPrepareRequest] request: {:?}", request);
-                self.validate_prepare(&request).unwrap();
-                self.state.insert_prepare(peer_id.clone(), request.clone());
-
-                self.queued_events
-                    .push_back(NetworkBehaviourAction::SendEvent {
-                        peer_id,
-                        event: PbftHandlerIn::PrepareResponse("OK".into(), connection_id),
-                    });
-
-                if self.prepared(request.view(), request.sequence_number()) {
-                    let commit: Commit = request.into();
-                    for p in self.connected_peers.iter() {
-                        self.queued_events
-                            .push_back(NetworkBehaviourAction::SendEvent {
-                                peer_id: p.clone(),
-                                event: PbftHandlerIn::CommitRequest(commit.clone()),
-                            })
-                    }
-                }
+                println!("[Pbft::process_handler_event] [PbftHandlerEvent::ProcessPrepareRequest] request: {:?}", request);
+                let result = self.process_prepare(&peer_id, request);
+                self.respond(peer_id, result, |ack| PbftHandlerIn::PrepareResponse(ack, connection_id));
             }
             PbftHandlerEvent::ProcessCommitRequest {
                 request,
                 connection_id,
             } => {
-                println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] request: {:?}", request);
-
-                self.validate_commit(&request).unwrap();
-
-                self.queued_events
-                    .push_back(NetworkBehaviourAction::SendEvent {
-                        peer_id: peer_id.clone(),
-                        event: PbftHandlerIn::CommitResponse("OK".into(), connection_id),
-                    });
-
-                // Replicas accept commit messages and insert them in their log
-                self.state.insert_commit(peer_id, request.clone());
-
-                // Each replica _i_ executes the operation requested by _m_ after `committed-local(m, v, n, i)` is true
-                if self.committed_local(request.view(), request.sequence_number()) {
-                    let client_request = self
-                        .state
-                        .get_pre_prepare_by_key(request.view(), request.sequence_number())
-                        .unwrap()
-                        .client_reqeust();
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] client_message: {:?}", client_request);
-
-                    // Discard requests whose timestamp is lower than the timestamp in the last reply this node sent to the client to guarantee exactly-once semantics.
-                    if client_request.timestamp() <= self.state.last_timestamp() {
-                        eprintln!(
-                            "[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] the request was discarded as its timestamp is lower than the last timestamp. last_timestamp: {:?}",
-                            self.state.last_timestamp()
-                        );
-                        return;
-                    }
+                println!("[Pbft::process_handler_event] [PbftHandlerEvent::ProcessCommitRequest] request: {:?}", request);
+                let result = self.process_commit(&peer_id, request);
+                self.respond(peer_id, result, |ack| PbftHandlerIn::CommitResponse(ack, connection_id));
+            }
+            PbftHandlerEvent::ProcessForwardedRequest {
+                request,
+                connection_id,
+            } => {
+                println!("[Pbft::process_handler_event] [PbftHandlerEvent::ProcessForwardedRequest] request: {:?}", request);
+                // Not forwarded again, so that a request can't bounce between replicas that disagree on the primary
+                let result = if self.next_primary() == self.local_peer_id() {
+                    self.propose(request);
+                    Ok(())
+                } else {
+                    Err(RejectReason::NotPrimary.into())
+                };
+                self.respond(peer_id, result, |ack| PbftHandlerIn::ForwardedResponse(ack, connection_id));
+            }
+            PbftHandlerEvent::ProcessStateRequest {
+                request,
+                connection_id,
+            } => {
+                println!("[Pbft::process_handler_event] [PbftHandlerEvent::ProcessStateRequest] request: {:?}", request);
+                let result = self.process_state_request(peer_id, request);
+                self.respond(peer_id, result, |ack| PbftHandlerIn::StateResponse(ack, connection_id));
+            }
+            PbftHandlerEvent::ProcessStateReply {
+                request,
+                connection_id,
+            } => {
+                println!("[Pbft::process_handler_event] [PbftHandlerEvent::ProcessStateReply] entries: {}", request.entries().len());
+                let result = self.process_state_reply(peer_id, request);
+                self.respond(peer_id, result, |ack| PbftHandlerIn::StateResponse(ack, connection_id));
+            }
+        }
+    }
 
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] the operation has been executed: {:?}", client_request.operation());
-
-                    // After executing the requested operation, replicas send a reply to the client.
-                    let reply = ClientReply::new(
-                        PeerId::from_public_key(self.keypair.public()),
-                        client_request,
-                        &request,
-                    );
-                    println!("[Pbft::inject_node_event] [PbftHandlerEvent::ProcessCommitRequest] reply: {:?}", reply);
-                    self.state.update_last_timestamp(reply.timestamp());
-                    self.client_replies.write().unwrap().push_back(reply);
-                }
+    // Answers a consensus message. Invalid messages are dropped, counted and Nacked. Local failures are
+    // not the sender's fault, so they aren't counted, but the message is Nacked as `Unavailable` so that
+    // the sender resends it.
+    fn respond<F>(&mut self, peer_id: PeerId, result: Result<(), PbftError>, response: F)
+    where
+        F: FnOnce(Acknowledgement) -> PbftHandlerIn,
+    {
+        let ack = match result {
+            Ok(()) => Acknowledgement::Ack,
+            Err(PbftError::Validation(reason)) => {
+                self.count_invalid_message(&peer_id);
+                Acknowledgement::Nack(reason)
+            }
+            Err(e) => {
+                eprintln!("[Pbft::respond] Failed to process the message from peer_id: {:?}, error: {}", peer_id, e);
+                Acknowledgement::Nack(RejectReason::Unavailable)
+            }
+        };
+        if self.queued_events.len() >= MAX_QUEUED_EVENTS {
+            // The handler times the request out, and the peer resends it
+            eprintln!("[Pbft::respond] the event queue is full, dropped the response to peer_id: {:?}", peer_id);
+            return;
+        }
+        self.queued_events
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::Any,
+                event: response(ack),
+            });
+    }
+
+    fn count_invalid_message(&mut self, peer_id: &PeerId) {
+        *self.invalid_messages.entry(*peer_id).or_insert(0) += 1;
+    }
+
+    fn process_prepare(&mut self, peer_id: &PeerId, prepare: Prepare) -> Result<(), PbftError> {
+        if self.already_executed(prepare.sequence_number()) {
+            return Ok(());
+        }
+        self.validate_prepare(&prepare)?;
+        self.state.insert_prepare(*peer_id, prepare.clone());
+
+        let local_peer_id = self.local_peer_id();
+        if self.prepared(prepare.view(), prepare.sequence_number())
+            && !self.state.has_commit(&local_peer_id, prepare.view(), prepare.sequence_number())
+        {
+            // The own commit counts towards the 2f + 1 commits
+            let commit: Commit = prepare.into();
+            self.broadcast(Message::Commit(commit.clone()));
+            self.process_commit(&local_peer_id, commit)?;
+        }
+        Ok(())
+    }
+
+    fn process_commit(&mut self, peer_id: &PeerId, commit: Commit) -> Result<(), PbftError> {
+        if self.already_executed(commit.sequence_number()) {
+            return Ok(());
+        }
+        self.validate_commit(&commit)?;
+
+        // Replicas accept commit messages and insert them in their log
+        self.state.insert_commit(*peer_id, commit.clone());
+
+        let view = commit.view();
+        self.execute_committed(view)?;
+
+        // A later request is committed while the next one to execute is unknown to this replica, e.g. because
+        // it restarted: the other replicas have moved on without it.
+        if commit.sequence_number() > self.state.last_executed() + 1
+            && self.committed_local(view, commit.sequence_number())
+            && self.state.get_pre_prepare_by_key(view, self.state.last_executed() + 1).is_none()
+        {
+            self.generate_event(PbftEvent::StateTransferNeeded { peer_id: *peer_id, view });
+        }
+        Ok(())
+    }
+
+    // Each replica _i_ executes the operation requested by _m_ after `committed-local(m, v, n, i)` is true
+    // and its state reflects the sequential execution of all requests with lower sequence numbers.
+    fn execute_committed(&mut self, view: u64) -> Result<(), PbftError> {
+        loop {
+            let sequence_number = self.state.last_executed() + 1;
+            if !self.committed_local(view, sequence_number) {
+                return Ok(());
             }
+            self.execute(view, sequence_number)?;
         }
     }
 
-    fn poll(
-        &mut self,
-        _: &mut impl PollParameters,
-    ) -> Async<NetworkBehaviourAction<PbftHandlerIn, PbftEvent>> {
-        println!("[Pbft::poll]");
-        if let Some(event) = self.queued_events.pop_front() {
-            println!("[Pbft::poll] event: {:?}", event);
-            return Async::Ready(event);
+    /// Asks every peer for the requests they executed after the last one this replica executed. Called when
+    /// this replica finds out it is behind. A transfer that is still running is not restarted before the
+    /// retransmission timeout elapses.
+    pub fn start_state_transfer(&mut self) {
+        let now = self.clock.now_millis();
+        if let Some(state_transfer) = self.state_transfer.as_ref() {
+            if now < state_transfer.started_at + self.retransmission.timeout() {
+                return;
+            }
+        }
+        let after = self.state.last_executed();
+        println!("[Pbft::start_state_transfer] after: {}", after);
+        self.state_transfer = Some(StateTransfer::new(now));
+        self.broadcast(Message::StateRequest(StateRequest::new(after)));
+    }
+
+    fn process_state_request(&mut self, peer_id: PeerId, request: StateRequest) -> Result<(), PbftError> {
+        let entries = self.state.executed_pre_prepares(request.after(), MAX_STATE_TRANSFER_ENTRIES);
+        if entries.is_empty() {
+            return Ok(());
         }
-        Async::NotReady
+        self.send_request(peer_id, Message::StateReply(StateReply::new(entries)));
+        Ok(())
+    }
+
+    fn process_state_reply(&mut self, peer_id: PeerId, reply: StateReply) -> Result<(), PbftError> {
+        let last_executed = self.state.last_executed();
+        let state_transfer = match self.state_transfer.as_mut() {
+            Some(state_transfer) => state_transfer,
+            None => {
+                println!("[Pbft::process_state_reply] No state transfer is running, ignored the reply from {:?}", peer_id);
+                return Ok(());
+            }
+        };
+        let full = reply.entries().len() >= MAX_STATE_TRANSFER_ENTRIES;
+        for entry in reply.into_entries() {
+            if entry.validate_digest().is_err() {
+                return Err(RejectReason::BadDigest.into());
+            }
+            if entry.sequence_number() > last_executed {
+                state_transfer.insert(peer_id, entry);
+            }
+        }
+
+        // Requests f+1 peers executed are committed, as at least one of those peers is correct
+        while let Some(entry) = self
+            .state_transfer
+            .as_ref()
+            .and_then(|state_transfer| state_transfer.agreed(self.state.last_executed() + 1, self.f))
+        {
+            println!("[Pbft::process_state_reply] Applying the transferred request: {}", entry);
+            let (view, sequence_number) = (entry.view(), entry.sequence_number());
+            self.pre_prepare_sequence.observe(sequence_number);
+            self.state.insert_pre_prepare(entry);
+            self.execute(view, sequence_number)?;
+        }
+
+        if self.state.last_executed() > last_executed {
+            self.state_transfer = None;
+            if full {
+                // The peers may have executed more than they could send at once
+                self.start_state_transfer();
+            }
+            // Requests committed while this replica was behind can be executed now
+            self.execute_committed(self.state.current_view())?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, view: u64, sequence_number: u64) -> Result<(), PbftError> {
+        let pre_prepare = self
+            .state
+            .get_pre_prepare_by_key(view, sequence_number)
+            .ok_or_else(|| {
+                PbftError::Storage(format!(
+                    "no PrePrepare for the committed view: {}, sequence_number: {}",
+                    view, sequence_number
+                ))
+            })?
+            .clone();
+        let client_request = pre_prepare.client_reqeust();
+        println!("[Pbft::execute] client_message: {:?}", client_request);
+
+        // The sequence number is used up even if the request is discarded below
+        self.state.record_execution(pre_prepare.clone());
+        if pre_prepare.sequence_number() > RETRANSMISSION_WINDOW {
+            self.collect_garbage(pre_prepare.sequence_number() - RETRANSMISSION_WINDOW);
+        }
+
+        // Discard requests whose timestamp is lower than the timestamp in the last reply this node sent to the client to guarantee exactly-once semantics.
+        let last_timestamp = self.state.last_timestamp(&client_request.client());
+        if client_request.timestamp() <= last_timestamp {
+            eprintln!(
+                "[Pbft::execute] the request was discarded as its timestamp is lower than the last timestamp. last_timestamp: {:?}",
+                last_timestamp
+            );
+            return Ok(());
+        }
+
+        let result = self
+            .state_machine
+            .execute(&client_request.operation(), pre_prepare.non_deterministic());
+        println!("[Pbft::execute] the operation has been executed: {:?}, result: {:?}", client_request.operation(), result);
+
+        if let Some(execution_log) = self.execution_log.as_mut() {
+            execution_log
+                .append(&ExecutionEntry {
+                    sequence_number: pre_prepare.sequence_number(),
+                    view: pre_prepare.view(),
+                    digest: pre_prepare.digest().clone(),
+                    operation: client_request.operation(),
+                    timestamp: client_request.timestamp(),
+                    result: result.clone(),
+                })
+                .map_err(|e| PbftError::Storage(format!("failed to append to the execution log: {}", e)))?;
+        }
+
+        // After executing the requested operation, replicas send a reply to the client.
+        let commit: Commit = Prepare::from(&pre_prepare).into();
+        let reply = ClientReply::new(
+            PeerId::from_public_key(&self.keypair.public()),
+            client_request,
+            &commit,
+            result,
+        );
+        println!("[Pbft::execute] reply: {:?}", reply);
+        self.state.update_last_timestamp(client_request.client(), reply.timestamp());
+        self.push_client_reply(reply)
+    }
+
+    // A message for a sequence number this replica has executed is a late retransmission. The log entries it
+    // would join are gone, so it is acknowledged and dropped.
+    fn already_executed(&self, sequence_number: u64) -> bool {
+        sequence_number <= self.state.last_executed()
+    }
+
+    // Requests proposed by this replica that it hasn't executed yet.
+    fn in_flight_requests(&self) -> u64 {
+        self.pre_prepare_sequence.value().saturating_sub(self.state.last_executed())
+    }
+
+    fn push_client_reply(&mut self, reply: ClientReply) -> Result<(), PbftError> {
+        let mut client_replies = self
+            .client_replies
+            .write()
+            .map_err(|e| PbftError::Storage(format!("the client replies are poisoned: {}", e)))?;
+        if client_replies.len() >= MAX_CLIENT_REPLIES {
+            // The client retransmits its request if it doesn't get a reply
+            eprintln!("[Pbft::push_client_reply] the client replies are full, dropped: {:?}", reply);
+            return Ok(());
+        }
+        client_replies.push_back(reply);
+        Ok(())
     }
 }
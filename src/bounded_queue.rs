@@ -0,0 +1,87 @@
+use crate::message::Message;
+use std::collections::VecDeque;
+
+/// How urgently a message has to go out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Traffic that lets the cluster make progress when the normal case is stuck, i.e. state transfer,
+    /// view-change and checkpoint messages. It is sent ahead of normal-case traffic and is never dropped in favour of it.
+    Control,
+    /// Normal-case traffic: PrePrepare, Prepare and Commit.
+    Normal,
+}
+
+impl Priority {
+    pub fn of(message: &Message) -> Self {
+        match message {
+            // TODO: view-change and checkpoint messages are `Control` once they exist
+            Message::StateRequest(_) | Message::StateReply(_) => Priority::Control,
+            Message::PrePrepare(_) | Message::Prepare(_) | Message::Commit(_) | Message::ClientRequest(_) => {
+                Priority::Normal
+            }
+        }
+    }
+}
+
+/// A FIFO queue per priority, bounded by a capacity shared between them.
+pub struct BoundedQueue<T> {
+    control: VecDeque<T>,
+    normal: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "the capacity must be positive");
+        Self {
+            control: VecDeque::new(),
+            normal: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Enqueues `item`. When the queue is full, returns the item that didn't make it: `item` itself, or
+    /// the most recent normal item if it had to make room for a control one.
+    pub fn push(&mut self, item: T, priority: Priority) -> Result<(), T> {
+        if self.len() < self.capacity {
+            self.queue(priority).push_back(item);
+            return Ok(());
+        }
+
+        match priority {
+            Priority::Control => match self.normal.pop_back() {
+                Some(evicted) => {
+                    self.control.push_back(item);
+                    Err(evicted)
+                }
+                None => Err(item),
+            },
+            Priority::Normal => Err(item),
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.control.pop_front().or_else(|| self.normal.pop_front())
+    }
+
+    pub fn len(&self) -> usize {
+        self.control.len() + self.normal.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut items: Vec<T> = self.control.drain(..).collect();
+        items.extend(self.normal.drain(..));
+        items
+    }
+
+    fn queue(&mut self, priority: Priority) -> &mut VecDeque<T> {
+        match priority {
+            Priority::Control => &mut self.control,
+            Priority::Normal => &mut self.normal,
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage:
+    $ pbft run --config <node.toml>
+    $ pbft client --cluster <cluster.json> [--reply-to <host:port>] [--client-address <host:port>] [--timeout <ms>] [--trace <file>] [--file <file> | <operation>]
+    $ pbft status --node <host:port>
+    $ pbft keygen [--out <file>]
+    $ pbft inspect-log <file>
+    $ pbft check-trace <file>
+    $ pbft testnet --replicas <n> --faults <f> --out <dir>
+    $ pbft launch --dir <dir>";
+
+#[derive(Debug)]
+pub enum Command {
+    /// Starts a replica
+    Run { config: PathBuf },
+    /// Submits operations and prints the agreed results
+    Client {
+        cluster: PathBuf,
+        reply_to: Option<SocketAddr>,
+        client_address: Option<SocketAddr>,
+        timeout: Option<u64>,
+        trace: Option<PathBuf>,
+        operations: Operations,
+    },
+    /// Prints the status of a replica
+    Status { node: SocketAddr },
+    Keygen { out: PathBuf },
+    /// Prints the entries of an execution log
+    InspectLog { path: PathBuf },
+    /// Checks that the history in a client trace is linearizable
+    CheckTrace { path: PathBuf },
+    Testnet {
+        replicas: usize,
+        faults: usize,
+        out: PathBuf,
+    },
+    Launch { dir: PathBuf },
+}
+
+/// Where `pbft client` reads the operations to submit from.
+#[derive(Debug)]
+pub enum Operations {
+    One(String),
+    /// One operation per line
+    File(PathBuf),
+    /// One operation per line
+    Stdin,
+}
+
+/// Parses the arguments, without the name of the binary.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let (subcommand, args) = match args.split_first() {
+        Some((subcommand, args)) => (subcommand.as_str(), args),
+        None => return Err("a subcommand is required".to_owned()),
+    };
+    let mut args = args.to_vec();
+
+    let command = match subcommand {
+        "run" => Command::Run {
+            config: PathBuf::from(required(&mut args, "--config")?),
+        },
+        "client" => {
+            let cluster = PathBuf::from(required(&mut args, "--cluster")?);
+            let reply_to = match take_option(&mut args, "--reply-to")? {
+                Some(reply_to) => Some(parse_value(&reply_to, "--reply-to")?),
+                None => None,
+            };
+            let client_address = match take_option(&mut args, "--client-address")? {
+                Some(client_address) => Some(parse_value(&client_address, "--client-address")?),
+                None => None,
+            };
+            let timeout = match take_option(&mut args, "--timeout")? {
+                Some(timeout) => Some(parse_value(&timeout, "--timeout")?),
+                None => None,
+            };
+            let trace = take_option(&mut args, "--trace")?.map(PathBuf::from);
+            let operations = match (take_option(&mut args, "--file")?, args.len()) {
+                (Some(file), 0) => Operations::File(PathBuf::from(file)),
+                (None, 0) => Operations::Stdin,
+                (None, 1) => Operations::One(args.remove(0)),
+                _ => return Err("`client` takes either one operation or --file".to_owned()),
+            };
+            Command::Client {
+                cluster,
+                reply_to,
+                client_address,
+                timeout,
+                trace,
+                operations,
+            }
+        }
+        "status" => Command::Status {
+            node: parse_value(&required(&mut args, "--node")?, "--node")?,
+        },
+        "keygen" => Command::Keygen {
+            out: PathBuf::from(take_option(&mut args, "--out")?.unwrap_or_else(|| "pbft.key".to_owned())),
+        },
+        "inspect-log" => {
+            if args.len() != 1 {
+                return Err("`inspect-log` takes the path of the log".to_owned());
+            }
+            Command::InspectLog {
+                path: PathBuf::from(args.remove(0)),
+            }
+        }
+        "check-trace" => {
+            if args.len() != 1 {
+                return Err("`check-trace` takes the path of the trace".to_owned());
+            }
+            Command::CheckTrace {
+                path: PathBuf::from(args.remove(0)),
+            }
+        }
+        "testnet" => Command::Testnet {
+            replicas: parse_value(&required(&mut args, "--replicas")?, "--replicas")?,
+            faults: parse_value(&required(&mut args, "--faults")?, "--faults")?,
+            out: PathBuf::from(required(&mut args, "--out")?),
+        },
+        "launch" => Command::Launch {
+            dir: PathBuf::from(required(&mut args, "--dir")?),
+        },
+        _ => return Err(format!("unknown subcommand: {}", subcommand)),
+    };
+
+    if !args.is_empty() {
+        return Err(format!("unexpected arguments: {:?}", args));
+    }
+    Ok(command)
+}
+
+// Removes `name <value>` from the arguments and returns the value, or `None` when the option isn't given. The
+// option is an error when it is the last argument or followed by another option, rather than taking the
+// option as its value or leaving it among the positional arguments.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let position = match args.iter().position(|a| a == name) {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+    match args.get(position + 1) {
+        Some(value) if !value.starts_with("--") => {
+            let value = args.remove(position + 1);
+            args.remove(position);
+            Ok(Some(value))
+        }
+        _ => Err(format!("{} requires a value", name)),
+    }
+}
+
+fn required(args: &mut Vec<String>, name: &str) -> Result<String, String> {
+    take_option(args, name)?.ok_or_else(|| format!("{} is required", name))
+}
+
+fn parse_value<T>(value: &str, name: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| format!("invalid {} {:?}: {}", name, value, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Command, String> {
+        parse(&args.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn client_operations() {
+        let command = parse_args("client --cluster cluster.json --file operations.txt").unwrap();
+        assert!(matches!(command, Command::Client { operations: Operations::File(_), .. }));
+        let command = parse_args("client --cluster cluster.json --trace trace.jsonl op").unwrap();
+        assert!(matches!(command, Command::Client { trace: Some(_), operations: Operations::One(_), .. }));
+        let command = parse_args("client --cluster cluster.json --reply-to 0.0.0.0:9000 --client-address 203.0.113.7:9000 op").unwrap();
+        assert!(matches!(command, Command::Client { reply_to: Some(_), client_address: Some(_), .. }));
+        let command = parse_args("client --cluster cluster.json").unwrap();
+        assert!(matches!(command, Command::Client { operations: Operations::Stdin, .. }));
+    }
+
+    #[test]
+    fn missing_option_value() {
+        let missing = |args: &str, name: &str| {
+            assert_eq!(parse_args(args).unwrap_err(), format!("{} requires a value", name), "{}", args);
+        };
+        missing("client --cluster cluster.json --file", "--file");
+        missing("client --cluster cluster.json --trace", "--trace");
+        missing("client --cluster cluster.json --trace --file operations.txt", "--trace");
+        missing("client --cluster cluster.json --file --trace trace.jsonl", "--file");
+        missing("client --cluster", "--cluster");
+        missing("keygen --out", "--out");
+    }
+}
@@ -0,0 +1,450 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::ClusterConfig;
+use crate::linearizability::TraceWriter;
+use crate::message::{ClientMessage, ClientRequest, RequestRejected};
+use crate::primary_selection::{PrimarySelection, RoundRobin};
+use crate::view::View;
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+// How long to wait for f+1 matching replies before the request is broadcast to every replica
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// How many times the request is broadcast before giving up
+const MAX_RETRANSMISSIONS: u32 = 3;
+// How often the connections are polled for replies
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A client of a PBFT cluster.
+///
+/// `invoke` sends the request to the replica the client believes is the primary, and returns once f+1
+/// replicas sent the same result for it in the same view, as at most f of them are faulty. When the replies
+/// don't arrive in time, the request is broadcast to every replica, so that the backups forward it to the
+/// primary. The view of the accepted replies tells the client which replica is the primary.
+///
+/// The client keeps a connection to every replica it can reach and subscribes to its replies on each, so that
+/// the backups can reply to requests sent to the primary.
+pub struct PbftClient {
+    // The replicas in the canonical order used by the primary selection, with their client address
+    replicas: Vec<(PeerId, SocketAddr)>,
+    f: usize,
+    view: u64,
+    last_timestamp: u64,
+    connections: HashMap<PeerId, ReplicaConnection>,
+    // Identifies the client to the replicas, see `client_address`
+    client_address: Option<SocketAddr>,
+    // Set when the replicas are asked to dial back
+    reply_address: Option<SocketAddr>,
+    listener: Option<TcpListener>,
+    // The connections the replicas dialed back on, each dropped once its deadline passes
+    dial_backs: Vec<(ReplicaConnection, Instant)>,
+    timeout: Duration,
+    max_retransmissions: u32,
+    clock: Box<dyn Clock + Send>,
+    primary_selection: Box<dyn PrimarySelection + Send>,
+    // Records the invocations and the accepted results, to check the history for linearizability
+    trace: Option<TraceWriter>,
+}
+
+/// The result of an operation agreed on by f+1 replicas.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientResult {
+    pub result: String,
+    pub view: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    /// Not enough matching replies arrived. `replies` is the number of replicas that answered.
+    Timeout { replies: usize },
+    /// The primary, or f+1 replicas, reported being saturated. The request may be retried later.
+    Busy,
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Timeout { replies } => write!(f, "timed out, {} replicas answered", replies),
+            ClientError::Busy => f.write_str("the primary is busy"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+// A `ClientReply` as serialized by the replicas.
+#[derive(Debug, Deserialize)]
+struct Reply {
+    view: u64,
+    timestamp: u64,
+    peer_id: String,
+    result: String,
+    busy: bool,
+}
+
+impl PbftClient {
+    /// `replicas` are the PeerIds of the replicas and the addresses they accept clients on.
+    pub fn new(replicas: Vec<(PeerId, SocketAddr)>, f: usize) -> Self {
+        let mut replicas = replicas;
+        replicas.sort_by_key(|(peer_id, _)| peer_id.to_bytes());
+        replicas.dedup_by(|(a, _), (b, _)| a == b);
+        Self {
+            replicas,
+            f,
+            view: View::new().value(),
+            last_timestamp: 0,
+            connections: HashMap::new(),
+            client_address: None,
+            reply_address: None,
+            listener: None,
+            dial_backs: Vec::new(),
+            timeout: REQUEST_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+            clock: Box::new(SystemClock),
+            primary_selection: Box::new(RoundRobin),
+            trace: None,
+        }
+    }
+
+    /// A client of the cluster described by the `cluster.json` of `pbft testnet`.
+    pub fn from_cluster(cluster: &ClusterConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut replicas = Vec::with_capacity(cluster.nodes.len());
+        for node in cluster.nodes.iter() {
+            let peer_id: PeerId = node.peer_id.parse().map_err(|e| format!("node-{}: invalid PeerId: {:?}", node.index, e))?;
+            let address: Multiaddr = node.address.parse()?;
+            replicas.push((peer_id, SocketAddr::new(host(&address), node.client_port)));
+        }
+        Ok(Self::new(replicas, cluster.f).with_primary_selection(cluster.primary_selection.build()?))
+    }
+
+    /// Listens for replies on `reply_address`, for replicas configured to dial back clients they have no
+    /// connection to. The client is known by `reply_address` unless `with_client_address` says otherwise.
+    pub fn with_reply_address(mut self, reply_address: SocketAddr) -> Self {
+        self.reply_address = Some(reply_address);
+        self.listener = None;
+        self
+    }
+
+    /// The address the client is known by to the replicas, which has to be unique among the clients of the
+    /// cluster. Replicas dial it back, so it has to reach the reply listener when there is one, e.g. through NAT.
+    pub fn with_client_address(mut self, client_address: SocketAddr) -> Self {
+        self.client_address = Some(client_address);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Appends every invocation and the result accepted for it to `trace`.
+    pub fn with_trace(mut self, trace: TraceWriter) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Has to be the policy the replicas use.
+    pub fn with_primary_selection(mut self, primary_selection: Box<dyn PrimarySelection + Send>) -> Self {
+        self.primary_selection = primary_selection;
+        self
+    }
+
+    /// The replicas a request is sent to first: the one the client believes is the primary, or all of them when
+    /// the primary depends on the sequence number the request will get.
+    pub fn primaries(&self) -> Vec<(PeerId, SocketAddr)> {
+        if self.replicas.is_empty() || self.primary_selection.rotates_within_view() {
+            return self.replicas.clone();
+        }
+        let peer_ids: Vec<PeerId> = self.replicas.iter().map(|(peer_id, _)| *peer_id).collect();
+        // The sequence number doesn't matter to a policy that doesn't rotate within the view
+        let primary = self.primary_selection.primary(self.view, 0, &peer_ids);
+        self.replicas.iter().filter(|(peer_id, _)| *peer_id == primary).cloned().collect()
+    }
+
+    /// Submits `operation` and waits for f+1 matching replies.
+    pub fn invoke(&mut self, operation: &str) -> Result<ClientResult, ClientError> {
+        if self.connections.is_empty() {
+            self.connect_all()?;
+        }
+        let client_address = self.client_address()?;
+        let timestamp = self.next_timestamp();
+        let request = ClientMessage::ClientRequest(ClientRequest::new(operation.to_owned(), timestamp, client_address));
+        println!("[PbftClient::invoke] request: {}", request);
+        if let Some(trace) = self.trace.as_mut() {
+            trace.invoke(&client_address.to_string(), timestamp, operation, self.clock.now_millis())?;
+        }
+
+        let primaries = self.primaries();
+        if primaries.is_empty() {
+            return Err(ClientError::Timeout { replies: 0 });
+        }
+        for (peer_id, address) in primaries {
+            self.send(&peer_id, address, &request);
+        }
+
+        // Replies are kept per replica so that a replica sending twice is counted once
+        let mut replies: HashMap<String, Reply> = HashMap::new();
+        let mut busy: HashSet<String> = HashSet::new();
+        let mut retransmissions = 0;
+        let mut deadline = Instant::now() + self.timeout;
+        loop {
+            let received = self.receive()?;
+            if received.is_empty() {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            for reply in received {
+                if reply.timestamp != timestamp || !self.is_replica(&reply.peer_id) {
+                    println!("[PbftClient::invoke] Ignored a reply to another request: {:?}", reply);
+                    continue;
+                }
+                if reply.busy {
+                    // A faulty backup could otherwise make the client give up on any request
+                    if self.is_primary(&reply.peer_id) {
+                        return Err(ClientError::Busy);
+                    }
+                    busy.insert(reply.peer_id);
+                    if busy.len() > self.f {
+                        return Err(ClientError::Busy);
+                    }
+                    continue;
+                }
+                replies.insert(reply.peer_id.clone(), reply);
+
+                if let Some(result) = self.quorum(&replies) {
+                    // The view of f+1 replicas, at least one of which is correct
+                    self.view = result.view;
+                    if let Some(trace) = self.trace.as_mut() {
+                        trace.complete(&client_address.to_string(), timestamp, &result.result, self.clock.now_millis())?;
+                    }
+                    return Ok(result);
+                }
+            }
+
+            if Instant::now() >= deadline {
+                if retransmissions >= self.max_retransmissions {
+                    return Err(ClientError::Timeout { replies: replies.len() });
+                }
+                retransmissions += 1;
+                println!("[PbftClient::invoke] Timed out, broadcasting the request. retransmissions: {}", retransmissions);
+                // Replicas that were unreachable so far may be back
+                self.connect_all()?;
+                for (peer_id, address) in self.replicas.clone() {
+                    self.send(&peer_id, address, &request);
+                }
+                deadline = Instant::now() + self.timeout;
+            }
+        }
+    }
+
+    // Timestamps are totally ordered so that replicas can discard duplicated and stale requests, even when
+    // the clock goes backwards or two requests are sent within the same millisecond.
+    fn next_timestamp(&mut self) -> u64 {
+        self.last_timestamp = std::cmp::max(self.clock.now_millis(), self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
+    // The `client` of the requests. Replicas route the replies by it: it is the configured address, otherwise the
+    // address of the reply listener when they dial back, and otherwise a random one that nothing dials.
+    fn client_address(&mut self) -> Result<SocketAddr, ClientError> {
+        if let Some(reply_address) = self.reply_address {
+            if self.listener.is_none() {
+                let listener = TcpListener::bind(reply_address)?;
+                listener.set_nonblocking(true)?;
+                println!("[PbftClient::client_address] Listening on {:?}", listener.local_addr()?);
+                if self.client_address.is_none() {
+                    // The port is only known once bound when `reply_address` leaves it to the system
+                    let advertised = SocketAddr::new(reply_address.ip(), listener.local_addr()?.port());
+                    if advertised.ip().is_unspecified() {
+                        return Err(ClientError::Io(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("the replicas can't dial back {}, the client address has to be set", advertised),
+                        )));
+                    }
+                    self.client_address = Some(advertised);
+                }
+                self.listener = Some(listener);
+            }
+        }
+        Ok(*self.client_address.get_or_insert_with(random_client_address))
+    }
+
+    fn connect_all(&mut self) -> Result<(), ClientError> {
+        for (peer_id, address) in self.replicas.clone() {
+            if !self.connections.contains_key(&peer_id) {
+                if let Err(e) = self.connect(&peer_id, address) {
+                    eprintln!("[PbftClient::connect_all] Failed to connect to {:?} ({}). error: {:?}", peer_id, address, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self, peer_id: &PeerId, address: SocketAddr) -> Result<(), ClientError> {
+        let client_address = self.client_address()?;
+        let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+        let mut connection = ReplicaConnection { stream, buffer: Vec::new() };
+        connection.send(&ClientMessage::Subscribe { client: client_address })?;
+        connection.stream.set_nonblocking(true)?;
+        self.connections.insert(*peer_id, connection);
+        Ok(())
+    }
+
+    // A replica that can't be reached is not an error as long as f+1 others answer.
+    fn send(&mut self, peer_id: &PeerId, address: SocketAddr, request: &ClientMessage) {
+        if !self.connections.contains_key(peer_id) {
+            if let Err(e) = self.connect(peer_id, address) {
+                eprintln!("[PbftClient::send] Failed to connect to {:?} ({}). error: {:?}", peer_id, address, e);
+                return;
+            }
+        }
+        let result = self.connections.get_mut(peer_id).map(|connection| connection.send(request));
+        if let Some(Err(e)) = result {
+            eprintln!("[PbftClient::send] Failed to send the request to {:?} ({}). error: {:?}", peer_id, address, e);
+            self.connections.remove(peer_id);
+        }
+    }
+
+    // The replies received since the last call, on the connections and, when replicas dial back, the listener.
+    fn receive(&mut self) -> Result<Vec<Reply>, ClientError> {
+        let mut lines = Vec::new();
+        let mut closed = Vec::new();
+        for (peer_id, connection) in self.connections.iter_mut() {
+            if let Err(e) = connection.read_lines(&mut lines) {
+                eprintln!("[PbftClient::receive] Lost the connection to {:?}. error: {:?}", peer_id, e);
+                closed.push(*peer_id);
+            }
+        }
+        for peer_id in closed {
+            self.connections.remove(&peer_id);
+        }
+
+        if let Some(listener) = self.listener.as_ref() {
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(true)?;
+                        let connection = ReplicaConnection { stream, buffer: Vec::new() };
+                        self.dial_backs.push((connection, Instant::now() + self.timeout));
+                    }
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        // A replica that dials back sends one reply and closes the connection
+        let now = Instant::now();
+        self.dial_backs.retain_mut(|(connection, deadline)| match connection.read_lines(&mut lines) {
+            Ok(()) if now < *deadline => true,
+            Ok(()) => {
+                eprintln!("[PbftClient::receive] Dropped a dial-back connection that sent no reply in time");
+                false
+            }
+            Err(_) => false,
+        });
+
+        let mut replies = Vec::with_capacity(lines.len());
+        for line in lines {
+            match serde_json::from_str::<Reply>(&line) {
+                Ok(reply) => replies.push(reply),
+                Err(_) => match serde_json::from_str::<RequestRejected>(&line) {
+                    Ok(rejected) => eprintln!("[PbftClient::receive] A replica rejected the request. error: {}", rejected.error),
+                    Err(e) => eprintln!("[PbftClient::receive] Discarded an invalid reply. error: {}", e),
+                },
+            }
+        }
+        Ok(replies)
+    }
+
+    // Whether `peer_id` is the primary of the latest view the client knows. Never true for a policy that
+    // rotates the primary within the view, as any replica may be the primary of the request.
+    fn is_primary(&self, peer_id: &str) -> bool {
+        !self.primary_selection.rotates_within_view()
+            && self.primaries().iter().any(|(p, _)| p.to_string() == peer_id)
+    }
+
+    fn is_replica(&self, peer_id: &str) -> bool {
+        self.replicas.iter().any(|(p, _)| p.to_string() == peer_id)
+    }
+
+    fn quorum(&self, replies: &HashMap<String, Reply>) -> Option<ClientResult> {
+        let mut votes: HashMap<(&String, u64, u64), usize> = HashMap::new();
+        for reply in replies.values() {
+            let count = votes.entry((&reply.result, reply.timestamp, reply.view)).or_insert(0);
+            *count += 1;
+            if *count > self.f {
+                return Some(ClientResult {
+                    result: reply.result.clone(),
+                    view: reply.view,
+                    timestamp: reply.timestamp,
+                });
+            }
+        }
+        None
+    }
+}
+
+// A connection to the client port of a replica.
+struct ReplicaConnection {
+    stream: TcpStream,
+    // Bytes received that don't make a complete line yet
+    buffer: Vec<u8>,
+}
+
+impl ReplicaConnection {
+    fn send(&mut self, message: &ClientMessage) -> std::io::Result<()> {
+        self.stream.write_all(format!("{}\n", message).as_bytes())
+    }
+
+    // Appends the complete lines received so far to `lines`, including the ones sent right before the
+    // connection was closed.
+    fn read_lines(&mut self, lines: &mut Vec<String>) -> std::io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        let result = loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => break Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(size) => self.buffer.extend_from_slice(&buffer[..size]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        while let Some(position) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=position).collect();
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+        result
+    }
+}
+
+// Identifies a client that the replicas don't dial back. Taken at random from the unique local range fd00::/8,
+// as the local address of a connection may be the same for clients behind different NATs.
+fn random_client_address() -> SocketAddr {
+    let mut ip: [u8; 16] = rand::random();
+    ip[0] = 0xfd;
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), rand::random::<u16>().max(1))
+}
+
+// The IP address in a multiaddr such as `/ip4/127.0.0.1/tcp/7000`, localhost if there is none.
+fn host(address: &Multiaddr) -> IpAddr {
+    address
+        .iter()
+        .find_map(|protocol| match protocol {
+            Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+            Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+            _ => None,
+        })
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
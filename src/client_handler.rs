@@ -1,128 +1,612 @@
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
 use std::sync::{Arc, RwLock};
 use std::io::{Read, Write};
-use crate::message::{ClientRequest, Message, ClientReply};
-use std::collections::VecDeque;
-use crate::node_type::NodeType;
+use crate::message::{ClientMessage, ClientRequest, ClientReply, NodeStatus, RequestRejected};
+use std::collections::{HashMap, VecDeque};
+use libp2p::PeerId;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
 
+// Upper bound on the requests waiting for the consensus behaviour. Beyond it, client streams are left
+// unread so that TCP flow control pushes back on the clients, and the requests already read are answered
+// with "busy".
+const MAX_QUEUED_REQUESTS: usize = 1_024;
+/// Default upper bound on the size of a client message, in bytes.
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
+// Upper bound on the bytes waiting to be written to a client. A client that doesn't read its replies is
+// disconnected rather than buffered for.
+const MAX_OUTGOING_SIZE: usize = 1024 * 1024;
+// Upper bound on the replies waiting to be dialled back. Beyond it, replies are dropped and the clients
+// retransmit their request.
+const MAX_PENDING_DIAL_BACKS: usize = 256;
+// How long the connection of a request is remembered when no reply comes back, and how long replies may be
+// dialled back to an IP after a client was last heard from there
+const CLIENT_STATE_TTL: Duration = Duration::from_secs(60);
+// How often the entries older than `CLIENT_STATE_TTL` are dropped
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How replies are delivered to clients that have no open connection to this replica, e.g. because they sent
+/// their request to the primary only.
+#[derive(Clone, Debug)]
+pub struct DialBack {
+    /// Connections attempted per reply before it is dropped
+    pub attempts: u32,
+    /// Waited between two attempts
+    pub retry_interval: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Default for DialBack {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            retry_interval: Duration::from_millis(100),
+            connect_timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Accepts client connections. Clients send `ClientMessage`s as JSON, one per line, and may send several
+/// on the same connection. Connections are kept open, and the replies to a client are written as JSON lines
+/// to the connection its request arrived on, or that it subscribed on.
+///
+/// A `client` address belongs to the first connection that sends a message for it, until that connection
+/// closes, and a connection only acts for one client. A client can't take over the replies of another one,
+/// whether it is on another host or behind the same NAT.
 pub struct ClientHandler {
-    node_type: NodeType,
+    // Signs the "busy" replies
+    peer_id: PeerId,
     listener: TcpListener,
     client_requests: Arc<RwLock<VecDeque<ClientRequest>>>,
     client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
+    // Kept up to date by the event loop, answered to `ClientMessage::Status`
+    status: Arc<RwLock<NodeStatus>>,
+    max_request_size: usize,
+    // Hands the replies to clients without a connection to the dial-back thread. `None` drops them, the
+    // clients retransmit their request.
+    dial_back: Option<SyncSender<ClientReply>>,
+    connections: HashMap<u64, ClientConnection>,
+    next_connection_id: u64,
+    // The connection each request received by this replica arrived on and when, by (client, timestamp).
+    // Dropped once the reply is sent, or after `CLIENT_STATE_TTL`.
+    requests: HashMap<(SocketAddr, u64), (u64, Instant)>,
+    // The connection that acts for each client, which the replies to the client are written to
+    clients: HashMap<SocketAddr, u64>,
+    // The IPs clients connected from, and when they were last heard from. Replies are only dialled back to
+    // these.
+    client_ips: HashMap<IpAddr, Instant>,
+    last_eviction: Instant,
     stream_states: VecDeque<ClientStreamState>,
 }
 
 impl ClientHandler {
     pub fn new(
-        node_type: NodeType,
+        peer_id: PeerId,
+        address: SocketAddr,
         client_requests: Arc<RwLock<VecDeque<ClientRequest>>>,
         client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
-    ) -> Self {
-        let address = if node_type == NodeType::Primary {
-            // Make the port number of primary node fixed in order to easily debug
-            "127.0.0.1:8000"
-        } else {
-            "127.0.0.1:0"
-        };
-        let listener = TcpListener::bind(address).unwrap();
-        listener.set_nonblocking(true).expect("Cannot set non-blocking");
-        println!("[ClientHandler::new] Listening on {:?}", listener.local_addr().unwrap());
+        status: Arc<RwLock<NodeStatus>>,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        println!("[ClientHandler::new] Listening on {:?}", listener.local_addr()?);
 
-        Self {
-            node_type,
+        Ok(Self {
+            peer_id,
             listener,
             client_requests,
             client_replies,
+            status,
+            max_request_size: MAX_REQUEST_SIZE,
+            dial_back: None,
+            connections: HashMap::new(),
+            next_connection_id: 0,
+            requests: HashMap::new(),
+            clients: HashMap::new(),
+            client_ips: HashMap::new(),
+            last_eviction: Instant::now(),
             stream_states: VecDeque::new(),
-        }
+        })
+    }
+
+    /// Longer messages are answered with a `RequestRejected` and skipped, the connection stays usable.
+    pub fn with_max_request_size(mut self, max_request_size: usize) -> Self {
+        self.max_request_size = max_request_size;
+        self
+    }
+
+    /// Replies to clients without a connection are sent to the `client` address of their request, if a client
+    /// connected from its IP before. The connections are made on a thread of their own, so that a slow client
+    /// doesn't hold up the others.
+    pub fn with_dial_back(mut self, dial_back: DialBack) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<ClientReply>(MAX_PENDING_DIAL_BACKS);
+        std::thread::spawn(move || {
+            for reply in receiver {
+                dial_with_retries(&reply, &dial_back);
+            }
+        });
+        self.dial_back = Some(sender);
+        self
     }
 
-    pub fn tick(&mut self) {
-        println!("[ClientHandler::tick]");
+    /// Serves the clients until the process exits. The sockets are blocking at times, so this is meant to run on
+    /// a thread of its own rather than on the async runtime.
+    pub fn run(mut self, tick_interval: Duration) {
+        loop {
+            self.tick();
+            std::thread::sleep(tick_interval);
+        }
+    }
 
-        // Accept an incoming stream
-        if let Some(tcp_stream) = self.incoming().unwrap() {
-            self.stream_states.push_back(ClientStreamState::WaitingForIncomingStream(tcp_stream));
+    fn tick(&mut self) {
+        // Accept the incoming streams
+        loop {
+            match self.incoming() {
+                Ok(Some(tcp_stream)) => {
+                    if let Ok(peer_addr) = tcp_stream.peer_addr() {
+                        self.client_ips.insert(peer_addr.ip(), Instant::now());
+                    }
+                    let id = self.next_connection_id;
+                    self.next_connection_id += 1;
+                    self.connections.insert(id, ClientConnection::new(tcp_stream));
+                    self.stream_states.push_back(ClientStreamState::WaitingForIncomingStream(id));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("[ClientHandler::tick] Failed to accept a client stream. error: {:?}", e);
+                    break;
+                }
+            }
         }
 
-        // Consume a job to reply to client
-        if let Some(reply) = self.client_replies.write().unwrap().pop_front() {
-            self.stream_states.push_back(ClientStreamState::PrepareToSendReply(reply));
+        if self.last_eviction.elapsed() >= EVICTION_INTERVAL {
+            self.evict_expired();
         }
 
-        if let Some(state) = self.stream_states.pop_front() {
+        // Consume the jobs to reply to clients
+        let replies: Vec<ClientReply> = self.client_replies.write().unwrap().drain(..).collect();
+        self.stream_states.extend(replies.into_iter().map(ClientStreamState::PrepareToSendReply));
+
+        // Every connection is served on each tick, the states pushed back meanwhile wait for the next one
+        for _ in 0..self.stream_states.len() {
+            let state = match self.stream_states.pop_front() {
+                Some(state) => state,
+                None => break,
+            };
             match state {
-                ClientStreamState::WaitingForIncomingStream(tcp_stream) => {
-                    println!("[ClientHandler::tick] [ClientStreamState::WaitingForIncomingStream]");
-                    let new_state = self.read_client_stream(tcp_stream).unwrap();
-                    self.stream_states.push_back(new_state);
+                ClientStreamState::WaitingForIncomingStream(id) => {
+                    if self.client_requests.read().unwrap().len() >= MAX_QUEUED_REQUESTS {
+                        eprintln!("[ClientHandler::tick] [ClientStreamState::WaitingForIncomingStream] Too many queued requests, the stream is read later");
+                        self.stream_states.push_back(ClientStreamState::WaitingForIncomingStream(id));
+                        continue;
+                    }
+                    match self.read_client_stream(id) {
+                        Ok(new_state) => self.stream_states.push_back(new_state),
+                        Err(e) => {
+                            eprintln!("[ClientHandler::tick] [ClientStreamState::WaitingForIncomingStream] Dropped the client stream. error: {:?}", e);
+                            self.close(id);
+                        }
+                    }
                 }
-                ClientStreamState::ReceivedClientMessage(message) => {
+                ClientStreamState::Closed(id) => self.close(id),
+                ClientStreamState::ReceivedClientMessage(message, id) => {
                     println!("[ClientHandler::tick] [ClientStreamState::ReceivedClientMessage] message: {:?}", message);
+                    if let Some(Ok(peer_addr)) = self.connections.get(&id).map(|c| c.peer_addr()) {
+                        self.client_ips.insert(peer_addr.ip(), Instant::now());
+                    }
                     match message {
-                        Message::ClientRequest(client_request) => {
-                            if self.node_type == NodeType::Backup {
-                                // TODO: transfer the message to primary replica if this node is running as backup
-                                eprintln!("Can't process the client request as running as backup. client_request: {:?}", client_request);
-                                return;
+                        ClientMessage::ClientRequest(client_request) => {
+                            if let Err(e) = self.claim(id, client_request.client()) {
+                                self.reject(id, e);
+                                continue;
+                            }
+                            if self.client_requests.read().unwrap().len() >= MAX_QUEUED_REQUESTS {
+                                eprintln!("[ClientHandler::tick] [ClientStreamState::ReceivedClientMessage] Too many queued requests, replying busy");
+                                let view = self.status.read().unwrap().view;
+                                let reply = ClientReply::busy(self.peer_id, &client_request, view);
+                                if let Err(e) = self.send(id, &reply.to_string()) {
+                                    eprintln!("[ClientHandler::tick] [ClientStreamState::ReceivedClientMessage] Failed to send the busy reply. error: {:?}", e);
+                                    self.close(id);
+                                    continue;
+                                }
+                            } else {
+                                self.requests.insert((client_request.client(), client_request.timestamp()), (id, Instant::now()));
+                                self.client_requests.write().unwrap().push_back(client_request);
+                            }
+                        }
+                        ClientMessage::Subscribe { client } => {
+                            if let Err(e) = self.claim(id, client) {
+                                self.reject(id, e);
+                                continue;
+                            }
+                        }
+                        ClientMessage::Status => {
+                            let status = self.status.read().unwrap().to_string();
+                            if let Err(e) = self.send(id, &status) {
+                                eprintln!("[ClientHandler::tick] [ClientStreamState::ReceivedClientMessage] Failed to send the status. error: {:?}", e);
+                                self.close(id);
+                                continue;
                             }
-                            self.client_requests.write().unwrap().push_back(client_request);
                         }
-                        _ => unreachable!()
                     }
+                    // The client may send more messages on the same connection
+                    self.stream_states.push_back(ClientStreamState::WaitingForIncomingStream(id));
                 }
                 ClientStreamState::PrepareToSendReply(reply) => {
                     println!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] reply: {:?}", reply);
-                    let mut stream = TcpStream::connect(reply.client_address()).unwrap();
-                    stream.set_nonblocking(true).expect("Cannot set non-blocking");
-
-                    match stream.write(reply.to_string().as_bytes()) {
-                        Ok(_size) => println!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] Sent the reply to the client. reply: {:?}", reply),
-                        Err(e) => eprintln!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] Failed to send the reply to the client. error: {:?}, reply: {:?}", e, reply)
+                    // The connection the request arrived on, or else the one the client subscribed on
+                    let connection = self
+                        .requests
+                        .remove(&(reply.client_address(), reply.timestamp()))
+                        .map(|(id, _)| id)
+                        .or_else(|| self.clients.get(&reply.client_address()).cloned());
+                    if let Some(id) = connection {
+                        match self.send(id, &reply.to_string()) {
+                            Ok(()) => {
+                                println!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] Sent the reply to the client. reply: {:?}", reply);
+                                continue;
+                            }
+                            Err(e) => {
+                                eprintln!("[ClientHandler::tick] [ClientStreamState::PrepareToSendReply] Failed to send the reply to the client. error: {:?}, reply: {:?}", e, reply);
+                                self.close(id);
+                            }
+                        }
                     }
+                    self.dial_back(reply);
                 }
             }
         }
     }
 
+    // Binds connection `id` to `client`, unless another connection acts for `client` or connection `id`
+    // acts for another client.
+    fn claim(&mut self, id: u64, client: SocketAddr) -> Result<(), String> {
+        let connection = self.connections.get_mut(&id).ok_or_else(|| "the connection is closed".to_owned())?;
+        match connection.client {
+            Some(claimed) if claimed == client => return Ok(()),
+            Some(claimed) => return Err(format!("the connection acts for the client {} already", claimed)),
+            None => {}
+        }
+        if self.clients.get(&client).is_some_and(|owner| *owner != id) {
+            return Err(format!("the client address {} is used by another connection", client));
+        }
+        connection.client = Some(client);
+        self.clients.insert(client, id);
+        Ok(())
+    }
+
+    // Forgets the requests that never got a reply, e.g. because the primary dropped them, and the IPs no client
+    // was heard from lately.
+    fn evict_expired(&mut self) {
+        let now = Instant::now();
+        self.last_eviction = now;
+        self.requests.retain(|_, (_, received_at)| now.duration_since(*received_at) < CLIENT_STATE_TTL);
+        self.client_ips.retain(|_, last_seen| now.duration_since(*last_seen) < CLIENT_STATE_TTL);
+    }
+
+    fn reject(&mut self, id: u64, error: String) {
+        eprintln!("[ClientHandler::reject] {}", error);
+        if let Err(e) = self.send(id, &RequestRejected::new(error).to_string()) {
+            eprintln!("[ClientHandler::reject] Failed to send the rejection. error: {:?}", e);
+            self.close(id);
+            return;
+        }
+        self.stream_states.push_back(ClientStreamState::WaitingForIncomingStream(id));
+    }
+
+    // Hands the reply to the dial-back thread, if dialling back is enabled and a client connected from its IP.
+    fn dial_back(&mut self, reply: ClientReply) {
+        let sender = match self.dial_back.as_ref() {
+            Some(sender) => sender,
+            None => {
+                eprintln!("[ClientHandler::dial_back] The client has no connection, dropped the reply: {:?}", reply);
+                return;
+            }
+        };
+        if !self.client_ips.contains_key(&reply.client_address().ip()) {
+            eprintln!("[ClientHandler::dial_back] No client connected from the address of the reply, dropped it: {:?}", reply);
+            return;
+        }
+        match sender.try_send(reply) {
+            Ok(()) => {}
+            Err(TrySendError::Full(reply)) => {
+                eprintln!("[ClientHandler::dial_back] Too many replies to dial back, dropped: {:?}", reply)
+            }
+            Err(TrySendError::Disconnected(reply)) => {
+                eprintln!("[ClientHandler::dial_back] The dial-back thread is gone, dropped: {:?}", reply)
+            }
+        }
+    }
+
     fn incoming(&self) -> Result<Option<TcpStream>, std::io::Error> {
-        for tcp_stream in self.listener.incoming() {
-            match tcp_stream {
-                Ok(s) => return Ok(Some(s)),
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+        match self.listener.accept() {
+            Ok((s, _)) => {
+                // Accepted streams don't inherit the non-blocking mode of the listener
+                s.set_nonblocking(true)?;
+                Ok(Some(s))
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => {
+                println!("encountered IO error: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    // Returns the next complete message of the connection, or waits for more bytes.
+    fn read_client_stream(&mut self, id: u64) -> Result<ClientStreamState, std::io::Error> {
+        let max_request_size = self.max_request_size;
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return Ok(ClientStreamState::Closed(id)),
+        };
+        // Replies that didn't fit in the socket buffer on the previous tick
+        connection.flush()?;
+
+        loop {
+            while let Some(line) = connection.next_line(max_request_size) {
+                match line {
+                    Ok(line) if line.iter().all(u8::is_ascii_whitespace) => continue,
+                    Ok(line) => match ClientMessage::try_from(line.as_slice()) {
+                        Ok(message) => {
+                            println!("[ClientHandler::read_client_stream] message: {:?}", message);
+                            return Ok(ClientStreamState::ReceivedClientMessage(message, id));
+                        }
+                        Err(e) => {
+                            eprintln!("[ClientHandler::read_client_stream] Discarded an invalid message from {:?}. error: {}", connection.peer_addr(), e);
+                            connection.send(&RequestRejected::new(e.to_string()).to_string())?;
+                        }
+                    },
+                    Err(size) => {
+                        eprintln!("[ClientHandler::read_client_stream] Discarded a message of {} bytes from {:?}", size, connection.peer_addr());
+                        let error = format!("the message exceeds the maximum size of {} bytes", max_request_size);
+                        connection.send(&RequestRejected::new(error).to_string())?;
+                    }
+                }
+            }
+
+            let mut buffer = [0u8; 4096];
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => {
+                    println!("[ClientHandler::read_client_stream] The client closed the stream. peer_addr: {:?}", connection.peer_addr());
+                    return Ok(ClientStreamState::Closed(id));
+                }
+                Ok(size) => connection.buffer.extend_from_slice(&buffer[..size]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(ClientStreamState::WaitingForIncomingStream(id));
+                },
                 Err(e) => {
                     println!("encountered IO error: {}", e);
                     return Err(e)
                 },
             }
         }
-        unreachable!();
     }
 
-    fn read_client_stream(&self, mut tcp_stream: TcpStream) -> Result<ClientStreamState, std::io::Error> {
-        let mut buffer = [0u8; 512];
-        match tcp_stream.read(&mut buffer) {
-            Ok(size) => {
-                let message = String::from_utf8_lossy(&buffer[..size]).to_string().into();
-                println!("[ClientHandler::read_client_stream] message: {:?}", message);
-                return Ok(ClientStreamState::ReceivedClientMessage(message));
+    fn send(&mut self, id: u64, line: &str) -> std::io::Result<()> {
+        match self.connections.get_mut(&id) {
+            Some(connection) => connection.send(line),
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    // Forgets the connection, and the requests and clients whose replies were written to it.
+    fn close(&mut self, id: u64) {
+        self.connections.remove(&id);
+        self.requests.retain(|_, (connection_id, _)| *connection_id != id);
+        self.clients.retain(|_, connection_id| *connection_id != id);
+    }
+}
+
+fn dial_with_retries(reply: &ClientReply, dial_back: &DialBack) {
+    for attempt in 1..=dial_back.attempts {
+        match dial(reply, dial_back) {
+            Ok(()) => {
+                println!("[client_handler::dial_with_retries] Sent the reply to the client. reply: {:?}", reply);
+                return;
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                println!("[ClientHandler::read_client_stream] [ErrorKind::WouldBlock] e: {:?}", e);
-                return Ok(ClientStreamState::WaitingForIncomingStream(tcp_stream));
-            },
-            Err(e) => {
-                println!("encountered IO error: {}", e);
-                return Err(e)
-            },
+            Err(e) if attempt < dial_back.attempts => {
+                eprintln!("[client_handler::dial_with_retries] Failed to send the reply to the client, retrying. attempt: {}, error: {:?}", attempt, e);
+                std::thread::sleep(dial_back.retry_interval);
+            }
+            Err(e) => eprintln!("[client_handler::dial_with_retries] Gave up sending the reply to the client. error: {:?}, reply: {:?}", e, reply),
         }
     }
 }
 
+// Sends the reply on a new connection to the `client` address of the request.
+fn dial(reply: &ClientReply, dial_back: &DialBack) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&reply.client_address(), dial_back.connect_timeout)?;
+    stream.set_write_timeout(Some(dial_back.connect_timeout))?;
+    stream.write_all(format!("{}\n", reply).as_bytes())
+}
+
+// A client stream, the bytes received on it that don't make a complete line yet, and the bytes waiting to be
+// written to it.
+struct ClientConnection {
+    stream: TcpStream,
+    // The client the connection acts for, set by the first message that names one
+    client: Option<SocketAddr>,
+    buffer: Vec<u8>,
+    // Bytes of an oversized message skipped so far. The rest of it is skipped up to the next newline.
+    discarding: Option<usize>,
+    outgoing: Vec<u8>,
+}
+
+impl ClientConnection {
+    fn new(stream: TcpStream) -> Self {
+        Self { stream, client: None, buffer: Vec::new(), discarding: None, outgoing: Vec::new() }
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    // The next line without its newline, or `Err` with its size once a line turns out to be longer than
+    // `max_size`.
+    fn next_line(&mut self, max_size: usize) -> Option<Result<Vec<u8>, usize>> {
+        let newline = self.buffer.iter().position(|b| *b == b'\n');
+        match (newline, self.discarding) {
+            (Some(position), Some(skipped)) => {
+                self.buffer.drain(..=position);
+                self.discarding = None;
+                Some(Err(skipped + position))
+            }
+            (Some(position), None) if position > max_size => {
+                self.buffer.drain(..=position);
+                Some(Err(position))
+            }
+            (Some(position), None) => {
+                let mut line: Vec<u8> = self.buffer.drain(..=position).collect();
+                line.pop();
+                Some(Ok(line))
+            }
+            (None, discarding) => {
+                if discarding.is_some() || self.buffer.len() > max_size {
+                    // Don't buffer a message that will be rejected anyway
+                    self.discarding = Some(discarding.unwrap_or(0) + self.buffer.len());
+                    self.buffer.clear();
+                }
+                None
+            }
+        }
+    }
+
+    fn send(&mut self, line: &str) -> std::io::Result<()> {
+        if self.outgoing.len() + line.len() + 1 > MAX_OUTGOING_SIZE {
+            return Err(std::io::Error::other("the client doesn't read its replies"));
+        }
+        self.outgoing.extend_from_slice(line.as_bytes());
+        self.outgoing.push(b'\n');
+        self.flush()
+    }
+
+    // Writes as much of the outgoing bytes as the socket accepts without blocking.
+    fn flush(&mut self) -> std::io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(size) => {
+                    self.outgoing.drain(..size);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
 enum ClientStreamState {
-    WaitingForIncomingStream(TcpStream),
-    ReceivedClientMessage(Message),
+    WaitingForIncomingStream(u64),
+    Closed(u64),
+    ReceivedClientMessage(ClientMessage, u64),
     PrepareToSendReply(ClientReply),
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_handler() -> ClientHandler {
+        ClientHandler::new(
+            PeerId::random(),
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(RwLock::new(VecDeque::new())),
+            Arc::new(RwLock::new(VecDeque::new())),
+            Arc::new(RwLock::new(NodeStatus::default())),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn evicts_expired_entries() {
+        let mut handler = client_handler();
+        let now = Instant::now();
+        let expired = now - CLIENT_STATE_TTL - Duration::from_secs(1);
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let other: IpAddr = "10.0.0.1".parse().unwrap();
+        handler.requests.insert((client, 1), (0, expired));
+        handler.requests.insert((client, 2), (0, now));
+        handler.client_ips.insert(client.ip(), now);
+        handler.client_ips.insert(other, expired);
+
+        handler.evict_expired();
+        assert_eq!(handler.requests.keys().collect::<Vec<_>>(), vec![&(client, 2)]);
+        assert_eq!(handler.client_ips.keys().collect::<Vec<_>>(), vec![&client.ip()]);
+    }
+
+    fn connection() -> ClientConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        ClientConnection::new(stream)
+    }
+
+    // Appends `bytes` as if they were just read from the socket, and returns the lines they complete.
+    fn read(connection: &mut ClientConnection, bytes: &[u8], max_size: usize) -> Vec<Result<Vec<u8>, usize>> {
+        connection.buffer.extend_from_slice(bytes);
+        std::iter::from_fn(|| connection.next_line(max_size)).collect()
+    }
+
+    #[test]
+    fn claims() {
+        let mut handler = client_handler();
+        for id in 0..3 {
+            handler.connections.insert(id, connection());
+        }
+        // Every connection comes from the same IP
+        let client: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+
+        assert!(handler.claim(0, client).is_ok());
+        assert!(handler.claim(0, client).is_ok());
+        assert!(handler.claim(1, other).is_ok());
+        // Another connection can't act for the client, nor can a connection act for two clients
+        assert!(handler.claim(2, client).is_err());
+        assert!(handler.claim(0, other).is_err());
+        assert_eq!(handler.clients.get(&client), Some(&0));
+
+        // Until the connection closes
+        handler.close(0);
+        assert!(handler.claim(2, client).is_ok());
+        assert_eq!(handler.clients.get(&client), Some(&2));
+    }
+
+    #[test]
+    fn partial_lines() {
+        let mut connection = connection();
+        assert!(read(&mut connection, b"{\"Subscribe\"", 64).is_empty());
+        assert!(read(&mut connection, b": {\"client\": ", 64).is_empty());
+        assert_eq!(
+            read(&mut connection, b"\"127.0.0.1:9000\"}}\n", 64),
+            vec![Ok(b"{\"Subscribe\": {\"client\": \"127.0.0.1:9000\"}}".to_vec())]
+        );
+        assert!(connection.buffer.is_empty());
+    }
+
+    #[test]
+    fn several_lines_in_one_read() {
+        let mut connection = connection();
+        assert_eq!(
+            read(&mut connection, b"\"Status\"\n\n\"Status\"\n\"Sta", 64),
+            vec![Ok(b"\"Status\"".to_vec()), Ok(Vec::new()), Ok(b"\"Status\"".to_vec())]
+        );
+        assert_eq!(read(&mut connection, b"tus\"\n", 64), vec![Ok(b"\"Status\"".to_vec())]);
+    }
+
+    #[test]
+    fn oversized_line() {
+        let mut connection = connection();
+        // A line of exactly the maximum size is accepted
+        assert_eq!(read(&mut connection, b"1234\n", 4), vec![Ok(b"1234".to_vec())]);
+        assert_eq!(read(&mut connection, b"1234567890\nok\n", 4), vec![Err(10), Ok(b"ok".to_vec())]);
+
+        // Spread over several reads, the oversized line isn't buffered while the rest of it is skipped
+        assert!(read(&mut connection, b"1234567", 4).is_empty());
+        assert!(connection.buffer.is_empty());
+        assert!(read(&mut connection, b"890", 4).is_empty());
+        assert!(connection.buffer.is_empty());
+        assert_eq!(read(&mut connection, b"\nok\n", 4), vec![Err(10), Ok(b"ok".to_vec())]);
+    }
+}
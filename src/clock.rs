@@ -0,0 +1,18 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The wall clock as seen by the consensus core. The simulator replaces it with its virtual clock.
+pub trait Clock {
+    /// Milliseconds since the Unix epoch.
+    fn now_millis(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("the system clock is set before the Unix epoch")
+            .as_millis() as u64
+    }
+}
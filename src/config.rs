@@ -0,0 +1,506 @@
+use crate::behavior::NonDeterminismBounds;
+use crate::client_handler::{DialBack, MAX_REQUEST_SIZE};
+use crate::handler::PbftHandlerConfig;
+use crate::primary_selection::{PrimarySelection, Rotating, RoundRobin, WeightedRoundRobin};
+use crate::protocol_config::{Name, PbftProtocolConfig};
+use crate::transport::{self, AllowList, NoiseKeyType};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The membership of a cluster, shared by all of its replicas.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// The number of faulty replicas the cluster tolerates
+    pub f: usize,
+    pub nodes: Vec<ClusterNode>,
+    #[serde(default)]
+    pub primary_selection: PrimarySelectionConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterNode {
+    pub index: usize,
+    pub peer_id: String,
+    /// Hex-encoded ed25519 public key
+    pub public_key: String,
+    /// The libp2p multiaddr the replica listens on
+    pub address: String,
+    pub client_port: u16,
+}
+
+/// The configuration of a single replica, read from `node.toml`:
+///
+/// ```toml
+/// index = 0
+/// key_file = "identity.key"
+/// listen = ["/ip4/127.0.0.1/tcp/7000"]
+/// client_port = 8000
+/// f = 1
+///
+/// [[peers]]
+/// peer_id = "12D3KooW..."
+/// address = "/ip4/127.0.0.1/tcp/7001"
+///
+/// [client]
+/// bind_address = "127.0.0.1"
+/// max_request_size = 65536
+/// dial_back = false
+///
+/// [timeouts]
+/// retransmission = 1000
+///
+/// [batching]
+/// max_in_flight_requests = 64
+///
+/// [non_determinism]
+/// max_clock_skew = 5000
+///
+/// [transport]
+/// noise = "x25519_spec"
+/// timeout = 20000
+///
+/// [protocol]
+/// versions = ["protobuf_v3", "json_v3", "protobuf_v2", "json_v1"]
+///
+/// [logging]
+/// execution_log = "execution.log"
+///
+/// [primary_selection]
+/// policy = "round_robin"
+/// ```
+///
+/// Relative paths are resolved against the directory of the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub index: usize,
+    pub key_file: PathBuf,
+    pub listen: Vec<String>,
+    pub client_port: u16,
+    pub f: usize,
+    pub peers: Vec<PeerConfig>,
+    #[serde(default)]
+    pub client: ClientConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    #[serde(default)]
+    pub batching: BatchingConfig,
+    #[serde(default)]
+    pub non_determinism: NonDeterminismConfig,
+    /// Has to be the same on every replica
+    #[serde(default)]
+    pub transport: TransportConfig,
+    #[serde(default)]
+    pub protocol: ProtocolConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Has to be the same on every replica
+    #[serde(default)]
+    pub primary_selection: PrimarySelectionConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub peer_id: String,
+    pub address: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// The address the client port is bound to
+    pub bind_address: IpAddr,
+    /// Client messages longer than this, in bytes, are rejected
+    pub max_request_size: usize,
+    /// Replies to clients without a connection to this replica are sent to the address in their request
+    pub dial_back: bool,
+    pub dial_back_attempts: u32,
+    /// Milliseconds between two attempts
+    pub dial_back_retry_interval: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        let dial_back = DialBack::default();
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            max_request_size: MAX_REQUEST_SIZE,
+            dial_back: false,
+            dial_back_attempts: dial_back.attempts,
+            dial_back_retry_interval: dial_back.retry_interval.as_millis() as u64,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn dial_back(&self) -> Option<DialBack> {
+        if !self.dial_back {
+            return None;
+        }
+        Some(DialBack {
+            attempts: self.dial_back_attempts,
+            retry_interval: Duration::from_millis(self.dial_back_retry_interval),
+            ..DialBack::default()
+        })
+    }
+}
+
+/// Timeouts in milliseconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub retransmission: u64,
+    pub open: u64,
+    pub answer: u64,
+    pub processing: u64,
+    pub idle: u64,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            retransmission: 1_000,
+            open: 10_000,
+            answer: 10_000,
+            processing: 10_000,
+            idle: 60_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchingConfig {
+    /// Requests the primary may have proposed but not executed before it answers clients with "busy"
+    pub max_in_flight_requests: u64,
+    /// Requests that may wait for the connection to a peer
+    pub max_pending_requests: usize,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight_requests: 64,
+            max_pending_requests: 256,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NonDeterminismConfig {
+    /// How far, in milliseconds, the timestamp the primary proposes may be ahead of the local clock
+    pub max_clock_skew: u64,
+}
+
+impl Default for NonDeterminismConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_skew: NonDeterminismBounds::default().max_clock_skew,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    /// The Diffie-Hellman keys of the Noise handshake, `x25519_spec` or the legacy `x25519`
+    pub noise: NoiseKeyType,
+    /// Milliseconds a connection may take to be established and upgraded
+    pub timeout: u64,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        let transport = transport::TransportConfig::default();
+        Self {
+            noise: transport.noise,
+            timeout: transport.timeout.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    /// The wire formats offered to and accepted from peers, in order of preference. Two replicas talk as long
+    /// as they share one, so a format is only dropped once every replica prefers a newer one.
+    pub versions: Vec<Name>,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            versions: PbftProtocolConfig::default().versions().to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Where the executed requests are appended. Nothing is written when it is not set.
+    pub execution_log: Option<PathBuf>,
+}
+
+/// The policy that picks the primary of a view, one of:
+///
+/// ```toml
+/// policy = "round_robin"
+///
+/// policy = "weighted"
+/// weights = { "12D3KooW..." = 2 }
+///
+/// policy = "rotating"
+/// every = 10
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PrimarySelectionConfig {
+    #[default]
+    RoundRobin,
+    /// Replicas missing from `weights` have weight 1
+    Weighted { weights: BTreeMap<String, u64> },
+    /// The primary changes every `every` sequence numbers
+    Rotating { every: u64 },
+}
+
+impl PrimarySelectionConfig {
+    pub fn build(&self) -> Result<Box<dyn PrimarySelection + Send>, ConfigError> {
+        match self {
+            PrimarySelectionConfig::RoundRobin => Ok(Box::new(RoundRobin)),
+            PrimarySelectionConfig::Weighted { weights } => {
+                let mut parsed = HashMap::new();
+                for (peer_id, weight) in weights.iter() {
+                    let key = format!("primary_selection.weights.{}", peer_id);
+                    if *weight == 0 {
+                        return Err(ConfigError::new(key, "must be positive"));
+                    }
+                    parsed.insert(parse_peer_id(&key, peer_id)?, *weight);
+                }
+                Ok(Box::new(WeightedRoundRobin::new(parsed)))
+            }
+            PrimarySelectionConfig::Rotating { every } => match Rotating::new(*every) {
+                Ok(rotating) => Ok(Box::new(rotating)),
+                Err(e) => Err(ConfigError::new("primary_selection.every", e)),
+            },
+        }
+    }
+}
+
+/// A config file that can't be used. `key` names the offending key, e.g. `peers[1].address`.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "`{}`: {}", self.key, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl NodeConfig {
+    /// Reads and validates a config file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let toml = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::new("", format!("can't read {:?}: {}", path, e)))?;
+        let mut config: NodeConfig = toml::from_str(&toml).map_err(|e| {
+            let (key, message) = locate_toml_error(&toml, &e);
+            ConfigError::new(key, format!("{:?}: {}", path, message))
+        })?;
+        config.validate()?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        config.key_file = base.join(&config.key_file);
+        if let Some(execution_log) = config.logging.execution_log.take() {
+            config.logging.execution_log = Some(base.join(execution_log));
+        }
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::new("listen", "at least one address is required"));
+        }
+        for (i, address) in self.listen.iter().enumerate() {
+            parse_multiaddr(&format!("listen[{}]", i), address)?;
+        }
+
+        let mut seen = HashSet::new();
+        for (i, peer) in self.peers.iter().enumerate() {
+            let peer_id = parse_peer_id(&format!("peers[{}].peer_id", i), &peer.peer_id)?;
+            if !seen.insert(peer_id) {
+                return Err(ConfigError::new(format!("peers[{}].peer_id", i), "duplicated peer"));
+            }
+            parse_multiaddr(&format!("peers[{}].address", i), &peer.address)?;
+        }
+
+        let replicas = self.peers.len() + 1;
+        if replicas < 3 * self.f + 1 {
+            return Err(ConfigError::new(
+                "f",
+                format!("{} replicas can't tolerate {} faults, at least {} are needed", replicas, self.f, 3 * self.f + 1),
+            ));
+        }
+
+        if self.client.max_request_size == 0 {
+            return Err(ConfigError::new("client.max_request_size", "must be positive"));
+        }
+        if self.client.dial_back && self.client.dial_back_attempts == 0 {
+            return Err(ConfigError::new("client.dial_back_attempts", "must be positive when `client.dial_back` is set"));
+        }
+
+        let timeouts = [
+            ("timeouts.retransmission", self.timeouts.retransmission),
+            ("timeouts.open", self.timeouts.open),
+            ("timeouts.answer", self.timeouts.answer),
+            ("timeouts.processing", self.timeouts.processing),
+            ("timeouts.idle", self.timeouts.idle),
+        ];
+        for (key, value) in timeouts.iter() {
+            if *value == 0 {
+                return Err(ConfigError::new(*key, "must be positive"));
+            }
+        }
+        if self.batching.max_in_flight_requests == 0 {
+            return Err(ConfigError::new("batching.max_in_flight_requests", "must be positive"));
+        }
+        if self.batching.max_pending_requests == 0 {
+            return Err(ConfigError::new("batching.max_pending_requests", "must be positive"));
+        }
+        if self.non_determinism.max_clock_skew == 0 {
+            return Err(ConfigError::new("non_determinism.max_clock_skew", "must be positive"));
+        }
+        if self.transport.timeout == 0 {
+            return Err(ConfigError::new("transport.timeout", "must be positive"));
+        }
+        if self.protocol.versions.is_empty() {
+            return Err(ConfigError::new("protocol.versions", "at least one version is required"));
+        }
+        let mut versions = HashSet::new();
+        for (i, version) in self.protocol.versions.iter().enumerate() {
+            if !versions.insert(version) {
+                return Err(ConfigError::new(format!("protocol.versions[{}]", i), "duplicated version"));
+            }
+        }
+        self.primary_selection.build()?;
+        Ok(())
+    }
+
+    pub fn client_address(&self) -> SocketAddr {
+        SocketAddr::new(self.client.bind_address, self.client_port)
+    }
+
+    pub fn listen_addresses(&self) -> Vec<Multiaddr> {
+        // Validated by `load`
+        self.listen.iter().filter_map(|a| a.parse().ok()).collect()
+    }
+
+    /// Every member of the cluster but this replica.
+    pub fn peer_ids(&self) -> Vec<PeerId> {
+        self.peer_addresses().into_iter().map(|(peer_id, _)| peer_id).collect()
+    }
+
+    pub fn peer_addresses(&self) -> Vec<(PeerId, Multiaddr)> {
+        self.peers
+            .iter()
+            .filter_map(|p| Some((p.peer_id.parse().ok()?, p.address.parse().ok()?)))
+            .collect()
+    }
+
+    pub fn non_determinism_bounds(&self) -> NonDeterminismBounds {
+        NonDeterminismBounds {
+            max_clock_skew: self.non_determinism.max_clock_skew,
+        }
+    }
+
+    /// Only the configured peers are allowed to connect.
+    pub fn transport_config(&self) -> transport::TransportConfig {
+        transport::TransportConfig {
+            noise: self.transport.noise,
+            allow_list: AllowList::peers(self.peer_ids()),
+            timeout: Duration::from_millis(self.transport.timeout),
+        }
+    }
+
+    pub fn handler_config(&self) -> PbftHandlerConfig {
+        PbftHandlerConfig {
+            open_timeout: Duration::from_millis(self.timeouts.open),
+            answer_timeout: Duration::from_millis(self.timeouts.answer),
+            processing_timeout: Duration::from_millis(self.timeouts.processing),
+            idle_timeout: Duration::from_millis(self.timeouts.idle),
+            max_pending_requests: self.batching.max_pending_requests,
+            protocol: PbftProtocolConfig::new(self.protocol.versions.clone()),
+            ..PbftHandlerConfig::default()
+        }
+    }
+}
+
+fn parse_multiaddr(key: &str, value: &str) -> Result<Multiaddr, ConfigError> {
+    value
+        .parse()
+        .map_err(|e| ConfigError::new(key, format!("invalid multiaddr {:?}: {}", value, e)))
+}
+
+fn parse_peer_id(key: &str, value: &str) -> Result<PeerId, ConfigError> {
+    value
+        .parse()
+        .map_err(|e| ConfigError::new(key, format!("invalid PeerId {:?}: {:?}", value, e)))
+}
+
+// `toml` only names the table of a failing key in its message, e.g. "missing field `address` for key `peers`
+// at line 8 column 1", and the reported position doesn't reliably point into the failing entry of an array.
+// Returns the full key path, e.g. `peers[1].address`, and the message without it.
+fn locate_toml_error(toml: &str, error: &toml::de::Error) -> (String, String) {
+    let mut message = error.to_string();
+    let mut key = String::new();
+    if let Some(start) = message.find(" for key `") {
+        let rest = &message[start + " for key `".len()..];
+        if let Some(end) = rest.find('`') {
+            key = rest[..end].to_owned();
+            message = format!("{}{}", &message[..start], &rest[end + 1..]);
+        }
+    }
+
+    // The only array of tables. Find the entry that fails on its own.
+    if key == "peers" {
+        let failing = toml
+            .parse::<toml::Value>()
+            .ok()
+            .and_then(|value| value.get("peers").and_then(|p| p.as_array()).cloned())
+            .and_then(|peers| peers.into_iter().position(|p| p.try_into::<PeerConfig>().is_err()));
+        if let Some(i) = failing {
+            key = format!("peers[{}]", i);
+        }
+    }
+
+    // The field itself is only part of the message
+    for prefix in ["missing field `", "unknown field `", "duplicate field `"].iter() {
+        if let Some(field) = message.strip_prefix(prefix).and_then(|rest| rest.split('`').next()) {
+            key = if key.is_empty() { field.to_owned() } else { format!("{}.{}", key, field) };
+        }
+    }
+    (key, message)
+}
@@ -0,0 +1,100 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+/// The connectivity state of a replica as seen by this node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Connectivity {
+    Connected,
+    /// A dial is in progress. `attempt` is the number of consecutive failed dials before this one.
+    Dialing { attempt: u32 },
+    /// Waiting until `retry_at` (milliseconds) before dialling again.
+    Disconnected { attempt: u32, retry_at: u64 },
+}
+
+/// Exponential backoff between redials: `initial`, `initial * 2`, ... capped at `max`, in milliseconds.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: u64,
+    pub max: u64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { initial: 500, max: 30_000 }
+    }
+}
+
+impl Backoff {
+    fn delay(&self, attempt: u32) -> u64 {
+        let delay = self.initial.saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX));
+        std::cmp::min(delay, self.max)
+    }
+}
+
+/// Tracks the connectivity of every replica and decides when disconnected ones are redialled.
+pub struct PeerConnectivity {
+    peers: HashMap<PeerId, Connectivity>,
+    backoff: Backoff,
+}
+
+impl PeerConnectivity {
+    pub fn new(backoff: Backoff) -> Self {
+        Self {
+            peers: HashMap::new(),
+            backoff,
+        }
+    }
+
+    pub fn dialing(&mut self, peer_id: &PeerId) {
+        let attempt = match self.peers.get(peer_id) {
+            Some(Connectivity::Disconnected { attempt, .. }) => *attempt,
+            Some(Connectivity::Dialing { attempt }) => *attempt,
+            _ => 0,
+        };
+        self.peers.insert(*peer_id, Connectivity::Dialing { attempt });
+    }
+
+    pub fn connected(&mut self, peer_id: &PeerId) {
+        self.peers.insert(*peer_id, Connectivity::Connected);
+    }
+
+    /// The connection was lost. The first redial is scheduled after the initial backoff.
+    pub fn disconnected(&mut self, peer_id: &PeerId, now: u64) {
+        self.schedule_retry(peer_id, 0, now);
+    }
+
+    /// A dial failed. The next one is scheduled with a longer backoff.
+    pub fn dial_failed(&mut self, peer_id: &PeerId, now: u64) {
+        let attempt = match self.peers.get(peer_id) {
+            Some(Connectivity::Dialing { attempt }) | Some(Connectivity::Disconnected { attempt, .. }) => attempt + 1,
+            _ => 1,
+        };
+        self.schedule_retry(peer_id, attempt, now);
+    }
+
+    /// Returns the peers whose backoff has elapsed and marks them as being dialled.
+    pub fn due(&mut self, now: u64) -> Vec<PeerId> {
+        let due: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter_map(|(peer_id, connectivity)| match connectivity {
+                Connectivity::Disconnected { retry_at, .. } if *retry_at <= now => Some(*peer_id),
+                _ => None,
+            })
+            .collect();
+
+        for peer_id in due.iter() {
+            self.dialing(peer_id);
+        }
+        due
+    }
+
+    fn schedule_retry(&mut self, peer_id: &PeerId, attempt: u32, now: u64) {
+        let retry_at = now + self.backoff.delay(attempt);
+        println!(
+            "[PeerConnectivity::schedule_retry] peer_id: {:?}, attempt: {}, retry_at: {}",
+            peer_id, attempt, retry_at
+        );
+        self.peers.insert(*peer_id, Connectivity::Disconnected { attempt, retry_at });
+    }
+}
@@ -0,0 +1,36 @@
+use crate::message::{DecodeError, RejectReason};
+
+/// Everything that can go wrong while a replica processes a message.
+#[derive(Debug)]
+pub enum PbftError {
+    /// A message failed validation. The sender is told why with a Nack.
+    Validation(RejectReason),
+    /// Bytes received from a peer or a client could not be decoded.
+    Decode(DecodeError),
+    /// The local log or state is not as expected.
+    Storage(String),
+}
+
+impl std::fmt::Display for PbftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PbftError::Validation(reason) => write!(f, "invalid message: {}", reason),
+            PbftError::Decode(e) => write!(f, "decode error: {}", e),
+            PbftError::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PbftError {}
+
+impl From<RejectReason> for PbftError {
+    fn from(reason: RejectReason) -> Self {
+        PbftError::Validation(reason)
+    }
+}
+
+impl From<DecodeError> for PbftError {
+    fn from(error: DecodeError) -> Self {
+        PbftError::Decode(error)
+    }
+}
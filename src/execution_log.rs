@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// An executed request, as recorded in the execution log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExecutionEntry {
+    pub sequence_number: u64,
+    pub view: u64,
+    pub digest: String,
+    pub operation: String,
+    pub timestamp: u64,
+    pub result: String,
+}
+
+/// Appends the requests a replica executes to a file, one JSON object per line, so that what it executed
+/// can be inspected after it stopped or crashed.
+pub struct ExecutionLog {
+    file: File,
+}
+
+impl ExecutionLog {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// The entry is flushed to disk before this returns.
+    pub fn append(&mut self, entry: &ExecutionEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+}
+
+/// Reads an execution log. A truncated last line, as left by a crash in the middle of a write, is ignored.
+pub fn read(path: &Path) -> std::io::Result<Vec<ExecutionEntry>> {
+    let lines: Vec<String> = BufReader::new(File::open(path)?).lines().collect::<Result<_, _>>()?;
+    let mut entries = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if i + 1 == lines.len() => {
+                eprintln!("[execution_log::read] Ignored a truncated last line: {:?}", line);
+            }
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: {}", i + 1, e),
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
@@ -1,84 +1,136 @@
-use crate::behavior::PbftFailure;
-use crate::message::{Commit, Message, PrePrepare, Prepare};
-use crate::protocol_config::{PbftInStreamSink, PbftOutStreamSink, PbftProtocolConfig};
-use core::task::Poll;
-use futures::sink::Sink;
-use futures::stream::Stream;
-use libp2p::core::Negotiated;
+use crate::bounded_queue::{BoundedQueue, Priority};
+use crate::error::PbftError;
+use crate::message::{
+    Acknowledgement, ClientRequest, Commit, DecodeError, Frame, Message, PrePrepare, Prepare, StateReply, StateRequest,
+};
+use crate::protocol_config::{PbftProtocolConfig, PbftStream};
+use futures::future::FutureExt;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
 use libp2p::swarm::protocols_handler::{
     KeepAlive, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr, SubstreamProtocol,
 };
-use libp2p::swarm::ProtocolsHandler;
-use libp2p::{InboundUpgrade, OutboundUpgrade};
-use std::collections::VecDeque;
-use tokio::prelude::{Async, AsyncRead, AsyncSink, AsyncWrite};
+use libp2p::swarm::{NegotiatedSubstream, ProtocolsHandler};
+use std::collections::{HashMap, VecDeque};
+use std::task::{Context, Poll};
+use futures_timer::Delay;
+use std::time::{Duration, Instant};
 
 /// Event to send to the handler.
 #[derive(Debug)]
 pub enum PbftHandlerIn {
     PrePrepareRequest(PrePrepare),
-    PrePrepareResponse(Vec<u8>, ConnectionId),
+    PrePrepareResponse(Acknowledgement, ConnectionId),
     PrepareRequest(Prepare),
-    PrepareResponse(Vec<u8>, ConnectionId),
+    PrepareResponse(Acknowledgement, ConnectionId),
     CommitRequest(Commit),
-    CommitResponse(Vec<u8>, ConnectionId),
+    CommitResponse(Acknowledgement, ConnectionId),
+    /// A client request a backup hands to the primary.
+    ForwardedRequest(ClientRequest),
+    ForwardedResponse(Acknowledgement, ConnectionId),
+    StateRequest(StateRequest),
+    StateReply(StateReply),
+    /// Answers a `StateRequest` or a `StateReply`.
+    StateResponse(Acknowledgement, ConnectionId),
+}
+
+/// Deadlines of the handler.
+#[derive(Clone, Debug)]
+pub struct PbftHandlerConfig {
+    /// Negotiating the outbound substream.
+    pub open_timeout: Duration,
+    /// Waiting for the remote to answer a request.
+    pub answer_timeout: Duration,
+    /// Waiting for the behaviour to process a received request.
+    pub processing_timeout: Duration,
+    /// How long the connection is kept alive once no request is pending.
+    pub idle_timeout: Duration,
+    /// The wire formats offered to and accepted from peers.
+    pub protocol: PbftProtocolConfig,
+    /// How many requests may wait for the outbound substream. Normal-case requests beyond that are dropped.
+    pub max_pending_requests: usize,
+    /// How many inbound substreams the remote may keep open at once. It only needs one, new substreams
+    /// beyond that are closed right away.
+    pub max_inbound_substreams: usize,
+}
+
+impl Default for PbftHandlerConfig {
+    fn default() -> Self {
+        Self {
+            open_timeout: Duration::from_secs(10),
+            answer_timeout: Duration::from_secs(10),
+            processing_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60),
+            protocol: PbftProtocolConfig::default(),
+            max_pending_requests: 256,
+            max_inbound_substreams: 4,
+        }
+    }
 }
 
-pub struct PbftHandler<TSubstream>
-where
-    TSubstream: AsyncRead + AsyncWrite,
-{
+/// Talks to one peer over long-lived substreams instead of a substream per message.
+///
+/// The handler opens a single outbound substream on which it sends its requests, and accepts the inbound
+/// substreams the remote opens to send its own. Every request carries an id, and the answer to it comes
+/// back on the same substream with the same id, so any number of requests can be in flight at once.
+pub struct PbftHandler {
     config: PbftProtocolConfig,
-    substreams: VecDeque<SubstreamState<Negotiated<TSubstream>>>,
+    handler_config: PbftHandlerConfig,
+    outbound: OutboundState,
+    // Requests waiting to be written to the outbound substream
+    pending_requests: BoundedQueue<Message>,
+    // Requests written to the outbound substream, by request id, with the timer for their answer
+    waiting_answers: HashMap<u64, (Message, Delay)>,
+    next_request_id: u64,
+    inbound: HashMap<u64, InboundSubstream>,
+    next_inbound_id: u64,
+    // Received requests waiting for the behaviour to answer: (inbound substream, request id, timer)
+    waiting_processing: HashMap<ConnectionId, (u64, u64, Delay)>,
+    // Events to report to the behaviour
+    pending_events: VecDeque<PbftHandlerEvent>,
     next_connection_id: ConnectionId,
-    _marker: std::marker::PhantomData<TSubstream>,
+    keep_alive: KeepAlive,
+}
+
+impl std::fmt::Debug for PbftHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PbftHandler")
+            .field("pending_requests", &self.pending_requests.len())
+            .field("waiting_answers", &self.waiting_answers.len())
+            .field("inbound", &self.inbound.len())
+            .field("waiting_processing", &self.waiting_processing.len())
+            .finish()
+    }
 }
 
-/// Unique identifier for a connection.
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// Unique identifier for a request received from the remote, used to route the answer back to it.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct ConnectionId(u64);
 
 impl ConnectionId {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self(0)
     }
 
-    fn next_id(&mut self) -> Self {
+    pub fn next_id(&mut self) -> Self {
         let next = self.0;
         self.0 += 1;
-        println!(
-            "[ConnectionId::next_id] issued the connection_id: {:?}",
-            next
-        );
         Self(next)
     }
 }
 
-enum SubstreamState<TSubstream>
-where
-    TSubstream: AsyncRead + AsyncWrite,
-{
-    /// We haven't started opening the outgoing substream yet.
-    /// Contains the request we want to send, and the user data if we expect an answer.
-    OutPendingOpen(Message),
-    /// Waiting to send a message to the remote.
-    OutPendingSend(PbftOutStreamSink<TSubstream>, Message),
-    /// Waiting to flush the substream so that the data arrives to the remote.
-    OutPendingFlush(PbftOutStreamSink<TSubstream>),
-    // TODO: add timeout
-    OutWaitingAnswer(PbftOutStreamSink<TSubstream>),
-    /// The substream is being closed.
-    OutClosing(PbftOutStreamSink<TSubstream>),
-    /// Waiting for a request from the remote.
-    InWaitingMessage(ConnectionId, PbftInStreamSink<TSubstream>),
-    /// Waiting to send a `PbftHandlerIn` event containing the response.
-    InWaitingToProcessMessage(ConnectionId, PbftInStreamSink<TSubstream>),
-    /// Waiting to send an answer back to the remote.
-    InPendingSend(PbftInStreamSink<TSubstream>, Vec<u8>),
-    /// Waiting to flush an answer back to the remote.
-    InPendingFlush(PbftInStreamSink<TSubstream>),
-    /// The substream is being closed.
-    InClosing(PbftInStreamSink<TSubstream>),
+enum OutboundState {
+    /// No outbound substream. One is requested as soon as there is something to send.
+    Closed,
+    /// Waiting for the outbound substream to be negotiated.
+    Opening,
+    Open(Box<PbftStream<NegotiatedSubstream>>),
+}
+
+struct InboundSubstream {
+    substream: PbftStream<NegotiatedSubstream>,
+    // Answers waiting to be written
+    pending_responses: VecDeque<Frame>,
 }
 
 #[derive(Debug)]
@@ -88,7 +140,21 @@ pub enum PbftHandlerEvent {
         connection_id: ConnectionId,
     },
     Response {
-        response: Vec<u8>,
+        request: Message,
+        response: Acknowledgement,
+    },
+    /// The request could not be delivered to the remote.
+    SendFailed {
+        request: Message,
+    },
+    /// The request was dropped because too many requests are waiting for the remote.
+    QueueFull {
+        request: Message,
+    },
+    /// A request stayed unanswered past its deadline. `request` is set for our own requests, and is `None`
+    /// for a request of the remote that the behaviour didn't answer in time.
+    Timeout {
+        request: Option<Message>,
     },
     ProcessPrepareRequest {
         request: Prepare,
@@ -98,439 +164,425 @@ pub enum PbftHandlerEvent {
         request: Commit,
         connection_id: ConnectionId,
     },
+    ProcessForwardedRequest {
+        request: ClientRequest,
+        connection_id: ConnectionId,
+    },
+    ProcessStateRequest {
+        request: StateRequest,
+        connection_id: ConnectionId,
+    },
+    ProcessStateReply {
+        request: StateReply,
+        connection_id: ConnectionId,
+    },
+    /// The remote sent bytes that could not be decoded, or a message it is not supposed to send.
+    InvalidMessage {
+        error: DecodeError,
+    },
 }
 
-impl<TSubstream> PbftHandler<TSubstream>
-where
-    TSubstream: AsyncRead + AsyncWrite,
-{
-    pub fn new() -> Self {
+impl PbftHandler {
+    pub fn new(handler_config: PbftHandlerConfig) -> Self {
         Self {
-            config: PbftProtocolConfig {},
-            substreams: VecDeque::with_capacity(100), // FIXME
+            config: handler_config.protocol.clone(),
+            pending_requests: BoundedQueue::new(handler_config.max_pending_requests),
+            handler_config,
+            outbound: OutboundState::Closed,
+            waiting_answers: HashMap::new(),
+            next_request_id: 0,
+            inbound: HashMap::new(),
+            next_inbound_id: 0,
+            waiting_processing: HashMap::new(),
+            pending_events: VecDeque::new(),
             next_connection_id: ConnectionId::new(),
-            _marker: std::marker::PhantomData,
+            keep_alive: KeepAlive::Yes,
+        }
+    }
+
+    fn queue_request(&mut self, request: Message) {
+        let priority = Priority::of(&request);
+        if let Err(dropped) = self.pending_requests.push(request, priority) {
+            eprintln!("[PbftHandler::queue_request] the queue is full, dropped: {:?}", dropped);
+            self.pending_events
+                .push_back(PbftHandlerEvent::QueueFull { request: dropped });
+        }
+    }
+
+    fn queue_response(&mut self, response: Acknowledgement, connection_id: ConnectionId) {
+        match self.waiting_processing.remove(&connection_id) {
+            Some((inbound_id, request_id, _)) => match self.inbound.get_mut(&inbound_id) {
+                Some(inbound) => inbound.pending_responses.push_back(Frame::Response {
+                    id: request_id,
+                    ack: response,
+                }),
+                None => eprintln!("[PbftHandler::queue_response] the inbound substream has been closed, connection_id: {:?}", connection_id),
+            },
+            None => eprintln!("[PbftHandler::queue_response] the request is not found, it may have expired, connection_id: {:?}", connection_id),
         }
     }
 
-    fn find_waiting_substream_state_pos(&self, connection_id: &ConnectionId) -> Option<usize> {
-        self.substreams.iter().position(|state| match state {
-            SubstreamState::InWaitingToProcessMessage(substream_connection_id, _) => {
-                substream_connection_id == connection_id
+    // The outbound substream is gone. Everything written to it that wasn't answered has to be sent again.
+    fn close_outbound(&mut self) {
+        self.outbound = OutboundState::Closed;
+        for (_, (request, _)) in self.waiting_answers.drain() {
+            self.pending_events
+                .push_back(PbftHandlerEvent::SendFailed { request });
+        }
+    }
+
+    fn poll_outbound(&mut self, cx: &mut Context) {
+        let mut substream = match std::mem::replace(&mut self.outbound, OutboundState::Closed) {
+            OutboundState::Open(substream) => substream,
+            other => {
+                self.outbound = other;
+                return;
+            }
+        };
+
+        // Write the pending requests
+        while !self.pending_requests.is_empty() {
+            match substream.poll_ready_unpin(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Pending => break,
+                Poll::Ready(Err(e)) => {
+                    println!("[PbftHandler::poll_outbound] [poll_ready::Err] Err: {:?}", e);
+                    self.close_outbound();
+                    return;
+                }
             }
-            _ => false,
-        })
+            let request = match self.pending_requests.pop() {
+                Some(request) => request,
+                None => break,
+            };
+            if !substream.codec().name().carries(&request) {
+                eprintln!(
+                    "[PbftHandler::poll_outbound] {:?} can't carry the request, dropped: {:?}",
+                    substream.codec().name(),
+                    request
+                );
+                self.pending_events
+                    .push_back(PbftHandlerEvent::SendFailed { request });
+                continue;
+            }
+            let id = self.next_request_id;
+            match substream.start_send_unpin(Frame::Request { id, message: request.clone() }) {
+                Ok(()) => {
+                    self.next_request_id += 1;
+                    let timeout = Delay::new(self.handler_config.answer_timeout);
+                    self.waiting_answers.insert(id, (request, timeout));
+                }
+                Err(e) => {
+                    println!("[PbftHandler::poll_outbound] [start_send::Err] Err: {:?}", e);
+                    self.pending_events
+                        .push_back(PbftHandlerEvent::SendFailed { request });
+                    self.close_outbound();
+                    return;
+                }
+            }
+        }
+
+        if let Poll::Ready(Err(e)) = substream.poll_flush_unpin(cx) {
+            println!("[PbftHandler::poll_outbound] [poll_flush::Err] Err: {:?}", e);
+            self.close_outbound();
+            return;
+        }
+
+        // Read the answers
+        loop {
+            match substream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Frame::Response { id, ack: response }))) => {
+                    println!("[PbftHandler::poll_outbound] [Ready::Some] id: {}, response: {:?}", id, response);
+                    match self.waiting_answers.remove(&id) {
+                        Some((request, _)) => self
+                            .pending_events
+                            .push_back(PbftHandlerEvent::Response { request, response }),
+                        None => eprintln!("[PbftHandler::poll_outbound] unknown or expired request id: {}", id),
+                    }
+                }
+                Poll::Ready(Some(Ok(frame))) => {
+                    eprintln!("[PbftHandler::poll_outbound] unexpected frame on the outbound substream: {:?}", frame);
+                    self.pending_events.push_back(PbftHandlerEvent::InvalidMessage {
+                        error: DecodeError::UnexpectedMessage("Request on an outbound substream"),
+                    });
+                }
+                Poll::Pending => break,
+                Poll::Ready(None) => {
+                    println!("[PbftHandler::poll_outbound] [Ready::None] the remote closed the substream");
+                    self.close_outbound();
+                    return;
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    println!("[PbftHandler::poll_outbound] [Err] Err: {:?}", e);
+                    if let Some(error) = self.config.decode_error(&e) {
+                        self.pending_events
+                            .push_back(PbftHandlerEvent::InvalidMessage { error });
+                    }
+                    self.close_outbound();
+                    return;
+                }
+            }
+        }
+
+        self.outbound = OutboundState::Open(substream);
+    }
+
+    fn poll_inbound(&mut self, cx: &mut Context) {
+        let inbound_ids: Vec<u64> = self.inbound.keys().cloned().collect();
+        for inbound_id in inbound_ids {
+            if !self.poll_inbound_substream(cx, inbound_id) {
+                println!("[PbftHandler::poll_inbound] inbound substream closed: {}", inbound_id);
+                self.inbound.remove(&inbound_id);
+                self.waiting_processing
+                    .retain(|_, (id, _, _)| *id != inbound_id);
+            }
+        }
+    }
+
+    // Returns `false` once the substream is closed.
+    fn poll_inbound_substream(&mut self, cx: &mut Context, inbound_id: u64) -> bool {
+        let inbound = match self.inbound.get_mut(&inbound_id) {
+            Some(inbound) => inbound,
+            None => return false,
+        };
+
+        // Write the answers
+        while !inbound.pending_responses.is_empty() {
+            match inbound.substream.poll_ready_unpin(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Pending => break,
+                Poll::Ready(Err(e)) => {
+                    println!("[PbftHandler::poll_inbound_substream] [poll_ready::Err] Err: {:?}", e);
+                    return false;
+                }
+            }
+            if let Some(frame) = inbound.pending_responses.pop_front() {
+                if let Err(e) = inbound.substream.start_send_unpin(frame) {
+                    println!("[PbftHandler::poll_inbound_substream] [start_send::Err] Err: {:?}", e);
+                    return false;
+                }
+            }
+        }
+        if let Poll::Ready(Err(e)) = inbound.substream.poll_flush_unpin(cx) {
+            println!("[PbftHandler::poll_inbound_substream] [poll_flush::Err] Err: {:?}", e);
+            return false;
+        }
+
+        // Read the requests
+        let mut received = Vec::new();
+        let mut invalid = Vec::new();
+        let open = loop {
+            match inbound.substream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Frame::Request { id, message }))) => {
+                    println!("[PbftHandler::poll_inbound_substream] [Ready(Some)] id: {}, message: {:?}", id, message);
+                    received.push((id, message));
+                }
+                Poll::Ready(Some(Ok(frame))) => {
+                    eprintln!("[PbftHandler::poll_inbound_substream] unexpected frame on an inbound substream: {:?}", frame);
+                    invalid.push(DecodeError::UnexpectedMessage("Response on an inbound substream"));
+                }
+                Poll::Pending => break true,
+                Poll::Ready(None) => {
+                    println!("[PbftHandler::poll_inbound_substream] [Ready(None)] Inbound substream EOF");
+                    break false;
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    println!("[PbftHandler::poll_inbound_substream] [Err] Err: {:?}", e);
+                    invalid.extend(self.config.decode_error(&e));
+                    break false;
+                }
+            }
+        };
+
+        for (request_id, message) in received {
+            let connection_id = self.next_connection_id.next_id();
+            let event = message_to_handler_event(message, connection_id.clone());
+            let timeout = Delay::new(self.handler_config.processing_timeout);
+            self.waiting_processing
+                .insert(connection_id, (inbound_id, request_id, timeout));
+            self.pending_events.push_back(event);
+        }
+        for error in invalid {
+            self.pending_events
+                .push_back(PbftHandlerEvent::InvalidMessage { error });
+        }
+        open
+    }
+
+    // Polling the timers registers the task to be woken up when the next one fires, so that a request
+    // expires on time even if nothing else happens on the connection.
+    fn expire(&mut self, cx: &mut Context) {
+        let expired: Vec<u64> = self
+            .waiting_answers
+            .iter_mut()
+            .filter_map(|(id, (_, timeout))| timeout.poll_unpin(cx).is_ready().then_some(*id))
+            .collect();
+        for id in expired {
+            if let Some((request, _)) = self.waiting_answers.remove(&id) {
+                println!("[PbftHandler::expire] no answer to the request: {:?}", request);
+                self.pending_events.push_back(PbftHandlerEvent::Timeout {
+                    request: Some(request),
+                });
+            }
+        }
+
+        let expired: Vec<ConnectionId> = self
+            .waiting_processing
+            .iter_mut()
+            .filter_map(|(connection_id, (_, _, timeout))| {
+                timeout.poll_unpin(cx).is_ready().then(|| connection_id.clone())
+            })
+            .collect();
+        for connection_id in expired {
+            println!("[PbftHandler::expire] the request was not processed in time: {:?}", connection_id);
+            self.waiting_processing.remove(&connection_id);
+            self.pending_events
+                .push_back(PbftHandlerEvent::Timeout { request: None });
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pending_requests.is_empty()
+            && self.waiting_answers.is_empty()
+            && self.waiting_processing.is_empty()
+            && self.pending_events.is_empty()
+            && self.inbound.values().all(|i| i.pending_responses.is_empty())
     }
 }
 
-impl<TSubstream> ProtocolsHandler for PbftHandler<TSubstream>
-where
-    TSubstream: AsyncRead + AsyncWrite,
-{
+impl ProtocolsHandler for PbftHandler {
     type InEvent = PbftHandlerIn;
     type OutEvent = PbftHandlerEvent;
-    type Error = PbftFailure;
-    type Substream = TSubstream;
+    type Error = PbftError;
     type InboundProtocol = PbftProtocolConfig;
     type OutboundProtocol = PbftProtocolConfig;
-    type OutboundOpenInfo = Message;
-    
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
 
-    fn listen_protocol(&self) -> SubstreamProtocol<PbftProtocolConfig, TInfo::println!("PbftHandler::listen_protocol()")>;     SubstreamProtocol::new(self.config.clone());
+    fn listen_protocol(&self) -> SubstreamProtocol<PbftProtocolConfig, ()> {
+        println!("PbftHandler::listen_protocol()");
+        SubstreamProtocol::new(self.config.clone(), ()).with_timeout(self.handler_config.open_timeout)
     }
 
-    fn inject_fully_negotiated_inbound(
-        &mut self,
-        protocol: <Self::InboundProtocol as InboundUpgrade<TSubstream>>::Output,
-    ) {
+    fn inject_fully_negotiated_inbound(&mut self, protocol: PbftStream<NegotiatedSubstream>, _info: ()) {
         println!("PbftHandler::inject_fully_negotiated_inbound()");
-        self.substreams.push_back(SubstreamState::InWaitingMessage(
-            self.next_connection_id.next_id(),
-            protocol,
-        ));
+        if self.inbound.len() >= self.handler_config.max_inbound_substreams {
+            // Dropping the substream closes it
+            eprintln!(
+                "[PbftHandler::inject_fully_negotiated_inbound] Rejected an inbound substream, the remote already has {} open",
+                self.inbound.len()
+            );
+            return;
+        }
+        let inbound_id = self.next_inbound_id;
+        self.next_inbound_id += 1;
+        self.inbound.insert(
+            inbound_id,
+            InboundSubstream {
+                substream: protocol,
+                pending_responses: VecDeque::new(),
+            },
+        );
     }
 
-    fn inject_fully_negotiated_outbound(
-        &mut self,
-        protocol: <Self::OutboundProtocol as OutboundUpgrade<TSubstream>>::Output,
-        message: Self::OutboundOpenInfo,
-    ) {
+    fn inject_fully_negotiated_outbound(&mut self, protocol: PbftStream<NegotiatedSubstream>, _info: ()) {
         println!("PbftHandler::inject_fully_negotiated_outbound()");
-        self.substreams
-            .push_back(SubstreamState::OutPendingSend(protocol, message));
+        self.outbound = OutboundState::Open(Box::new(protocol));
     }
 
     fn inject_event(&mut self, handler_in: PbftHandlerIn) {
         println!("[PbftHandler::inject_event] handler_in: {:?}", handler_in);
         match handler_in {
             PbftHandlerIn::PrePrepareRequest(request) => {
-                self.substreams
-                    .push_back(SubstreamState::OutPendingOpen(Message::PrePrepare(request)));
-            }
-            PbftHandlerIn::PrePrepareResponse(response, connection_id) => {
-                println!("[PbftHandler::inject_event] [PbftHandlerIn::PrePrepareResponse] response: {:?}, connection_id: {:?}", response, connection_id);
-                let pos = self.substreams.iter().position(|state| match state {
-                    SubstreamState::InWaitingToProcessMessage(substream_connection_id, _) => {
-                        substream_connection_id.clone() == connection_id
-                    }
-                    _ => false,
-                });
-
-                if let Some(pos) = pos {
-                    let (_connection_id, substream) = match self.substreams.remove(pos) {
-                        Some(SubstreamState::InWaitingToProcessMessage(
-                            connection_id,
-                            substream,
-                        )) => (connection_id, substream),
-                        _ => unreachable!(),
-                    };
-                    self.substreams
-                        .push_back(SubstreamState::InPendingSend(substream, response));
-                } else {
-                    panic!("[PbftHandler::inject_event] [PbftHandlerIn::PrePrepareResponse] substream state is not found, pos: {:?}, connection_id: {:?}", pos, connection_id);
-                }
+                self.queue_request(Message::PrePrepare(request));
             }
             PbftHandlerIn::PrepareRequest(request) => {
-                println!(
-                    "[PbftHandler::inject_event] [PbftHandlerIn::PrepareRequest] request: {:?}",
-                    request
-                );
-                self.substreams
-                    .push_back(SubstreamState::OutPendingOpen(Message::Prepare(request)));
-            }
-            PbftHandlerIn::PrepareResponse(response, connection_id) => {
-                println!("[PbftHandler::inject_event] [PbftHandlerIn::PrepareResponse] response: {:?}, connection_id: {:?}", response, connection_id);
-
-                if let Some(pos) = self.find_waiting_substream_state_pos(&connection_id) {
-                    let (_connection_id, substream) = match self.substreams.remove(pos) {
-                        Some(SubstreamState::InWaitingToProcessMessage(
-                            connection_id,
-                            substream,
-                        )) => (connection_id, substream),
-                        _ => unreachable!(),
-                    };
-                    self.substreams
-                        .push_back(SubstreamState::InPendingSend(substream, response));
-                } else {
-                    panic!("[PbftHandler::inject_event] [PbftHandlerIn::PrepareResponse] substream state is not found, connection_id: {:?}", connection_id);
-                }
+                self.queue_request(Message::Prepare(request));
             }
             PbftHandlerIn::CommitRequest(request) => {
-                println!(
-                    "[PbftHandler::inject_event] [PbftHandlerIn::CommitRequest] request: {:?}",
-                    request
-                );
-                self.substreams
-                    .push_back(SubstreamState::OutPendingOpen(Message::Commit(request)))
+                self.queue_request(Message::Commit(request));
             }
-            PbftHandlerIn::CommitResponse(response, connection_id) => {
-                println!("[PbftHandler::inject_event] [PbftHandlerIn::CommitResponse] response: {:?}, connection_id: {:?}", response, connection_id);
-
-                if let Some(pos) = self.find_waiting_substream_state_pos(&connection_id) {
-                    let (_connection_id, substream) = match self.substreams.remove(pos) {
-                        Some(SubstreamState::InWaitingToProcessMessage(
-                            connection_id,
-                            substream,
-                        )) => (connection_id, substream),
-                        _ => unreachable!(),
-                    };
-                    self.substreams
-                        .push_back(SubstreamState::InPendingSend(substream, response));
-                } else {
-                    panic!("[PbftHandler::inject_event] [PbftHandlerIn::CommitResponse] substream state is not found, connection_id: {:?}", connection_id);
-                }
+            PbftHandlerIn::ForwardedRequest(request) => {
+                self.queue_request(Message::ClientRequest(request));
+            }
+            PbftHandlerIn::StateRequest(request) => {
+                self.queue_request(Message::StateRequest(request));
+            }
+            PbftHandlerIn::StateReply(request) => {
+                self.queue_request(Message::StateReply(request));
+            }
+            PbftHandlerIn::PrePrepareResponse(response, connection_id)
+            | PbftHandlerIn::PrepareResponse(response, connection_id)
+            | PbftHandlerIn::CommitResponse(response, connection_id)
+            | PbftHandlerIn::ForwardedResponse(response, connection_id)
+            | PbftHandlerIn::StateResponse(response, connection_id) => {
+                self.queue_response(response, connection_id);
             }
         }
     }
 
     fn inject_dial_upgrade_error(
         &mut self,
-        info: Message,
+        _info: (),
         error: ProtocolsHandlerUpgrErr<std::io::Error>,
     ) {
         println!(
-            "PbftHandler::inject_dial_upgrade_error(), info: {:?}, error: {:?}",
-            info, error
+            "PbftHandler::inject_dial_upgrade_error(), error: {:?}",
+            error
         );
+        self.outbound = OutboundState::Closed;
+        for request in self.pending_requests.drain() {
+            self.pending_events
+                .push_back(PbftHandlerEvent::SendFailed { request });
+        }
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
         //        println!("PbftHandler::connection_keep_alive()");
-        KeepAlive::Yes
+        self.keep_alive
     }
 
     fn poll(
         &mut self,
-    ) -> Poll<ProtocolsHandlerEvent<PbftProtocolConfig, Message, Self::OutEvent>,TErr>::Self::Error::{
-        println!("[PbftHandler::poll]");
-
-        for _ in 0..self.substreams.len() {
-            if let Some(mut substream_state) = self.substreams.pop_front() {
-                println!("[PbftHandler::poll] [substream_state]");
-
-                loop {
-                    match handle_substream(substream_state, self.config.clone()) {
-                        (Some(new_substream_state), None, true) => {
-                            println!("[PbftHandler::poll] (Some, None false)");
-                            substream_state = new_substream_state;
-                            continue;
-                        }
-                        (Some(new_substream_state), None, false) => {
-                            println!("[PbftHandler::poll] (Some, None, false)");
-                            self.substreams.push_back(new_substream_state);
-                            break;
-                        }
-                        (None, Some(protocol_handler_event), _) => {
-                            println!(
-                                "[PbftHandler::poll] (None, Some, _) protocol_handler_event : {:?}",
-                                protocol_handler_event
-                            );
-                            return Ok(Async::Ready(protocol_handler_event));
-                        }
-                        (Some(new_substream_state), Some(protocol_handler_event), _) => {
-                            println!("[PbftHandler::poll] (Some, Some, _)");
-                            self.substreams.push_back(new_substream_state);
-                            return Ok(Async::Ready(protocol_handler_event));
-                        }
-                        (None, None, _) => {
-                            // TODO
-                            println!("[PbftHandler::poll] (None, None, _)");
-                            break;
-                        }
-                    }
-                }
-            }
-        }
+        cx: &mut Context,
+    ) -> Poll<ProtocolsHandlerEvent<PbftProtocolConfig, (), Self::OutEvent, Self::Error>> {
+        self.poll_outbound(cx);
+        self.poll_inbound(cx);
+        self.expire(cx);
 
-        Ok(Async::NotReady)
-    }
-}
-
-fn handle_substream<TSubstream,TErr>(
-    substream_state: SubstreamState<TSubstream>,
-    config: PbftProtocolConfig,
-) -> (
-    Option<SubstreamState<TSubstream>>,
-    Option<ProtocolsHandlerEvent<PbftProtocolConfig, Message, PbftHandlerEvent, TErr>>,
-    bool, // whether the substream should be polled again
-)
-where
-    TSubstream: AsyncRead + AsyncWrite,
-{
-    match substream_state {
-        SubstreamState::OutPendingOpen(message) => {
-            println!(
-                "[PbftHandler::handle_substream()] [SubstreamState::OutPendingOpen] message: {:?}",
-                message
-            );
-            /*let event = ProtocolsHandlerEvent::OutboundSubstreamRequest {
-                protocol:: SubstreamProtocol::new(config),
-                info: message
-            };
-            */
-            return (None, Some(event), false);
-        }
-        SubstreamState::OutPendingSend(mut substream, message) => {
-            println!(
-                "[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] message: {:?}",
-                message
-            );
-            match substream.start_send(message) {
-                Ok(AsyncSink::Ready) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] [start_send::Ready]");
-                    (Some(SubstreamState::OutPendingFlush(substream)), None, true)
-                }
-                Ok(AsyncSink::NotReady(msg)) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] [start_send::NotReady] msg: {:?}", msg);
-                    (
-                        Some(SubstreamState::OutPendingSend(substream, msg)),
-                        None,
-                        false,
-                    )
-                }
-                Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingSend] [start_send::Err] Err: {:?}", e);
-                    (None, None, false) // TODO
-                }
-            }
-        }
-        SubstreamState::OutPendingFlush(mut substream) => {
-            match substream.poll_complete() {
-                Ok(Async::Ready(())) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingFlush] [Ready]");
-                    (
-                        Some(SubstreamState::OutWaitingAnswer(substream)),
-                        None,
-                        true,
-                    )
-                }
-                Ok(Async::NotReady) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingFlush] [NotReady]");
-                    (
-                        Some(SubstreamState::OutPendingFlush(substream)),
-                        None,
-                        false,
-                    )
-                }
-                Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutPendingFlush] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
-                }
+        // Let the connection idle out once nothing is pending on it
+        if self.is_idle() {
+            if let KeepAlive::Yes = self.keep_alive {
+                self.keep_alive = KeepAlive::Until(Instant::now() + self.handler_config.idle_timeout);
             }
+        } else {
+            self.keep_alive = KeepAlive::Yes;
         }
-        SubstreamState::OutWaitingAnswer(mut substream) => {
-            println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer]");
-            match substream.poll() {
-                Ok(Async::Ready(Some(response))) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Ready::Some] response: {:?}", response);
-                    (
-                        Some(SubstreamState::OutClosing(substream)),
-                        Some(ProtocolsHandlerEvent::Custom(PbftHandlerEvent::Response {
-                            response,
-                        })),
-                        true,
-                    )
-                }
-                Ok(Async::NotReady) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [NotReady]");
-                    (
-                        Some(SubstreamState::OutWaitingAnswer(substream)),
-                        None,
-                        false,
-                    )
-                }
-                Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
-                }
-                Ok(Async::Ready(None)) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutWaitingAnswer] [Ready::None]");
-                    (None, None, false) // TODO
-                }
-            }
-        }
-        SubstreamState::OutClosing(mut substream) => {
-            match substream.close() {
-                Ok(Async::Ready(())) => {
-                    println!(
-                        "[PbftHandler::handle_substream()] [SubstreamState::OutClosing] [Ready]"
-                    );
-                    (None, None, false)
-                }
-                Ok(Async::NotReady) => {
-                    println!(
-                        "[PbftHandler::handle_substream()] [SubstreamState::OutClosing] [NotReady]"
-                    );
-                    (Some(SubstreamState::OutClosing(substream)), None, false)
-                }
-                Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::OutClosing] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
-                }
-            }
-        }
-        SubstreamState::InWaitingMessage(connection_id, mut substream) => {
-            match substream.poll() {
-                Ok(Async::Ready(Some(msg))) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InWaitingMessage] [Ready(Some)] msg: {:?}", msg);
-                    (
-                        Some(SubstreamState::InWaitingToProcessMessage(
-                            connection_id.clone(),
-                            substream,
-                        )),
-                        Some(ProtocolsHandlerEvent::Custom(message_to_handler_event(
-                            msg,
-                            connection_id,
-                        ))),
-                        false,
-                    )
-                }
-                Ok(Async::NotReady) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InWaitingMessage] [NotReady]");
-                    (
-                        Some(SubstreamState::InWaitingMessage(connection_id, substream)),
-                        None,
-                        false,
-                    )
-                }
-                Ok(Async::Ready(None)) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InWaitingMessage] [Ready(None)] Inbound substream EOF");
-                    (None, None, false)
-                }
-                Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InWaitingMessage] [Err] Err: {:?}", e);
-                    (None, None, false) // TODO
-                }
-            }
-        }
-        SubstreamState::InWaitingToProcessMessage(connection_id, substream) => {
-            println!("[PbftHandler::handle_substream()] [SubstreamState::InWaitingUser]");
-            (
-                Some(SubstreamState::InWaitingToProcessMessage(
-                    connection_id,
-                    substream,
-                )),
-                None,
-                false,
-            )
-        }
-        SubstreamState::InPendingSend(mut substream, response) => {
-            match substream.start_send(response) {
-                Ok(AsyncSink::Ready) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InPendingSend] [AsyncSink::Ready]");
-                    (Some(SubstreamState::InPendingFlush(substream)), None, true)
-                }
-                Ok(AsyncSink::NotReady(response)) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InPendingSend] [AsyncSink::NotReady]");
-                    (
-                        Some(SubstreamState::InPendingSend(substream, response)),
-                        None,
-                        false,
-                    )
-                }
-                Err(e) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InPendingSend] [Err]: {:?}", e);
-                    (None, None, false) // TODO
-                }
-            }
+
+        if let Some(event) = self.pending_events.pop_front() {
+            println!("[PbftHandler::poll] event: {:?}", event);
+            return Poll::Ready(ProtocolsHandlerEvent::Custom(event));
         }
-        SubstreamState::InPendingFlush(mut substream) => match substream.poll_complete() {
-            Ok(Async::Ready(())) => {
-                println!("[PbftHandler::handle_substream()] [SubstreamState::InPendingFlush] [Async::Ready]");
-                (Some(SubstreamState::InClosing(substream)), None, true)
-            }
-            Ok(Async::NotReady) => {
-                println!("[PbftHandler::handle_substream()] [SubstreamState::InPendingFlush] [Async::NotReady]");
-                (Some(SubstreamState::InPendingFlush(substream)), None, false)
-            }
-            Err(e) => {
-                println!("[PbftHandler::handle_substream()] [SubstreamState::InPendingFlush] [Err]: {:?}", e);
-                (None, None, false)
-            }
-        },
-        SubstreamState::InClosing(mut substream) => {
-            match substream.close() {
-                Ok(Async::Ready(())) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InClosing] [Async::Ready]");
-                    (None, None, false)
-                }
-                Ok(Async::NotReady) => {
-                    println!("[PbftHandler::handle_substream()] [SubstreamState::InClosing] [Async::NotReady]");
-                    (Some(SubstreamState::InClosing(substream)), None, false)
-                }
-                Err(e) => {
-                    println!(
-                        "[PbftHandler::handle_substream()] [SubstreamState::InClosing] [Err]: {:?}",
-                        e
-                    );
-                    (None, None, false) // TODO
-                }
+
+        if !self.pending_requests.is_empty() {
+            if let OutboundState::Closed = self.outbound {
+                println!("[PbftHandler::poll] opening the outbound substream");
+                self.outbound = OutboundState::Opening;
+                return Poll::Ready(ProtocolsHandlerEvent::OutboundSubstreamRequest {
+                    protocol: SubstreamProtocol::new(self.config.clone(), ())
+                        .with_timeout(self.handler_config.open_timeout),
+                });
             }
         }
+
+        Poll::Pending
     }
 }
 
-fn message_to_handler_event(message: Message, connection_id: ConnectionId) -> PbftHandlerEvent {
+pub fn message_to_handler_event(
+    message: Message,
+    connection_id: ConnectionId,
+) -> PbftHandlerEvent {
     match message {
         Message::PrePrepare(pre_prepare) => PbftHandlerEvent::ProcessPrePrepareRequest {
             request: pre_prepare,
@@ -544,6 +596,18 @@ fn message_to_handler_event(message: Message, connection_id: ConnectionId) -> Pb
             request: commit,
             connection_id,
         },
-        Message::ClientRequest(_) => unreachable!(),
+        // Only forwarded by a backup, clients talk to the client handler
+        Message::ClientRequest(client_request) => PbftHandlerEvent::ProcessForwardedRequest {
+            request: client_request,
+            connection_id,
+        },
+        Message::StateRequest(state_request) => PbftHandlerEvent::ProcessStateRequest {
+            request: state_request,
+            connection_id,
+        },
+        Message::StateReply(state_reply) => PbftHandlerEvent::ProcessStateReply {
+            request: state_reply,
+            connection_id,
+        },
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,432 @@
+use crate::linearizability::{self, HistoryRecorder};
+use crate::simulator::{SimConfig, SimRng, Simulator, CLIENT};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Runs many seeded executions of the simulated cluster and checks the PBFT safety invariants after every
+/// delivered message:
+///
+/// - no two replicas execute different digests at the same sequence number,
+/// - the executed histories of any two replicas are prefixes of each other,
+/// - the view of a replica never decreases.
+///
+/// Once a run is over, the history the client observed has to be linearizable.
+///
+/// A failing run is shrunk by removing faults and client requests until no single removal reproduces the
+/// same kind of violation, so the reported schedule is a minimal one for its seed.
+pub struct InvariantChecker {
+    config: CheckConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct CheckConfig {
+    pub replicas: usize,
+    pub runs: u64,
+    pub first_seed: u64,
+    // Upper bound on the number of client requests and faults in a generated schedule
+    pub max_requests: usize,
+    pub max_faults: usize,
+    // Virtual time window in which requests are submitted and faults are injected
+    pub horizon: u64,
+    pub max_steps: usize,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        Self {
+            replicas: 4,
+            runs: 100,
+            first_seed: 0,
+            max_requests: 5,
+            max_faults: 4,
+            horizon: 100,
+            max_steps: 10_000,
+        }
+    }
+}
+
+/// Everything that happens to a run besides the scheduling decisions drawn from the seed.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    pub seed: u64,
+    pub requests: Vec<ScheduledRequest>,
+    pub faults: Vec<Fault>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScheduledRequest {
+    pub at: u64,
+    pub operation: String,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    DropMessage { id: u64 },
+    Partition { at: u64, groups: Vec<Vec<usize>> },
+    Heal { at: u64 },
+    Crash { at: u64, replica: usize },
+    Restart { at: u64, replica: usize },
+    // The primary at that time sends conflicting PrePrepares from then on
+    Equivocate { at: u64 },
+}
+
+impl Fault {
+    fn at(&self) -> u64 {
+        match self {
+            Fault::DropMessage { .. } => 0,
+            Fault::Partition { at, .. }
+            | Fault::Heal { at }
+            | Fault::Crash { at, .. }
+            | Fault::Restart { at, .. }
+            | Fault::Equivocate { at } => *at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Violation {
+    Agreement { sequence_number: u64, replicas: (usize, usize), digests: (String, String) },
+    Prefix { replicas: (usize, usize), position: usize },
+    ViewDecreased { replica: usize, from: u64, to: u64 },
+    NonLinearizable { key: String, history: Vec<String> },
+    InvalidHistory { message: String },
+    Panicked { message: String },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Violation::Agreement { sequence_number, replicas, digests } => write!(
+                f,
+                "replicas {} and {} executed different digests at sequence number {}: {} / {}",
+                replicas.0, replicas.1, sequence_number, digests.0, digests.1
+            ),
+            Violation::Prefix { replicas, position } => write!(
+                f,
+                "the executed histories of replicas {} and {} diverge at position {}",
+                replicas.0, replicas.1, position
+            ),
+            Violation::ViewDecreased { replica, from, to } => {
+                write!(f, "the view of replica {} decreased from {} to {}", replica, from, to)
+            }
+            Violation::NonLinearizable { key, history } => {
+                write!(f, "the history of key {:?} is not linearizable: {}", key, history.join(", "))
+            }
+            Violation::InvalidHistory { message } => write!(f, "the history can't be checked: {}", message),
+            Violation::Panicked { message } => write!(f, "a replica panicked: {}", message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Counterexample {
+    pub schedule: Schedule,
+    pub violation: Violation,
+    pub step: usize,
+}
+
+impl std::fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "invariant violated at step {}: {}", self.step, self.violation)?;
+        writeln!(f, "seed: {}", self.schedule.seed)?;
+        for request in self.schedule.requests.iter() {
+            writeln!(f, "  request: {:?}", request)?;
+        }
+        for fault in self.schedule.faults.iter() {
+            writeln!(f, "  fault: {:?}", fault)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckError {
+    /// A run violated an invariant.
+    Violated(Box<Counterexample>),
+    /// The shrunk schedule didn't fail again when it was replayed, so the runs aren't deterministic.
+    NotReproducible(Box<Counterexample>),
+}
+
+impl std::fmt::Display for CheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckError::Violated(counterexample) => write!(f, "{}", counterexample),
+            CheckError::NotReproducible(counterexample) => {
+                writeln!(f, "the schedule didn't reproduce the violation when it was replayed")?;
+                write!(f, "{}", counterexample)
+            }
+        }
+    }
+}
+
+impl InvariantChecker {
+    pub fn new(config: CheckConfig) -> Self {
+        Self { config }
+    }
+
+    /// Runs `config.runs` seeded executions. Returns the shrunk counterexample of the first failing one.
+    pub fn check(&self) -> Result<(), CheckError> {
+        for seed in self.config.first_seed..self.config.first_seed + self.config.runs {
+            let schedule = self.generate(seed);
+            if let Err((violation, step)) = self.execute(&schedule) {
+                println!("[InvariantChecker::check] seed {} failed at step {}: {}", seed, step, violation);
+                let counterexample = self.shrink(schedule, violation, step)?;
+                return Err(CheckError::Violated(Box::new(counterexample)));
+            }
+        }
+        println!("[InvariantChecker::check] {} runs passed", self.config.runs);
+        Ok(())
+    }
+
+    fn generate(&self, seed: u64) -> Schedule {
+        // Use a stream independent from the one the simulator draws its scheduling decisions from.
+        let mut rng = SimRng::new(seed ^ 0x5DEE_CE66_D1CE_4E5B);
+        let horizon = self.config.horizon;
+        let replicas = self.config.replicas;
+
+        let request_count = 1 + rng.gen_range(0, self.config.max_requests as u64) as usize;
+        // A few keys, so that operations on the same key overlap
+        let requests = (0..request_count)
+            .map(|i| {
+                let key = ["x", "y"][rng.gen_range(0, 2) as usize];
+                let operation = match rng.gen_range(0, 3) {
+                    0 => format!("GET {}", key),
+                    1 => format!("PUT {} {}", key, i),
+                    _ => format!("DELETE {}", key),
+                };
+                ScheduledRequest {
+                    at: rng.gen_range(0, horizon),
+                    operation,
+                    timestamp: i as u64 + 1,
+                }
+            })
+            .collect();
+
+        let fault_count = rng.gen_range(0, self.config.max_faults as u64 + 1) as usize;
+        let faults = (0..fault_count)
+            .map(|_| match rng.gen_range(0, 6) {
+                0 => Fault::DropMessage { id: rng.gen_range(0, 200) },
+                1 => {
+                    let mut groups = vec![Vec::new(), Vec::new()];
+                    for replica in 0..replicas {
+                        groups[rng.gen_range(0, 2) as usize].push(replica);
+                    }
+                    Fault::Partition { at: rng.gen_range(0, horizon), groups }
+                }
+                2 => Fault::Heal { at: rng.gen_range(0, horizon) },
+                3 => Fault::Crash { at: rng.gen_range(0, horizon), replica: rng.gen_range(0, replicas as u64) as usize },
+                4 => Fault::Restart { at: rng.gen_range(0, horizon), replica: rng.gen_range(0, replicas as u64) as usize },
+                _ => Fault::Equivocate { at: rng.gen_range(0, horizon) },
+            })
+            .collect();
+
+        Schedule { seed, requests, faults }
+    }
+
+    /// Runs a schedule to completion. Returns the first violation and the step it was observed at. A
+    /// non-linearizable history is reported at the last step.
+    fn execute(&self, schedule: &Schedule) -> Result<(), (Violation, usize)> {
+        let mut simulator = Simulator::new(SimConfig::new(self.config.replicas, schedule.seed));
+        let mut views: Vec<u64> = (0..self.config.replicas)
+            .map(|i| simulator.replica(i).current_view())
+            .collect();
+
+        // Requests and timed faults are applied in time order, interleaved with message deliveries.
+        let mut timed: Vec<(u64, Action)> = schedule
+            .requests
+            .iter()
+            .map(|r| (r.at, Action::Request(r.clone())))
+            .chain(schedule.faults.iter().map(|f| (f.at(), Action::Fault(f.clone()))))
+            .collect();
+        timed.sort_by_key(|(at, _)| *at);
+        let mut timed = timed.into_iter().peekable();
+        let mut history = HistoryRecorder::new();
+        let mut replies = 0;
+
+        let mut step = 0;
+        while step < self.config.max_steps {
+            let next_delivery = simulator.next_delivery();
+            // A timed action goes first unless a message is due before it
+            let next_action = timed.next_if(|(at, _)| next_delivery.is_none_or(|d| *at < d));
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| match (next_action, next_delivery) {
+                (Some((at, action)), _) => {
+                    simulator.run_until(at);
+                    if let Action::Request(request) = &action {
+                        history.invoke(CLIENT, request.timestamp, &request.operation, simulator.now());
+                    }
+                    apply(&mut simulator, action);
+                    true
+                }
+                (None, Some(_)) => simulator.step(),
+                (None, None) => false,
+            }));
+
+            match result {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(payload) => return Err((Violation::Panicked { message: panic_message(payload) }, step)),
+            }
+
+            // An equivocating replica only lies in its PrePrepares and still executes what was committed, so the
+            // client accepts the first reply to a request
+            for delivered in simulator.replies()[replies..].iter() {
+                let reply = &delivered.reply;
+                history.complete(&reply.client_address().to_string(), reply.timestamp(), reply.result(), delivered.at);
+            }
+            replies = simulator.replies().len();
+
+            check_invariants(&simulator, &mut views).map_err(|v| (v, step))?;
+            step += 1;
+        }
+
+        match linearizability::check(&history.operations()) {
+            Err(linearizability::CheckError::NonLinearizable { key, history }) => {
+                let history = history.iter().map(|o| o.to_string()).collect();
+                Err((Violation::NonLinearizable { key, history }, step))
+            }
+            Err(e @ linearizability::CheckError::InvalidOperation(..)) => {
+                Err((Violation::InvalidHistory { message: e.to_string() }, step))
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn shrink(&self, mut schedule: Schedule, violation: Violation, step: usize) -> Result<Counterexample, CheckError> {
+        // Greedily remove single faults and requests while the run still fails with the same kind of
+        // violation, until a fixed point. Accepting any violation could shrink a real bug into an unrelated one.
+        let reproduces = |candidate: &Schedule| match self.execute(candidate) {
+            Err((v, _)) => mem::discriminant(&v) == mem::discriminant(&violation),
+            Ok(()) => false,
+        };
+
+        loop {
+            let mut shrunk = false;
+
+            for i in (0..schedule.faults.len()).rev() {
+                let mut candidate = schedule.clone();
+                candidate.faults.remove(i);
+                if reproduces(&candidate) {
+                    schedule = candidate;
+                    shrunk = true;
+                }
+            }
+
+            for i in (0..schedule.requests.len()).rev() {
+                if schedule.requests.len() == 1 {
+                    break;
+                }
+                let mut candidate = schedule.clone();
+                candidate.requests.remove(i);
+                if reproduces(&candidate) {
+                    schedule = candidate;
+                    shrunk = true;
+                }
+            }
+
+            if !shrunk {
+                break;
+            }
+        }
+
+        match self.execute(&schedule) {
+            Err((v, step)) if mem::discriminant(&v) == mem::discriminant(&violation) => {
+                println!(
+                    "[InvariantChecker::shrink] shrunk to {} requests and {} faults",
+                    schedule.requests.len(),
+                    schedule.faults.len()
+                );
+                Ok(Counterexample { schedule, violation: v, step })
+            }
+            _ => Err(CheckError::NotReproducible(Box::new(Counterexample { schedule, violation, step }))),
+        }
+    }
+}
+
+enum Action {
+    Request(ScheduledRequest),
+    Fault(Fault),
+}
+
+fn apply(simulator: &mut Simulator, action: Action) {
+    match action {
+        Action::Request(request) => {
+            let primary = simulator.primary();
+            simulator.submit(primary, &request.operation, request.timestamp)
+        }
+        Action::Fault(Fault::DropMessage { id }) => simulator.drop_message(id),
+        Action::Fault(Fault::Partition { groups, .. }) => {
+            let groups: Vec<&[usize]> = groups.iter().map(|g| g.as_slice()).collect();
+            simulator.partition(&groups);
+        }
+        Action::Fault(Fault::Heal { .. }) => simulator.heal(),
+        Action::Fault(Fault::Crash { replica, .. }) => simulator.crash(replica),
+        Action::Fault(Fault::Restart { replica, .. }) => simulator.restart(replica),
+        Action::Fault(Fault::Equivocate { .. }) => {
+            let primary = simulator.primary();
+            simulator.equivocate(primary)
+        }
+    }
+}
+
+fn check_invariants(simulator: &Simulator, views: &mut [u64]) -> Result<(), Violation> {
+    for (i, last) in views.iter_mut().enumerate() {
+        let view = simulator.replica(i).current_view();
+        if view < *last {
+            return Err(Violation::ViewDecreased { replica: i, from: *last, to: view });
+        }
+        *last = view;
+    }
+
+    for i in 0..simulator.replicas() {
+        for j in (i + 1)..simulator.replicas() {
+            let a = simulator.replica(i).executed();
+            let b = simulator.replica(j).executed();
+
+            for (sequence_number, digest) in a.iter() {
+                if let Some((_, other)) = b.iter().find(|(n, _)| n == sequence_number) {
+                    if digest != other {
+                        return Err(Violation::Agreement {
+                            sequence_number: *sequence_number,
+                            replicas: (i, j),
+                            digests: (digest.clone(), other.clone()),
+                        });
+                    }
+                }
+            }
+
+            if let Some(position) = a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+                return Err(Violation::Prefix { replicas: (i, j), position });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_schedules() {
+        let checker = InvariantChecker::new(CheckConfig {
+            runs: 20,
+            first_seed: 42,
+            ..CheckConfig::default()
+        });
+        if let Err(e) = checker.check() {
+            panic!("{}", e);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac, NewMac};
+use libp2p::identity::{ed25519, Keypair};
+use libp2p::PeerId;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+const VERSION: u32 = 2;
+const CIPHER: &str = "chacha20-poly1305";
+const KDF: &str = "pbkdf2-hmac-sha256";
+// Rounds of the key derivation. It only slows down guessing, a strong passphrase is still needed.
+const KDF_ROUNDS: u32 = 600_000;
+// Key files asking for fewer rounds are rejected rather than making the passphrase cheap to guess
+const MIN_KDF_ROUNDS: u32 = 100_000;
+
+/// The identity key of a node, stored in a file that only its owner can read so that the PeerId survives
+/// restarts. The secret key can be encrypted at rest with ChaCha20-Poly1305 under a key derived from a
+/// passphrase. The rest of the file is authenticated along with it, so that it can't be altered either.
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    // Hex-encoded ed25519 public key, readable without the passphrase
+    public_key: String,
+    // Hex-encoded ed25519 secret key, encrypted when `encryption` is set
+    secret_key: String,
+    encryption: Option<Encryption>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Encryption {
+    cipher: String,
+    kdf: String,
+    kdf_rounds: u32,
+    salt: String,
+    nonce: String,
+}
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(std::io::Error),
+    Format(String),
+    PassphraseRequired,
+    WrongPassphrase,
+    /// The key file is readable or writable by other users than its owner. Holds the file mode.
+    InsecurePermissions(u32),
+}
+
+impl std::fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "{}", e),
+            KeystoreError::Format(e) => write!(f, "invalid key file: {}", e),
+            KeystoreError::PassphraseRequired => f.write_str("the key file is encrypted, a passphrase is required"),
+            KeystoreError::WrongPassphrase => f.write_str("wrong passphrase, or the key file has been altered"),
+            KeystoreError::InsecurePermissions(mode) => {
+                write!(f, "the key file must only be accessible by its owner, its mode is {:o}", mode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<std::io::Error> for KeystoreError {
+    fn from(e: std::io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+/// Generates a new identity and writes it to `path`, which must not exist yet.
+pub fn generate(path: &Path, passphrase: Option<&str>) -> Result<Keypair, KeystoreError> {
+    generate_with_rounds(path, passphrase, KDF_ROUNDS)
+}
+
+fn generate_with_rounds(path: &Path, passphrase: Option<&str>, kdf_rounds: u32) -> Result<Keypair, KeystoreError> {
+    let keypair = ed25519::Keypair::generate();
+    let public_key = to_hex(&keypair.public().encode());
+    let secret = Zeroizing::new(keypair.secret().as_ref().to_vec());
+
+    let (secret_key, encryption) = match passphrase {
+        Some(passphrase) => {
+            let mut salt = [0u8; 16];
+            let mut nonce = [0u8; 12];
+            rand::thread_rng().fill_bytes(&mut salt);
+            rand::thread_rng().fill_bytes(&mut nonce);
+
+            let encryption = Encryption {
+                cipher: CIPHER.to_owned(),
+                kdf: KDF.to_owned(),
+                kdf_rounds,
+                salt: to_hex(&salt),
+                nonce: to_hex(&nonce),
+            };
+            let key = derive_key(passphrase, &salt, kdf_rounds);
+            let aad = header(VERSION, &public_key, &encryption)?;
+            let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&*key))
+                .encrypt(Nonce::from_slice(&nonce), Payload { msg: &secret, aad: &aad })
+                .map_err(|_| KeystoreError::Format("failed to encrypt the secret key".to_owned()))?;
+            (to_hex(&ciphertext), Some(encryption))
+        }
+        None => (to_hex(&secret), None),
+    };
+
+    let key_file = KeyFile {
+        version: VERSION,
+        public_key,
+        secret_key,
+        encryption,
+    };
+    let json = Zeroizing::new(serde_json::to_string_pretty(&key_file).map_err(|e| KeystoreError::Format(e.to_string()))?);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(json.as_bytes())?;
+    println!("[keystore::generate] The key has been written to {:?}", path);
+
+    Ok(Keypair::Ed25519(keypair))
+}
+
+pub fn load(path: &Path, passphrase: Option<&str>) -> Result<Keypair, KeystoreError> {
+    let mut file = OpenOptions::new().read(true).open(path)?;
+    let mode = file.metadata()?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(KeystoreError::InsecurePermissions(mode));
+    }
+    let mut json = Zeroizing::new(String::new());
+    file.read_to_string(&mut json)?;
+    let key_file: KeyFile = serde_json::from_str(&json).map_err(|e| KeystoreError::Format(e.to_string()))?;
+    if key_file.version != VERSION {
+        return Err(KeystoreError::Format(format!(
+            "unsupported version: {}, generate a new key with `pbft keygen`",
+            key_file.version
+        )));
+    }
+
+    let mut secret = Zeroizing::new(from_hex(&key_file.secret_key)?);
+    if let Some(encryption) = key_file.encryption.as_ref() {
+        let passphrase = passphrase.ok_or(KeystoreError::PassphraseRequired)?;
+        if encryption.cipher != CIPHER {
+            return Err(KeystoreError::Format(format!("unsupported cipher: {}", encryption.cipher)));
+        }
+        if encryption.kdf != KDF {
+            return Err(KeystoreError::Format(format!("unsupported kdf: {}", encryption.kdf)));
+        }
+        if encryption.kdf_rounds < MIN_KDF_ROUNDS {
+            return Err(KeystoreError::Format(format!(
+                "too few kdf rounds: {}, at least {} are required",
+                encryption.kdf_rounds, MIN_KDF_ROUNDS
+            )));
+        }
+        let salt = from_hex(&encryption.salt)?;
+        let nonce = from_hex(&encryption.nonce)?;
+        if nonce.len() != 12 {
+            return Err(KeystoreError::Format("the nonce must be 12 bytes".to_owned()));
+        }
+
+        let key = derive_key(passphrase, &salt, encryption.kdf_rounds);
+        let aad = header(key_file.version, &key_file.public_key, encryption)?;
+        secret = Zeroizing::new(
+            ChaCha20Poly1305::new(Key::from_slice(&*key))
+                .decrypt(Nonce::from_slice(&nonce), Payload { msg: &secret, aad: &aad })
+                .map_err(|_| KeystoreError::WrongPassphrase)?,
+        );
+    }
+
+    // Zeroizes `secret` as it goes
+    let secret = ed25519::SecretKey::from_bytes(&mut *secret).map_err(|e| KeystoreError::Format(e.to_string()))?;
+    let keypair = ed25519::Keypair::from(secret);
+    if to_hex(&keypair.public().encode()) != key_file.public_key {
+        return Err(KeystoreError::Format("the public key doesn't match the secret key".to_owned()));
+    }
+    Ok(Keypair::Ed25519(keypair))
+}
+
+/// The hex-encoded public key of an identity, as written to key files.
+pub fn public_key_hex(keypair: &Keypair) -> String {
+    match keypair {
+        Keypair::Ed25519(keypair) => to_hex(&keypair.public().encode()),
+        _ => to_hex(&keypair.public().to_protobuf_encoding()),
+    }
+}
+
+pub fn peer_id(keypair: &Keypair) -> PeerId {
+    PeerId::from(keypair.public())
+}
+
+// Everything in the key file but the secret key, authenticated along with it.
+fn header(version: u32, public_key: &str, encryption: &Encryption) -> Result<Vec<u8>, KeystoreError> {
+    serde_json::to_vec(&(version, public_key, encryption)).map_err(|e| KeystoreError::Format(e.to_string()))
+}
+
+// Stretches the passphrase into a ChaCha20-Poly1305 key with PBKDF2-HMAC-SHA256 (RFC 8018). Checked against
+// the published test vectors below.
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> Zeroizing<[u8; 32]> {
+    // HMAC accepts keys of any length
+    let prf = Hmac::<Sha256>::new_varkey(passphrase.as_bytes()).expect("HMAC takes keys of any size");
+
+    // A single block, as the key is as long as the output of SHA-256
+    let mut block = Zeroizing::new([0u8; 32]);
+    let mut mac = prf.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    block.copy_from_slice(&mac.finalize().into_bytes());
+
+    let mut key = block.clone();
+    for _ in 1..rounds {
+        let mut mac = prf.clone();
+        mac.update(&*block);
+        block.copy_from_slice(&mac.finalize().into_bytes());
+        key.iter_mut().zip(block.iter()).for_each(|(k, b)| *k ^= b);
+    }
+    key
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, KeystoreError> {
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return Err(KeystoreError::Format(format!("odd-length hex string: {}", s)));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| KeystoreError::Format(format!("invalid hex: {}", e)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    // A key file path unique to the test, removed when it is dropped
+    struct TempKeyFile(PathBuf);
+
+    impl TempKeyFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("pbft-keystore-{}-{}.key", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+
+        // Rewrites a field of the key file, keeping its mode
+        fn edit(&self, edit: impl FnOnce(&mut serde_json::Value)) {
+            let mut json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&self.0).unwrap()).unwrap();
+            edit(&mut json);
+            std::fs::write(&self.0, serde_json::to_string(&json).unwrap()).unwrap();
+        }
+    }
+
+    impl Drop for TempKeyFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn derive_key_test_vectors() {
+        // PBKDF2-HMAC-SHA256 with a 32-byte output, from RFC 7914 section 11 and the RFC 6070 inputs
+        let vectors = [
+            ("passwd", "salt", 1, "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"),
+            ("password", "salt", 1, "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"),
+            ("password", "salt", 2, "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"),
+            ("password", "salt", 4096, "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"),
+        ];
+        for (passphrase, salt, rounds, expected) in vectors.iter() {
+            assert_eq!(to_hex(&*derive_key(passphrase, salt.as_bytes(), *rounds)), *expected);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = TempKeyFile::new("round-trip");
+        let keypair = generate_with_rounds(&file.0, Some(PASSPHRASE), MIN_KDF_ROUNDS).unwrap();
+        let loaded = load(&file.0, Some(PASSPHRASE)).unwrap();
+        assert_eq!(peer_id(&loaded), peer_id(&keypair));
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&file.0).unwrap()).unwrap();
+        assert_eq!(json["encryption"]["kdf_rounds"], MIN_KDF_ROUNDS);
+
+        let plain = TempKeyFile::new("round-trip-plain");
+        let keypair = generate(&plain.0, None).unwrap();
+        assert_eq!(peer_id(&load(&plain.0, None).unwrap()), peer_id(&keypair));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let file = TempKeyFile::new("wrong-passphrase");
+        generate_with_rounds(&file.0, Some(PASSPHRASE), MIN_KDF_ROUNDS).unwrap();
+        assert!(matches!(load(&file.0, Some("wrong")), Err(KeystoreError::WrongPassphrase)));
+        assert!(matches!(load(&file.0, None), Err(KeystoreError::PassphraseRequired)));
+    }
+
+    #[test]
+    fn tampered_key_file() {
+        let file = TempKeyFile::new("tampered");
+        generate_with_rounds(&file.0, Some(PASSPHRASE), MIN_KDF_ROUNDS).unwrap();
+
+        // Flip a bit of the ciphertext
+        file.edit(|json| {
+            let mut ciphertext = from_hex(json["secret_key"].as_str().unwrap()).unwrap();
+            ciphertext[0] ^= 1;
+            json["secret_key"] = to_hex(&ciphertext).into();
+        });
+        assert!(matches!(load(&file.0, Some(PASSPHRASE)), Err(KeystoreError::WrongPassphrase)));
+    }
+
+    #[test]
+    fn tampered_header() {
+        let file = TempKeyFile::new("tampered-header");
+        generate_with_rounds(&file.0, Some(PASSPHRASE), MIN_KDF_ROUNDS).unwrap();
+
+        // The header is authenticated along with the secret key
+        file.edit(|json| json["encryption"]["kdf_rounds"] = (MIN_KDF_ROUNDS + 1).into());
+        assert!(matches!(load(&file.0, Some(PASSPHRASE)), Err(KeystoreError::WrongPassphrase)));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let file = TempKeyFile::new("version");
+        generate(&file.0, None).unwrap();
+        file.edit(|json| json["version"] = 1.into());
+        match load(&file.0, None).err() {
+            Some(KeystoreError::Format(e)) => assert!(e.contains("unsupported version"), "{}", e),
+            other => panic!("expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn insecure_permissions() {
+        let file = TempKeyFile::new("permissions");
+        generate(&file.0, None).unwrap();
+        assert_eq!(std::fs::metadata(&file.0).unwrap().permissions().mode() & 0o777, 0o600);
+
+        std::fs::set_permissions(&file.0, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(load(&file.0, None), Err(KeystoreError::InsecurePermissions(0o644))));
+    }
+}
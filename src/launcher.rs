@@ -0,0 +1,208 @@
+use crate::config::ClusterConfig;
+use crate::testnet::node_dir;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+// How often replicas are checked for having exited on their own while waiting for a command
+const REAP_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Runs every replica of a local cluster generated by `pbft testnet` as a child process, prefixes their
+/// output with `[node-<i>]`, and kills, pauses or restarts individual replicas on command.
+///
+/// Commands are read from stdin, one per line:
+///
+/// - `kill <i>`: SIGKILL the replica
+/// - `pause <i>`: SIGSTOP the replica, it keeps its connections but stops responding
+/// - `resume <i>`: SIGCONT a paused replica
+/// - `restart <i>`: kill the replica if it is running and start it again
+/// - `status`: print the state of every replica
+/// - `quit`: kill every replica and exit
+///
+/// Replicas that exit on their own are reaped and reported, and can be started again with `restart`.
+pub struct Launcher {
+    dir: PathBuf,
+    replicas: Vec<Replica>,
+}
+
+struct Replica {
+    index: usize,
+    child: Option<Child>,
+    paused: bool,
+}
+
+impl Launcher {
+    pub fn new(dir: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let cluster: ClusterConfig = serde_json::from_str(&std::fs::read_to_string(dir.join("cluster.json"))?)?;
+        let replicas = cluster
+            .nodes
+            .iter()
+            .map(|node| Replica {
+                index: node.index,
+                child: None,
+                paused: false,
+            })
+            .collect();
+        Ok(Self { dir, replicas })
+    }
+
+    pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for index in 0..self.replicas.len() {
+            self.start(index)?;
+        }
+
+        // Stdin is read on a thread of its own, so that exited replicas are reaped while waiting for a command
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let line = match receiver.recv_timeout(REAP_INTERVAL) {
+                Ok(line) => line?,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.reap();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            self.reap();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let result = match words.as_slice() {
+                [] => Ok(()),
+                ["quit"] => break,
+                ["status"] => {
+                    self.status();
+                    Ok(())
+                }
+                [command, index] => match index.parse::<usize>() {
+                    Ok(index) if index < self.replicas.len() => self.command(command, index),
+                    _ => Err(format!("no such replica: {}", index).into()),
+                },
+                _ => Err(format!("unknown command: {}", line).into()),
+            };
+            if let Err(e) = result {
+                eprintln!("[launcher] {}", e);
+            }
+        }
+
+        self.shutdown();
+        Ok(())
+    }
+
+    fn command(&mut self, command: &str, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        match command {
+            "kill" => self.kill(index),
+            "pause" => self.signal(index, Signal::SIGSTOP).map(|_| self.replicas[index].paused = true),
+            "resume" => self.signal(index, Signal::SIGCONT).map(|_| self.replicas[index].paused = false),
+            "restart" => {
+                self.kill(index)?;
+                self.start(index)
+            }
+            _ => Err(format!("unknown command: {}", command).into()),
+        }
+    }
+
+    fn start(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let dir = node_dir(&self.dir, index);
+        let mut command = Command::new(std::env::current_exe()?);
+        command.arg("run").arg("--config").arg(dir.join("node.toml"));
+        let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        println!("[launcher] node-{} started, pid: {}", index, child.id());
+
+        let prefix = format!("[node-{}]", index);
+        if let Some(stdout) = child.stdout.take() {
+            forward(stdout, prefix.clone(), false);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward(stderr, prefix, true);
+        }
+
+        let replica = &mut self.replicas[index];
+        replica.child = Some(child);
+        replica.paused = false;
+        Ok(())
+    }
+
+    fn kill(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let replica = &mut self.replicas[index];
+        if let Some(mut child) = replica.child.take() {
+            child.kill()?;
+            let status = child.wait()?;
+            println!("[launcher] node-{} killed, status: {}", replica.index, status);
+        }
+        replica.paused = false;
+        Ok(())
+    }
+
+    fn signal(&mut self, index: usize, signal: Signal) -> Result<(), Box<dyn std::error::Error>> {
+        let pid = match &self.replicas[index].child {
+            Some(child) => child.id(),
+            None => return Err(format!("node-{} is not running", index).into()),
+        };
+        signal::kill(Pid::from_raw(pid as i32), signal)
+            .map_err(|e| format!("failed to send {} to node-{}: {}", signal, index, e))?;
+        println!("[launcher] node-{} received {}", index, signal);
+        Ok(())
+    }
+
+    // Reports the replicas that exited on their own and forgets them, so that they don't linger as zombies.
+    fn reap(&mut self) {
+        for replica in self.replicas.iter_mut() {
+            let status = match replica.child.as_mut().map(|child| child.try_wait()) {
+                Some(Ok(Some(status))) => status,
+                Some(Err(e)) => {
+                    eprintln!("[launcher] failed to check on node-{}: {}", replica.index, e);
+                    continue;
+                }
+                _ => continue,
+            };
+            println!("[launcher] node-{} exited, status: {}", replica.index, status);
+            replica.child = None;
+            replica.paused = false;
+        }
+    }
+
+    fn status(&mut self) {
+        for replica in self.replicas.iter_mut() {
+            let state = match replica.child.as_mut().map(|child| (child.id(), child.try_wait())) {
+                None => "stopped".to_owned(),
+                Some((_, Ok(Some(status)))) => format!("exited ({})", status),
+                Some((pid, Ok(None))) if replica.paused => format!("paused (pid {})", pid),
+                Some((pid, Ok(None))) => format!("running (pid {})", pid),
+                Some((_, Err(e))) => format!("unknown ({})", e),
+            };
+            println!("[launcher] node-{}: {}", replica.index, state);
+        }
+    }
+
+    fn shutdown(&mut self) {
+        for index in 0..self.replicas.len() {
+            if let Err(e) = self.kill(index) {
+                eprintln!("[launcher] failed to kill node-{}: {}", index, e);
+            }
+        }
+    }
+}
+
+// Copies the output of a replica line by line, prefixed with the replica's name.
+fn forward<R: Read + Send + 'static>(output: R, prefix: String, stderr: bool) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) if stderr => eprintln!("{} {}", prefix, line),
+                Ok(line) => println!("{} {}", prefix, line),
+                Err(_) => break,
+            }
+        }
+    });
+}
@@ -0,0 +1,401 @@
+use crate::state_machine::KvCommand;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// A client-observable event. A history is a sequence of these, recorded by `HistoryRecorder` during a test
+/// run or read back from a trace `TraceWriter` wrote as one JSON object per line.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HistoryEvent {
+    /// The client sent a `ClientRequest`.
+    Invoke { client: String, timestamp: u64, operation: String, at: u64 },
+    /// The client accepted the result of a `ClientReply` for the request.
+    Complete { client: String, timestamp: u64, result: String, at: u64 },
+}
+
+/// An invocation paired with its response. `result` is `None` while the client is still waiting, in which
+/// case the operation may or may not have taken effect.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Operation {
+    pub client: String,
+    pub timestamp: u64,
+    pub operation: String,
+    pub invoked_at: u64,
+    pub completed_at: Option<u64>,
+    pub result: Option<String>,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.completed_at, &self.result) {
+            (Some(completed_at), Some(result)) => write!(
+                f,
+                "[{}, {}] {}#{} {:?} -> {:?}",
+                self.invoked_at, completed_at, self.client, self.timestamp, self.operation, result
+            ),
+            _ => write!(
+                f,
+                "[{}, ...] {}#{} {:?} -> (pending)",
+                self.invoked_at, self.client, self.timestamp, self.operation
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HistoryRecorder {
+    events: Vec<HistoryEvent>,
+}
+
+impl HistoryRecorder {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self { events: Vec::new() }
+    }
+
+    #[cfg(test)]
+    pub fn invoke(&mut self, client: &str, timestamp: u64, operation: &str, at: u64) {
+        self.events.push(HistoryEvent::Invoke {
+            client: client.to_owned(),
+            timestamp,
+            operation: operation.to_owned(),
+            at,
+        });
+    }
+
+    #[cfg(test)]
+    pub fn complete(&mut self, client: &str, timestamp: u64, result: &str, at: u64) {
+        self.events.push(HistoryEvent::Complete {
+            client: client.to_owned(),
+            timestamp,
+            result: result.to_owned(),
+            at,
+        });
+    }
+
+    #[cfg(test)]
+    pub fn events(&self) -> &Vec<HistoryEvent> {
+        &self.events
+    }
+
+    /// Reads a trace written as one JSON-encoded `HistoryEvent` per line.
+    pub fn read_trace<R: BufRead>(reader: R) -> Result<Self, String> {
+        let mut events = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| format!("failed to read line {}: {}", number + 1, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = serde_json::from_str(&line).map_err(|e| format!("invalid event on line {}: {}", number + 1, e))?;
+            events.push(event);
+        }
+        Ok(Self { events })
+    }
+
+    /// Pairs invocations with their responses. Only the first response accepted for a request counts.
+    pub fn operations(&self) -> Vec<Operation> {
+        let mut operations: Vec<Operation> = Vec::new();
+        let mut index: HashMap<(String, u64), usize> = HashMap::new();
+
+        for event in self.events.iter() {
+            match event {
+                HistoryEvent::Invoke { client, timestamp, operation, at } => {
+                    index.insert((client.clone(), *timestamp), operations.len());
+                    operations.push(Operation {
+                        client: client.clone(),
+                        timestamp: *timestamp,
+                        operation: operation.clone(),
+                        invoked_at: *at,
+                        completed_at: None,
+                        result: None,
+                    });
+                }
+                HistoryEvent::Complete { client, timestamp, result, at } => {
+                    match index.get(&(client.clone(), *timestamp)) {
+                        Some(i) if operations[*i].result.is_none() => {
+                            operations[*i].completed_at = Some(*at);
+                            operations[*i].result = Some(result.clone());
+                        }
+                        Some(_) => {}
+                        None => eprintln!("[HistoryRecorder::operations] response without invocation: {:?}", event),
+                    }
+                }
+            }
+        }
+        operations
+    }
+}
+
+/// Appends the events a client observes to a file, so that the history of a real cluster can be checked
+/// with `pbft check-trace`. Several clients may append to the same file.
+pub struct TraceWriter {
+    file: File,
+}
+
+impl TraceWriter {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// `at` is the wall-clock time in milliseconds, so that the traces of several clients can be merged.
+    pub fn invoke(&mut self, client: &str, timestamp: u64, operation: &str, at: u64) -> std::io::Result<()> {
+        self.append(&HistoryEvent::Invoke {
+            client: client.to_owned(),
+            timestamp,
+            operation: operation.to_owned(),
+            at,
+        })
+    }
+
+    pub fn complete(&mut self, client: &str, timestamp: u64, result: &str, at: u64) -> std::io::Result<()> {
+        self.append(&HistoryEvent::Complete {
+            client: client.to_owned(),
+            timestamp,
+            result: result.to_owned(),
+            at,
+        })
+    }
+
+    // Each event is written with a single call, so that lines of concurrent writers don't interleave.
+    fn append(&mut self, event: &HistoryEvent) -> std::io::Result<()> {
+        let mut line =
+            serde_json::to_string(event).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckError {
+    InvalidOperation(Box<Operation>, String),
+    NonLinearizable { key: String, history: Vec<Operation> },
+}
+
+impl std::fmt::Display for CheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckError::InvalidOperation(operation, e) => write!(f, "{}: {}", operation, e),
+            CheckError::NonLinearizable { key, history } => {
+                writeln!(f, "the history of key {:?} is not linearizable:", key)?;
+                for operation in history.iter() {
+                    writeln!(f, "  {}", operation)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Checks that `operations` are linearizable against the key-value store.
+///
+/// Linearizability is compositional, so the history is split per key and each key is checked on its own
+/// with the Wing & Gong search. When a key fails, its history is reduced to a minimal non-linearizable
+/// sub-history: removing any single operation from the reported one makes it linearizable.
+pub fn check(operations: &[Operation]) -> Result<(), CheckError> {
+    let mut per_key: BTreeMap<String, Vec<(KvCommand, Operation)>> = BTreeMap::new();
+    for operation in operations.iter() {
+        let command =
+            KvCommand::parse(&operation.operation).map_err(|e| CheckError::InvalidOperation(Box::new(operation.clone()), e))?;
+        per_key
+            .entry(command.key().clone())
+            .or_default()
+            .push((command, operation.clone()));
+    }
+
+    for (key, mut history) in per_key.into_iter() {
+        if linearizable(&history) {
+            continue;
+        }
+
+        let mut shrunk = true;
+        while shrunk {
+            shrunk = false;
+            let mut i = 0;
+            while i < history.len() {
+                let removed = history.remove(i);
+                if linearizable(&history) {
+                    history.insert(i, removed);
+                    i += 1;
+                } else {
+                    shrunk = true;
+                }
+            }
+        }
+        return Err(CheckError::NonLinearizable {
+            key,
+            history: history.into_iter().map(|(_, operation)| operation).collect(),
+        });
+    }
+    Ok(())
+}
+
+fn linearizable(history: &[(KvCommand, Operation)]) -> bool {
+    let mut linearized = vec![false; history.len()];
+    let mut visited = HashSet::new();
+    search(history, &mut linearized, None, &mut visited)
+}
+
+// Depth-first search over the operations that could take effect next. Memoizes (linearized set, value)
+// pairs that are known to lead nowhere.
+fn search(
+    history: &[(KvCommand, Operation)],
+    linearized: &mut Vec<bool>,
+    value: Option<String>,
+    visited: &mut HashSet<(Vec<bool>, Option<String>)>,
+) -> bool {
+    // Done once every completed operation is linearized. Pending ones may never have taken effect.
+    if history
+        .iter()
+        .zip(linearized.iter())
+        .all(|((_, operation), done)| *done || operation.result.is_none())
+    {
+        return true;
+    }
+    if !visited.insert((linearized.clone(), value.clone())) {
+        return false;
+    }
+
+    // An operation can be linearized next only if it was invoked before every remaining operation completed.
+    let earliest_completion = history
+        .iter()
+        .zip(linearized.iter())
+        .filter(|(_, done)| !**done)
+        .filter_map(|((_, operation), _)| operation.completed_at)
+        .min()
+        .unwrap_or(u64::MAX);
+
+    for i in 0..history.len() {
+        let (command, operation) = &history[i];
+        if linearized[i] || operation.invoked_at > earliest_completion {
+            continue;
+        }
+
+        let mut next_value = value.clone();
+        let result = command.apply(&mut next_value);
+        if let Some(expected) = &operation.result {
+            if &result != expected {
+                continue;
+            }
+        }
+
+        linearized[i] = true;
+        if search(history, linearized, next_value, visited) {
+            return true;
+        }
+        linearized[i] = false;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(events: &[HistoryEvent]) -> Vec<Operation> {
+        let mut recorder = HistoryRecorder::new();
+        for event in events.iter() {
+            match event.clone() {
+                HistoryEvent::Invoke { client, timestamp, operation, at } => recorder.invoke(&client, timestamp, &operation, at),
+                HistoryEvent::Complete { client, timestamp, result, at } => recorder.complete(&client, timestamp, &result, at),
+            }
+        }
+        recorder.operations()
+    }
+
+    fn invoke(client: &str, timestamp: u64, operation: &str, at: u64) -> HistoryEvent {
+        HistoryEvent::Invoke { client: client.to_owned(), timestamp, operation: operation.to_owned(), at }
+    }
+
+    fn complete(client: &str, timestamp: u64, result: &str, at: u64) -> HistoryEvent {
+        HistoryEvent::Complete { client: client.to_owned(), timestamp, result: result.to_owned(), at }
+    }
+
+    #[test]
+    fn linearizable_history() {
+        // The read overlaps the write, so it may observe either value. The pending write may or may not
+        // have taken effect.
+        let operations = history(&[
+            invoke("a", 1, "PUT x 1", 0),
+            complete("a", 1, "OK", 10),
+            invoke("a", 2, "PUT x 2", 20),
+            invoke("b", 1, "GET x", 25),
+            complete("b", 1, "1", 30),
+            complete("a", 2, "OK", 40),
+            invoke("b", 2, "GET x", 50),
+            complete("b", 2, "2", 60),
+            invoke("c", 1, "DELETE y", 70),
+        ]);
+        assert!(check(&operations).is_ok());
+    }
+
+    #[test]
+    fn non_linearizable_history() {
+        // The read starts after the write completed but doesn't observe it. The other key and the
+        // operations that don't contribute to the violation are left out of the reported history.
+        let operations = history(&[
+            invoke("a", 1, "GET x", 0),
+            complete("a", 1, "NOT_FOUND", 5),
+            invoke("a", 2, "PUT x 1", 10),
+            complete("a", 2, "OK", 20),
+            invoke("c", 1, "PUT y 1", 22),
+            complete("c", 1, "OK", 24),
+            invoke("b", 1, "GET x", 30),
+            complete("b", 1, "NOT_FOUND", 40),
+        ]);
+        match check(&operations) {
+            Err(CheckError::NonLinearizable { key, history }) => {
+                assert_eq!(key, "x");
+                let operations: Vec<&str> = history.iter().map(|o| o.operation.as_str()).collect();
+                assert_eq!(operations, vec!["PUT x 1", "GET x"]);
+            }
+            other => panic!("expected a non-linearizable history, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_trace() {
+        // A recorded history written out as a production trace reads back to the same operations
+        let mut recorder = HistoryRecorder::new();
+        recorder.invoke("a", 1, "PUT x 1", 0);
+        recorder.complete("a", 1, "OK", 10);
+        recorder.invoke("b", 1, "GET x", 20);
+        let trace: String = recorder
+            .events()
+            .iter()
+            .map(|event| format!("{}\n", serde_json::to_string(event).unwrap()))
+            .collect();
+
+        let read = HistoryRecorder::read_trace(trace.as_bytes()).unwrap();
+        assert_eq!(read.operations(), recorder.operations());
+        assert!(HistoryRecorder::read_trace("not json\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn check_recorded_trace() {
+        // Two clients appending to the same trace file, as `pbft client --trace` does
+        let path = std::env::temp_dir().join(format!("pbft-trace-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut a = TraceWriter::open(&path).unwrap();
+        let mut b = TraceWriter::open(&path).unwrap();
+        a.invoke("127.0.0.1:5000", 1, "PUT x 1", 100).unwrap();
+        a.complete("127.0.0.1:5000", 1, "OK", 110).unwrap();
+        b.invoke("127.0.0.1:5001", 1, "GET x", 120).unwrap();
+        b.complete("127.0.0.1:5001", 1, "1", 130).unwrap();
+        b.invoke("127.0.0.1:5001", 2, "GET x", 140).unwrap();
+        b.complete("127.0.0.1:5001", 2, "NOT_FOUND", 150).unwrap();
+
+        let recorder = HistoryRecorder::read_trace(std::io::BufReader::new(File::open(&path).unwrap())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let operations = recorder.operations();
+        assert_eq!(operations.len(), 3);
+        // The second read misses the write nothing deleted
+        match check(&operations) {
+            Err(CheckError::NonLinearizable { key, .. }) => assert_eq!(key, "x"),
+            other => panic!("expected a non-linearizable history, got {:?}", other),
+        }
+        assert!(check(&operations[..2]).is_ok());
+    }
+}
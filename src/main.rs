@@ -1,100 +1,297 @@
 use crate::behavior::Pbft;
+use crate::cli::{Command, Operations};
+use crate::client::PbftClient;
 use crate::client_handler::ClientHandler;
+use crate::config::{ClusterConfig, NodeConfig};
+use crate::execution_log::ExecutionLog;
+use crate::linearizability::{HistoryRecorder, TraceWriter};
+use crate::message::{ClientMessage, NodeStatus};
 use crate::network_behaviour_composer::NetworkBehaviourComposer;
-use crate::node_type::NodeType;
-//use futures::stream::Stream;
 use futures::stream::StreamExt;
-use libp2p::identity::Keypair;
-
+use libp2p::swarm::{SwarmBuilder, SwarmEvent};
 use libp2p::*;
-use syn::Expr::Async;
-use tokio;
 
 use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 mod behavior;
+mod bounded_queue;
+mod cli;
+mod client;
 mod client_handler;
+mod clock;
+mod config;
+mod connectivity;
+mod error;
+mod execution_log;
 mod handler;
+#[cfg(test)]
+mod invariant_checker;
+mod keystore;
+mod launcher;
+mod linearizability;
 mod message;
 mod network_behaviour_composer;
-mod node_type;
+mod primary_selection;
+mod proto;
 mod protocol_config;
+mod retransmission;
+#[cfg(test)]
+mod simulator;
 mod state;
+mod state_machine;
+mod testnet;
+mod transport;
 mod view;
 
-fn main() {
-    println!("Hello, PBFT!");
-    let cli_args: Vec<String> = std::env::args().collect();
-    println!("[main] cli_args: {:?}", cli_args);
-    let node_type = determine_node_type(&cli_args).expect("Usage: $ pbft [primary]");
-    println!("[main] node_type: {:?}", node_type);
+// How often the client handler serves its connections, and client requests are handed to the consensus behaviour
+const CLIENT_TICK_INTERVAL: Duration = Duration::from_millis(10);
+// The key file is encrypted with the passphrase in this environment variable, when it is set
+const PASSPHRASE_ENV: &str = "PBFT_KEY_PASSPHRASE";
 
-    let client_requests = Arc::new(RwLock::new(VecDeque::new()));
-    let client_replies = Arc::new(RwLock::new(VecDeque::new()));
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli_args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse(&cli_args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Run { config } => run(&config).await,
+        Command::Client { cluster, reply_to, client_address, timeout, trace, operations } => {
+            client(&cluster, reply_to, client_address, timeout, trace, operations)
+        }
+        Command::Status { node } => status(node),
+        Command::Keygen { out } => keygen(&out),
+        Command::InspectLog { path } => inspect_log(&path),
+        Command::CheckTrace { path } => check_trace(&path),
+        Command::Testnet { replicas, faults, out } => testnet(replicas, faults, out),
+        Command::Launch { dir } => launcher::Launcher::new(dir)?.run(),
+    }
+}
 
-    let mut client_request_handler =
-        ClientHandler::new(node_type, client_requests.clone(), client_replies.clone());
+// $ pbft run --config <node.toml>
+async fn run(config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = NodeConfig::load(config_path)?;
+    println!("[main] config: {:?}", config);
 
-    let local_key = Keypair::generate_ed25519();
+    let local_key = keystore::load(&config.key_file, passphrase().as_deref())
+        .map_err(|e| format!("`key_file` {:?}: {}", config.key_file, e))?;
     let local_peer_id = PeerId::from(local_key.public());
+    let peers = config.peer_addresses();
 
-    let transport = development_transport(local_key.clone());
-    let mut swarm = Swarm::new(
-        transport,
-        NetworkBehaviourComposer::new(
-            libp2p::mdns::Mdns::new.expect("Failed to create mDNS service"),
-            Pbft::new(local_key, client_replies.clone()),
-        ),
+    let client_requests = Arc::new(RwLock::new(VecDeque::new()));
+    let client_replies = Arc::new(RwLock::new(VecDeque::new()));
+    let status = Arc::new(RwLock::new(NodeStatus::default()));
+
+    let mut client_request_handler = ClientHandler::new(
         local_peer_id,
-    );
+        config.client_address(),
+        client_requests.clone(),
+        client_replies.clone(),
+        status.clone(),
+    )?
+    .with_max_request_size(config.client.max_request_size);
+    if let Some(dial_back) = config.client.dial_back() {
+        client_request_handler = client_request_handler.with_dial_back(dial_back);
+    }
+
+    let transport_config = config.transport_config();
+    let transport = transport::build(&local_key, &transport_config)?;
 
-    Swarm::listen_on(&mut swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+    let mut pbft = Pbft::new(local_key, config.f, client_replies.clone())
+        .with_replicas(config.peer_ids())
+        .with_primary_selection(config.primary_selection.build()?)
+        .with_non_determinism_bounds(config.non_determinism_bounds())
+        .with_retransmission_timeout(config.timeouts.retransmission)
+        .with_max_in_flight_requests(config.batching.max_in_flight_requests)
+        .with_handler_config(config.handler_config());
+    if let Some(path) = &config.logging.execution_log {
+        let execution_log = ExecutionLog::open(path).map_err(|e| format!("`logging.execution_log` {:?}: {}", path, e))?;
+        pbft = pbft.with_execution_log(execution_log);
+    }
+    for (peer_id, address) in peers.iter() {
+        pbft.add_peer(peer_id, address);
+    }
 
-    let mut listening = false;
+    // The connection tasks use the tokio TCP transport, so they have to be spawned onto the tokio runtime
+    let mut swarm = SwarmBuilder::new(
+        transport,
+        NetworkBehaviourComposer::new(pbft),
+        local_peer_id,
+    )
+    .executor(Box::new(|future| {
+        tokio::spawn(future);
+    }))
+    .build();
 
-    //async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    //if let Some(client_request) = ||{
-    //client_requests.write().unwrap().pop_front();
-    // swarm.pbft.add_client_request(client_request);
-    //}
+    for address in config.listen_addresses() {
+        swarm.listen_on(address)?;
+    }
 
-    //}
+    // Accepts client connections, reads requests and writes back the replies queued by `Pbft`.
+    tokio::task::spawn_blocking(move || client_request_handler.run(CLIENT_TICK_INTERVAL));
 
-    client_request_handler.tick();
+    let mut client_tick = tokio::time::interval(CLIENT_TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    println!("[main] Listening on {:?}", address);
+                }
+                event => println!("[main] swarm event: {:?}", event),
+            },
+            _ = client_tick.tick() => {
+                *status.write().unwrap() = swarm.behaviour().pbft.status();
 
-    match swarm.poll().expect("Error while polling swarm") {
-        syn::token::Async::Ready(Some(_)) => {}
-        syn::token::Async::Ready(None) | syn::token::Async::NotReady => {
-            if !listening {
-                if let Some(a) = Swarm::listeners(&swarm).next() {
-                    println!("Listening on {:?}", a);
-                    listening = true;
+                loop {
+                    // Don't hold the lock while the behaviour processes the request
+                    let client_request = client_requests.write().unwrap().pop_front();
+                    match client_request {
+                        Some(client_request) => swarm.behaviour_mut().pbft.add_client_request(client_request),
+                        None => break,
+                    }
                 }
             }
-            return Ok(syn::token::Async::NotReady);
+            _ = tokio::signal::ctrl_c() => {
+                println!("[main] Shutting down");
+                return Ok(());
+            }
         }
     }
 }
 
-fn determine_node_type(args: &Vec<String>) -> Result<NodeType, ()> {
-    match args.len() {
-        1 => Ok(NodeType::Backup),
-        2 => {
-            if let Some(node_type) = args.get(1) {
-                if node_type == "primary" {
-                    return Ok(NodeType::Primary);
-                } else {
-                    panic!(
-                        "[main::determine_node_type] Invalid node_type: {:?}",
-                        node_type
-                    );
-                }
-            }
-            {
-                unreachable!();
-            }
+// $ pbft client --cluster <cluster.json> [--reply-to <host:port>] [--client-address <host:port>] [--timeout <ms>] [--trace <file>] [--file <file> | <operation>]
+//
+// Submits the operations one after the other and prints the result agreed on by f+1 replicas. Gives up at the
+// first operation that doesn't get enough replies.
+fn client(
+    cluster: &Path,
+    reply_to: Option<SocketAddr>,
+    client_address: Option<SocketAddr>,
+    timeout: Option<u64>,
+    trace: Option<PathBuf>,
+    operations: Operations,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cluster: ClusterConfig = serde_json::from_str(&std::fs::read_to_string(cluster)?)?;
+    let mut client = PbftClient::from_cluster(&cluster)?;
+    if let Some(reply_to) = reply_to {
+        client = client.with_reply_address(reply_to);
+    }
+    if let Some(client_address) = client_address {
+        client = client.with_client_address(client_address);
+    }
+    if let Some(timeout) = timeout {
+        client = client.with_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(trace) = trace {
+        let writer = TraceWriter::open(&trace).map_err(|e| format!("--trace {:?}: {}", trace, e))?;
+        client = client.with_trace(writer);
+    }
+
+    let operations: Box<dyn Iterator<Item = std::io::Result<String>>> = match operations {
+        Operations::One(operation) => Box::new(std::iter::once(Ok(operation))),
+        Operations::File(path) => Box::new(BufReader::new(std::fs::File::open(path)?).lines()),
+        Operations::Stdin => Box::new(BufReader::new(std::io::stdin()).lines()),
+    };
+    for operation in operations {
+        let operation = operation?;
+        if operation.trim().is_empty() {
+            continue;
         }
-        _ => Err(()),
+        let started_at = Instant::now();
+        let result = client.invoke(&operation)?;
+        println!(
+            "{} => {} (view: {}, timestamp: {}, latency: {:?})",
+            operation,
+            result.result,
+            result.view,
+            result.timestamp,
+            started_at.elapsed()
+        );
+    }
+    Ok(())
+}
+
+// $ pbft status --node <host:port>
+fn status(node: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(node)?;
+    stream.write_all(format!("{}\n", ClientMessage::Status).as_bytes())?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    let status: NodeStatus = serde_json::from_str(&status)?;
+    println!("PeerId: {}", status.peer_id);
+    println!("View: {}", status.view);
+    println!("Primary: {}", status.primary);
+    println!("Executed: {} (last sequence number: {})", status.executed, status.last_executed_sequence_number);
+    println!("Connected peers: {}", status.connected_peers.len());
+    for peer_id in status.connected_peers.iter() {
+        println!("  {}", peer_id);
+    }
+    println!("Rejections: {}", status.rejections.values().sum::<u64>());
+    for (peer_id, count) in status.rejections.iter() {
+        println!("  {}: {}", peer_id, count);
     }
+    println!("Invalid messages: {}", status.invalid_messages.values().sum::<u64>());
+    for (peer_id, count) in status.invalid_messages.iter() {
+        println!("  {}: {}", peer_id, count);
+    }
+    Ok(())
+}
+
+// $ pbft keygen [--out <file>]
+fn keygen(out: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let keypair = keystore::generate(out, passphrase().as_deref())?;
+    println!("PeerId: {}", keystore::peer_id(&keypair));
+    println!("Public key: {}", keystore::public_key_hex(&keypair));
+    Ok(())
+}
+
+// $ pbft inspect-log <file>
+fn inspect_log(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let entries = execution_log::read(path)?;
+    for entry in entries.iter() {
+        println!(
+            "#{} view: {} timestamp: {} digest: {} operation: {:?} result: {:?}",
+            entry.sequence_number, entry.view, entry.timestamp, entry.digest, entry.operation, entry.result
+        );
+    }
+    println!("{} entries", entries.len());
+    Ok(())
+}
+
+// $ pbft check-trace <file>
+//
+// The trace is what `pbft client --trace` recorded, possibly by several clients.
+fn check_trace(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let recorder = HistoryRecorder::read_trace(BufReader::new(std::fs::File::open(path)?))?;
+    let operations = recorder.operations();
+    if let Err(e) = linearizability::check(&operations) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let pending = operations.iter().filter(|o| o.result.is_none()).count();
+    println!("linearizable: {} operations ({} pending)", operations.len(), pending);
+    Ok(())
+}
+
+// $ pbft testnet --replicas <n> --faults <f> --out <dir>
+fn testnet(replicas: usize, faults: usize, out: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let cluster = testnet::generate(&testnet::TestnetOptions::new(replicas, faults, out), passphrase().as_deref())?;
+    for node in cluster.nodes.iter() {
+        println!("node-{}: {} {} client port: {}", node.index, node.peer_id, node.address, node.client_port);
+    }
+    Ok(())
+}
+
+fn passphrase() -> Option<String> {
+    std::env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty())
 }
@@ -1,32 +1,231 @@
 use serde::{Serialize, Deserialize, Serializer};
 use serde::ser::SerializeStruct;
-use blake2::{Blake2b, Digest};
+use blake2::{Blake2b512, Digest};
 use libp2p::PeerId;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::net::SocketAddr;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Message {
     ClientRequest(ClientRequest),
     PrePrepare(PrePrepare),
     Prepare(Prepare),
     Commit(Commit),
+    StateRequest(StateRequest),
+    StateReply(StateReply),
+}
+
+/// The unit sent over the long-lived substreams between replicas. Requests carry an id chosen by the
+/// sender, and the receiver answers with a `Response` carrying the same id on the same substream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Frame {
+    Request { id: u64, message: Message },
+    Response { id: u64, ack: Acknowledgement },
+}
+
+/// A `Frame` as sent under the legacy protocol ids, where a request is answered with a raw "OK" instead of
+/// an `Acknowledgement`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LegacyFrame {
+    Request { id: u64, message: Message },
+    Response { id: u64, response: Vec<u8> },
+}
+
+const LEGACY_ACK: &[u8] = b"OK";
+
+impl From<Frame> for LegacyFrame {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Request { id, message } => LegacyFrame::Request { id, message },
+            Frame::Response { id, ack } => {
+                let response = match ack {
+                    Acknowledgement::Ack => LEGACY_ACK.to_vec(),
+                    Acknowledgement::Nack(reason) => reason.to_string().into_bytes(),
+                };
+                LegacyFrame::Response { id, response }
+            }
+        }
+    }
 }
 
-impl From<Vec<u8>> for Message {
-    fn from(item: Vec<u8>) -> Self {
-        serde_json::from_str(&String::from_utf8(item).unwrap()).unwrap()
+impl From<LegacyFrame> for Frame {
+    // Legacy replicas answer everything they don't drop with "OK"
+    fn from(frame: LegacyFrame) -> Self {
+        match frame {
+            LegacyFrame::Request { id, message } => Frame::Request { id, message },
+            LegacyFrame::Response { id, response } => {
+                let ack = match response.as_slice() {
+                    LEGACY_ACK => Acknowledgement::Ack,
+                    _ => Acknowledgement::Nack(RejectReason::Unavailable),
+                };
+                Frame::Response { id, ack }
+            }
+        }
     }
 }
 
-impl From<String> for Message {
-    fn from(s: String) -> Self {
-        serde_json::from_str(&s).unwrap()
+/// The answer of a replica to a consensus message.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Acknowledgement {
+    Ack,
+    Nack(RejectReason),
+}
+
+/// Why a replica rejected a consensus message.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// The message is not for the view the replica is in. `current_view` is the replica's view.
+    WrongView { current_view: u64 },
+    /// The digest doesn't match the request, or conflicts with an accepted PrePrepare.
+    BadDigest,
+    /// The sequence number is outside of the replica's water marks.
+    OutOfWindow { low: u64, high: u64 },
+    BadSignature,
+    /// The replica has no PrePrepare for the view and sequence number of the message.
+    UnknownSequence,
+    /// The PrePrepare was not sent by the primary.
+    NotPrimary,
+    /// The non-deterministic inputs chosen by the primary are out of bounds.
+    BadNonDeterministicInputs,
+    /// The replica failed to process a valid message. The sender should resend it later.
+    Unavailable,
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RejectReason::WrongView { current_view } => write!(f, "wrong view, the current view is {}", current_view),
+            RejectReason::BadDigest => f.write_str("bad digest"),
+            RejectReason::OutOfWindow { low, high } => write!(f, "out of the window [{}, {}]", low, high),
+            RejectReason::BadSignature => f.write_str("bad signature"),
+            RejectReason::UnknownSequence => f.write_str("unknown sequence number"),
+            RejectReason::NotPrimary => f.write_str("not sent by the primary"),
+            RejectReason::BadNonDeterministicInputs => f.write_str("non-deterministic inputs out of bounds"),
+            RejectReason::Unavailable => f.write_str("the replica failed to process the message"),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for Message {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let s = std::str::from_utf8(bytes).map_err(|e| DecodeError::InvalidUtf8(e.to_string()))?;
+        serde_json::from_str(s).map_err(|e| DecodeError::Json(e.to_string()))
+    }
+}
+
+impl TryFrom<String> for Message {
+    type Error = DecodeError;
+
+    fn try_from(s: String) -> Result<Self, DecodeError> {
+        serde_json::from_str(&s).map_err(|e| DecodeError::Json(e.to_string()))
+    }
+}
+
+/// What a client may send to the client port of a replica.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClientMessage {
+    ClientRequest(ClientRequest),
+    /// Asks the replica to send the replies to `client` on this connection, including those to requests the
+    /// client sent to other replicas
+    Subscribe { client: SocketAddr },
+    /// Asks the replica for its `NodeStatus`
+    Status,
+}
+
+impl TryFrom<&[u8]> for ClientMessage {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let s = std::str::from_utf8(bytes).map_err(|e| DecodeError::InvalidUtf8(e.to_string()))?;
+        serde_json::from_str(s).map_err(|e| DecodeError::Json(e.to_string()))
+    }
+}
+
+impl std::fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+/// Sent to a client whose message was discarded, on the connection it arrived on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequestRejected {
+    pub error: String,
+}
+
+impl RequestRejected {
+    pub fn new(error: String) -> Self {
+        Self { error }
+    }
+}
+
+impl std::fmt::Display for RequestRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+/// A snapshot of a replica, answered to `ClientMessage::Status`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub peer_id: String,
+    pub view: u64,
+    /// The primary that assigns the next sequence number
+    pub primary: String,
+    pub connected_peers: Vec<String>,
+    /// The number of requests executed since the replica started
+    pub executed: usize,
+    pub last_executed_sequence_number: u64,
+    /// The number of messages each peer rejected, by PeerId
+    #[serde(default)]
+    pub rejections: BTreeMap<String, u64>,
+    /// The number of invalid messages received from each peer and dropped, by PeerId
+    #[serde(default)]
+    pub invalid_messages: BTreeMap<String, u64>,
+}
+
+impl std::fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+/// Why bytes received from a peer or a client could not be turned into a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The frame is longer than the maximum frame size.
+    FrameTooLarge { max: usize },
+    InvalidUtf8(String),
+    Json(String),
+    Protobuf(String),
+    MissingField(&'static str),
+    InvalidAddress(String),
+    /// The message is well-formed but not expected from this sender, e.g. a `ClientRequest` from a replica.
+    UnexpectedMessage(&'static str),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::FrameTooLarge { max } => write!(f, "the frame exceeds the maximum size of {} bytes", max),
+            DecodeError::InvalidUtf8(e) => write!(f, "invalid UTF-8: {}", e),
+            DecodeError::Json(e) => write!(f, "invalid JSON: {}", e),
+            DecodeError::Protobuf(e) => write!(f, "invalid Protocol Buffers: {}", e),
+            DecodeError::MissingField(field) => write!(f, "missing field: {}", field),
+            DecodeError::InvalidAddress(e) => write!(f, "invalid client address: {}", e),
+            DecodeError::UnexpectedMessage(kind) => write!(f, "unexpected message: {}", kind),
+        }
     }
 }
 
+impl std::error::Error for DecodeError {}
+
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", serde_json::to_string(self).unwrap())
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
     }
 }
 
@@ -38,6 +237,10 @@ pub struct ClientRequest {
 }
 
 impl ClientRequest {
+    pub fn new(operation: String, timestamp: u64, client: SocketAddr) -> Self {
+        Self { operation, timestamp, client }
+    }
+
     pub fn operation(&self) -> String {
         self.operation.clone()
     }
@@ -47,7 +250,7 @@ impl ClientRequest {
     }
 
     pub fn client(&self) -> SocketAddr {
-        self.client.clone()
+        self.client
     }
 }
 
@@ -58,16 +261,30 @@ pub struct ClientReply {
     client: SocketAddr, // Is this correct as `c`?
     peer_id: PeerId,
     result: String,
+    // The request was not admitted because the primary is overloaded. The client may retry later.
+    busy: bool,
 }
 
 impl ClientReply {
-    pub fn new(peer_id: PeerId, client_request: &ClientRequest, commit: &Commit) -> Self {
+    pub fn new(peer_id: PeerId, client_request: &ClientRequest, commit: &Commit, result: String) -> Self {
         Self {
             view: commit.view(),
             timestamp: client_request.timestamp(),
             client: client_request.client(),
             peer_id,
-            result: "awesome!".to_owned(), // TODO
+            result,
+            busy: false,
+        }
+    }
+
+    pub fn busy(peer_id: PeerId, client_request: &ClientRequest, view: u64) -> Self {
+        Self {
+            view,
+            timestamp: client_request.timestamp(),
+            client: client_request.client(),
+            peer_id,
+            result: String::new(),
+            busy: true,
         }
     }
 }
@@ -77,8 +294,13 @@ impl ClientReply {
         self.timestamp
     }
 
+    #[cfg(test)]
+    pub fn result(&self) -> &String {
+        &self.result
+    }
+
     pub fn client_address(&self) -> SocketAddr {
-        self.client.clone()
+        self.client
     }
 }
 
@@ -87,18 +309,19 @@ impl Serialize for ClientReply {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("ClientReply", 4)?;
+        let mut state = serializer.serialize_struct("ClientReply", 5)?;
         state.serialize_field("view", &self.view)?;
         state.serialize_field("timestamp", &self.timestamp)?;
         state.serialize_field("peer_id", &self.peer_id.to_string())?;
         state.serialize_field("result", &self.result)?;
+        state.serialize_field("busy", &self.busy)?;
         state.end()
     }
 }
 
 impl std::fmt::Display for ClientReply {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", serde_json::to_string(self).unwrap())
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
     }
 }
 
@@ -112,6 +335,8 @@ pub struct PrePrepare {
     digest: String,
     // client message
     message: ClientRequest,
+    // values chosen by the primary that every replica hands to the state machine
+    non_deterministic: NonDeterministicInputs,
 }
 
 impl PrePrepare {
@@ -131,13 +356,29 @@ impl PrePrepare {
         &self.message
     }
 
-    pub fn from(view: u64, n: u64, client_request: ClientRequest) -> Self {
-        let digest = digest(client_request.operation.as_bytes());
-        Self { view, sequence_number: n, digest, message: client_request }
+    pub fn non_deterministic(&self) -> &NonDeterministicInputs {
+        &self.non_deterministic
+    }
+
+    pub fn from(view: u64, n: u64, client_request: ClientRequest, non_deterministic: NonDeterministicInputs) -> Self {
+        let digest = digest(&digest_input(&client_request, &non_deterministic));
+        Self { view, sequence_number: n, digest, message: client_request, non_deterministic }
+    }
+
+    // Rebuilds a `PrePrepare` received from a peer. The digest is not recomputed, so that
+    // `validate_digest` can detect a mismatch.
+    pub(crate) fn from_parts(
+        view: u64,
+        sequence_number: u64,
+        digest: String,
+        message: ClientRequest,
+        non_deterministic: NonDeterministicInputs,
+    ) -> Self {
+        Self { view, sequence_number, digest, message, non_deterministic }
     }
 
     pub fn validate_digest(&self) -> Result<(), String> {
-        if self.digest == digest(&self.message.operation.as_bytes()) {
+        if self.digest == digest(&digest_input(&self.message, &self.non_deterministic)) {
             Ok(())
         } else {
             Err(format!("The digest is not matched with message. digest: {}, message.operation: {}", self.digest, self.message.operation))
@@ -147,7 +388,56 @@ impl PrePrepare {
 
 impl std::fmt::Display for PrePrepare {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", serde_json::to_string(self).unwrap())
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+// The non-deterministic inputs are covered by the digest, so that a faulty primary can't get backups to
+// prepare the same digest with different values.
+fn digest_input(client_request: &ClientRequest, non_deterministic: &NonDeterministicInputs) -> Vec<u8> {
+    let mut input = client_request.operation.as_bytes().to_vec();
+    input.extend_from_slice(&non_deterministic.timestamp.to_be_bytes());
+    input.extend_from_slice(&non_deterministic.seed.to_be_bytes());
+    input
+}
+
+/// Values an operation may depend on that replicas can't compute deterministically on their own. The primary
+/// proposes them in the `PrePrepare` and backups check them before accepting it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NonDeterministicInputs {
+    // the primary's wall-clock time in milliseconds since the Unix epoch
+    timestamp: u64,
+    // a seed for any randomness the operation needs
+    seed: u64,
+}
+
+impl NonDeterministicInputs {
+    pub(crate) fn new(timestamp: u64, seed: u64) -> Self {
+        Self { timestamp, seed }
+    }
+
+    /// The seed is derived from the request and its position in the log. It is unpredictable to the client
+    /// before the primary assigns the sequence number, but a faulty primary can still choose the timestamp
+    /// to bias it.
+    pub fn propose(timestamp: u64, view: u64, n: u64, client_request: &ClientRequest) -> Self {
+        let mut input = client_request.operation.as_bytes().to_vec();
+        input.extend_from_slice(&client_request.timestamp.to_be_bytes());
+        input.extend_from_slice(&view.to_be_bytes());
+        input.extend_from_slice(&n.to_be_bytes());
+        input.extend_from_slice(&timestamp.to_be_bytes());
+
+        let hash = Blake2b512::digest(&input);
+        let mut seed = [0u8; 8];
+        seed.copy_from_slice(&hash[..8]);
+        Self { timestamp, seed: u64::from_be_bytes(seed) }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
     }
 }
 
@@ -161,7 +451,7 @@ impl PrePrepareSequence {
     }
 
     pub fn increment(&mut self) {
-        let from = self.value.clone();
+        let from = self.value;
         self.value += 1;
         println!("[PrePrepareSequence::increment] value has been incremented from {} to {}", from, self.value);
     }
@@ -169,6 +459,14 @@ impl PrePrepareSequence {
     pub fn value(&self) -> u64 {
         self.value
     }
+
+    // Backups follow the sequence numbers assigned by the primary so that they know the next one, which
+    // policies such as rotating leaders depend on.
+    pub fn observe(&mut self, sequence_number: u64) {
+        if sequence_number > self.value {
+            self.value = sequence_number;
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -179,6 +477,10 @@ pub struct Prepare {
 }
 
 impl Prepare {
+    pub(crate) fn new(view: u64, sequence_number: u64, digest: String) -> Self {
+        Self { view, sequence_number, digest }
+    }
+
     pub fn from(pre_prepare: &PrePrepare) -> Self {
         Self {
             view: pre_prepare.view,
@@ -202,12 +504,12 @@ impl Prepare {
 
 impl std::fmt::Display for Prepare {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", serde_json::to_string(self).unwrap())
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
     }
 }
 
 fn digest(message: &[u8]) -> String {
-    let hash = Blake2b::digest(message);
+    let hash = Blake2b512::digest(message);
     format!("{:x}", hash)
 }
 
@@ -219,6 +521,10 @@ pub struct Commit {
 }
 
 impl Commit {
+    pub(crate) fn new(view: u64, sequence_number: u64, digest: String) -> Self {
+        Self { view, sequence_number, digest }
+    }
+
     pub fn view(&self) -> u64 {
         self.view
     }
@@ -226,6 +532,10 @@ impl Commit {
     pub fn sequence_number(&self) -> u64 {
         self.sequence_number
     }
+
+    pub fn digest(&self) -> &String {
+        &self.digest
+    }
 }
 
 impl From<Prepare> for Commit {
@@ -240,6 +550,43 @@ impl From<Prepare> for Commit {
 
 impl std::fmt::Display for Commit {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", serde_json::to_string(self).unwrap())
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+/// Asks a peer for the requests it executed after sequence number `after`, sent by a replica that is behind.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateRequest {
+    after: u64,
+}
+
+impl StateRequest {
+    pub fn new(after: u64) -> Self {
+        Self { after }
+    }
+
+    pub fn after(&self) -> u64 {
+        self.after
+    }
+}
+
+/// The PrePrepares of the requests a peer executed, in execution order, answering a `StateRequest`. A single
+/// peer may be faulty, so the requester only applies a request once f+1 peers sent the same digest for it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateReply {
+    entries: Vec<PrePrepare>,
+}
+
+impl StateReply {
+    pub fn new(entries: Vec<PrePrepare>) -> Self {
+        Self { entries }
+    }
+
+    pub fn entries(&self) -> &[PrePrepare] {
+        &self.entries
+    }
+
+    pub fn into_entries(self) -> Vec<PrePrepare> {
+        self.entries
     }
 }
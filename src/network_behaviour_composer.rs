@@ -1,52 +1,42 @@
 use crate::behavior::{Pbft, PbftEvent};
-use libp2p::mdns::{Mdns, MdnsEvent};
 use libp2p::swarm::NetworkBehaviourEventProcess;
+use libp2p::NetworkBehaviour;
 
-use tokio::prelude::{AsyncRead, AsyncWrite};
-
-/*
 #[derive(NetworkBehaviour)]
-*/
-pub struct NetworkBehaviourComposer<TSubstream: AsyncRead + AsyncWrite> {
-    mdns: Mdns,
-    pub pbft: Pbft<TSubstream>,
+#[behaviour(event_process = true)]
+pub struct NetworkBehaviourComposer {
+    pub pbft: Pbft,
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourComposer<TSubstream> {
-    pub fn new(mdns: Mdns, pbft: Pbft<TSubstream>) -> Self {
-        Self { mdns, pbft }
+impl NetworkBehaviourComposer {
+    pub fn new(pbft: Pbft) -> Self {
+        Self { pbft }
     }
 }
 
-impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<MdnsEvent>
-    for NetworkBehaviourComposer<TSubstream>
-{
-    fn inject_event(&mut self, event: MdnsEvent) {
+impl NetworkBehaviourEventProcess<PbftEvent> for NetworkBehaviourComposer {
+    fn inject_event(&mut self, event: PbftEvent) {
+        println!("inject_event : PbftEvent: {:?}", event);
         match event {
-            MdnsEvent::Discovered(list) => {
-                for (peer_id, address) in list {
-                    if !self.pbft.has_peer(&peer_id) {
-                        println!("[NetworkBehaviourComposer::inject_event] [MdnsEvent::Discovered] The node has been discovered: {:?}", address);
-                        self.pbft.add_peer(&peer_id, &address);
-                    }
+            PbftEvent::PeerDisconnected(peer_id) => {
+                // There is no view change yet: requests aren't ordered until the primary is redialled
+                if peer_id == self.pbft.next_primary() {
+                    eprintln!("[NetworkBehaviourComposer::inject_event] The primary is unreachable. peer_id: {:?}", peer_id);
                 }
             }
-            MdnsEvent::Expired(list) => {
-                for (peer_id, addr) in list {
-                    if self.pbft.has_peer(&peer_id) {
-                        println!("[NetworkBehaviourComposer::inject_event] [MdnsEvent::Expired] The node has been expired: {:?}", addr);
-                        // TODO
-                    }
-                }
+            PbftEvent::InvalidMessage { peer_id, error } => {
+                eprintln!("[NetworkBehaviourComposer::inject_event] Invalid message from peer_id: {:?}, error: {}", peer_id, error);
+            }
+            PbftEvent::StateTransferNeeded { peer_id, view } => {
+                eprintln!("[NetworkBehaviourComposer::inject_event] This replica is behind. peer_id: {:?}, view: {}", peer_id, view);
+                self.pbft.start_state_transfer();
+            }
+            PbftEvent::PeerBehind { peer_id, view } => {
+                eprintln!("[NetworkBehaviourComposer::inject_event] The peer is behind. peer_id: {:?}, view: {}", peer_id, view);
+            }
+            PbftEvent::PeerConnected(peer_id) => {
+                println!("[NetworkBehaviourComposer::inject_event] Connected to peer_id: {:?}", peer_id);
             }
         }
     }
 }
-
-impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<PbftEvent>
-    for NetworkBehaviourComposer<TSubstream>
-{
-    fn inject_event(&mut self, event: PbftEvent) {
-        println!("inject_event : PbftEvent: {:?}", event);
-    }
-}
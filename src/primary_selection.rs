@@ -0,0 +1,91 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+/// Decides which replica is the primary for a given view and sequence number.
+///
+/// `replicas` is the membership of the cluster in a canonical (sorted) order and is never empty. Every
+/// replica has to use the same policy, otherwise they disagree on who may send `PrePrepare`s.
+pub trait PrimarySelection {
+    fn primary(&self, view: u64, sequence_number: u64, replicas: &[PeerId]) -> PeerId;
+
+    /// Whether the primary can change within a view. Clients can't tell which sequence number their request
+    /// will get, so they send it to every replica instead of the primary.
+    fn rotates_within_view(&self) -> bool {
+        false
+    }
+}
+
+/// The policy from the PBFT paper: the primary of view _v_ is replica `v mod |R|`.
+pub struct RoundRobin;
+
+impl PrimarySelection for RoundRobin {
+    fn primary(&self, view: u64, _sequence_number: u64, replicas: &[PeerId]) -> PeerId {
+        replicas[(view % replicas.len() as u64) as usize]
+    }
+}
+
+/// Round-robin over the views, where a replica with weight _w_ is the primary of _w_ consecutive views.
+/// Replicas missing from `weights` have weight 1.
+pub struct WeightedRoundRobin {
+    weights: HashMap<PeerId, u64>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(weights: HashMap<PeerId, u64>) -> Self {
+        Self { weights }
+    }
+
+    fn weight(&self, peer_id: &PeerId) -> u64 {
+        self.weights.get(peer_id).cloned().unwrap_or(1)
+    }
+}
+
+impl PrimarySelection for WeightedRoundRobin {
+    fn primary(&self, view: u64, sequence_number: u64, replicas: &[PeerId]) -> PeerId {
+        let total: u64 = replicas.iter().map(|r| self.weight(r)).sum();
+        if total == 0 {
+            return RoundRobin.primary(view, sequence_number, replicas);
+        }
+
+        // `slot` is below the sum of the weights, so some replica always owns it
+        let mut slot = view % total;
+        replicas
+            .iter()
+            .find(|replica| {
+                let weight = self.weight(replica);
+                if slot < weight {
+                    return true;
+                }
+                slot -= weight;
+                false
+            })
+            .copied()
+            .unwrap_or_else(|| RoundRobin.primary(view, sequence_number, replicas))
+    }
+}
+
+/// Rotates the primary every `every` sequence numbers within a view, as in rotating-leader protocols.
+pub struct Rotating {
+    every: u64,
+}
+
+impl Rotating {
+    pub fn new(every: u64) -> Result<Self, String> {
+        if every == 0 {
+            return Err("the rotation interval must be positive".to_owned());
+        }
+        Ok(Self { every })
+    }
+}
+
+impl PrimarySelection for Rotating {
+    fn primary(&self, view: u64, sequence_number: u64, replicas: &[PeerId]) -> PeerId {
+        // Sequence numbers start at 1
+        let rotation = sequence_number.saturating_sub(1) / self.every;
+        replicas[((view + rotation) % replicas.len() as u64) as usize]
+    }
+
+    fn rotates_within_view(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,288 @@
+//! Conversions between the consensus messages and the types generated from `proto/pbft.proto`, and from
+//! `proto/pbft_v1.proto` for the legacy protocol id.
+
+use crate::message::{
+    Acknowledgement, ClientRequest, Commit, DecodeError, Frame, LegacyFrame, Message, NonDeterministicInputs,
+    PrePrepare, Prepare, RejectReason, StateReply, StateRequest,
+};
+use prost::Message as _;
+use std::convert::TryFrom;
+
+pub mod v1 {
+    include!(concat!(env!("OUT_DIR"), "/pbft.v1.rs"));
+}
+
+pub mod v2 {
+    include!(concat!(env!("OUT_DIR"), "/pbft.v2.rs"));
+}
+
+pub fn encode_frame(frame: &Frame) -> Vec<u8> {
+    let frame = v2::Frame::from(frame);
+    let mut bytes = Vec::with_capacity(frame.encoded_len());
+    // Writing into a `Vec` only fails when it runs out of capacity, which it can't
+    frame.encode(&mut bytes).expect("the buffer grows as needed");
+    bytes
+}
+
+pub fn decode_frame(bytes: &[u8]) -> Result<Frame, DecodeError> {
+    let frame = v2::Frame::decode(bytes).map_err(|e| DecodeError::Protobuf(e.to_string()))?;
+    Frame::try_from(frame)
+}
+
+pub fn encode_legacy_frame(frame: &LegacyFrame) -> Vec<u8> {
+    let frame = match frame {
+        LegacyFrame::Request { id, message } => v1::frame::Frame::Request(v1::frame::Request {
+            id: *id,
+            message: Some(transcode(&v2::Message::from(message))),
+        }),
+        LegacyFrame::Response { id, response } => v1::frame::Frame::Response(v1::frame::Response {
+            id: *id,
+            response: response.clone(),
+        }),
+    };
+    v1::Frame { frame: Some(frame) }.encode_to_vec()
+}
+
+pub fn decode_legacy_frame(bytes: &[u8]) -> Result<LegacyFrame, DecodeError> {
+    let frame = v1::Frame::decode(bytes).map_err(|e| DecodeError::Protobuf(e.to_string()))?;
+    match frame.frame.ok_or_else(|| missing("Frame.frame"))? {
+        v1::frame::Frame::Request(request) => {
+            let message: v2::Message = transcode(&request.message.ok_or_else(|| missing("Request.message"))?);
+            Ok(LegacyFrame::Request { id: request.id, message: Message::try_from(message)? })
+        }
+        v1::frame::Frame::Response(response) => Ok(LegacyFrame::Response {
+            id: response.id,
+            response: response.response,
+        }),
+    }
+}
+
+// pbft.v2 only added variants to `Message`, so the messages of both packages are the same on the wire. The
+// variants missing from pbft.v1 are dropped, which `Name::carries` rules out before encoding.
+fn transcode<From: prost::Message, To: prost::Message + Default>(message: &From) -> To {
+    To::decode(message.encode_to_vec().as_slice()).expect("pbft.v1 and pbft.v2 messages are wire compatible")
+}
+
+fn missing(field: &'static str) -> DecodeError {
+    DecodeError::MissingField(field)
+}
+
+impl From<&Frame> for v2::Frame {
+    fn from(frame: &Frame) -> Self {
+        let frame = match frame {
+            Frame::Request { id, message } => v2::frame::Frame::Request(v2::frame::Request {
+                id: *id,
+                message: Some(v2::Message::from(message)),
+            }),
+            Frame::Response { id, ack } => v2::frame::Frame::Response(v2::frame::Response {
+                id: *id,
+                ack: Some(v2::Acknowledgement::from(ack)),
+            }),
+        };
+        Self { frame: Some(frame) }
+    }
+}
+
+impl TryFrom<v2::Frame> for Frame {
+    type Error = DecodeError;
+
+    fn try_from(frame: v2::Frame) -> Result<Self, DecodeError> {
+        match frame.frame.ok_or_else(|| missing("Frame.frame"))? {
+            v2::frame::Frame::Request(request) => Ok(Frame::Request {
+                id: request.id,
+                message: Message::try_from(request.message.ok_or_else(|| missing("Request.message"))?)?,
+            }),
+            v2::frame::Frame::Response(response) => Ok(Frame::Response {
+                id: response.id,
+                ack: Acknowledgement::try_from(response.ack.ok_or_else(|| missing("Response.ack"))?)?,
+            }),
+        }
+    }
+}
+
+impl From<&Message> for v2::Message {
+    fn from(message: &Message) -> Self {
+        let message = match message {
+            Message::ClientRequest(m) => v2::message::Message::ClientRequest(m.into()),
+            Message::PrePrepare(m) => v2::message::Message::PrePrepare(m.into()),
+            Message::Prepare(m) => v2::message::Message::Prepare(m.into()),
+            Message::Commit(m) => v2::message::Message::Commit(m.into()),
+            Message::StateRequest(m) => v2::message::Message::StateRequest(v2::StateRequest { after: m.after() }),
+            Message::StateReply(m) => v2::message::Message::StateReply(v2::StateReply {
+                entries: m.entries().iter().map(v2::PrePrepare::from).collect(),
+            }),
+        };
+        Self { message: Some(message) }
+    }
+}
+
+impl TryFrom<v2::Message> for Message {
+    type Error = DecodeError;
+
+    fn try_from(message: v2::Message) -> Result<Self, DecodeError> {
+        Ok(match message.message.ok_or_else(|| missing("Message.message"))? {
+            v2::message::Message::ClientRequest(m) => Message::ClientRequest(ClientRequest::try_from(m)?),
+            v2::message::Message::PrePrepare(m) => Message::PrePrepare(PrePrepare::try_from(m)?),
+            v2::message::Message::Prepare(m) => Message::Prepare(m.into()),
+            v2::message::Message::Commit(m) => Message::Commit(m.into()),
+            v2::message::Message::StateRequest(m) => Message::StateRequest(StateRequest::new(m.after)),
+            v2::message::Message::StateReply(m) => Message::StateReply(StateReply::new(
+                m.entries.into_iter().map(PrePrepare::try_from).collect::<Result<_, _>>()?,
+            )),
+        })
+    }
+}
+
+impl From<&ClientRequest> for v2::ClientRequest {
+    fn from(request: &ClientRequest) -> Self {
+        Self {
+            operation: request.operation(),
+            timestamp: request.timestamp(),
+            client: request.client().to_string(),
+        }
+    }
+}
+
+impl TryFrom<v2::ClientRequest> for ClientRequest {
+    type Error = DecodeError;
+
+    fn try_from(request: v2::ClientRequest) -> Result<Self, DecodeError> {
+        let client = request
+            .client
+            .parse()
+            .map_err(|e: std::net::AddrParseError| DecodeError::InvalidAddress(e.to_string()))?;
+        Ok(ClientRequest::new(request.operation, request.timestamp, client))
+    }
+}
+
+impl From<&PrePrepare> for v2::PrePrepare {
+    fn from(pre_prepare: &PrePrepare) -> Self {
+        let non_deterministic = pre_prepare.non_deterministic();
+        Self {
+            view: pre_prepare.view(),
+            sequence_number: pre_prepare.sequence_number(),
+            digest: pre_prepare.digest().clone(),
+            message: Some(pre_prepare.client_reqeust().into()),
+            non_deterministic: Some(v2::NonDeterministicInputs {
+                timestamp: non_deterministic.timestamp(),
+                seed: non_deterministic.seed(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<v2::PrePrepare> for PrePrepare {
+    type Error = DecodeError;
+
+    fn try_from(pre_prepare: v2::PrePrepare) -> Result<Self, DecodeError> {
+        let message = pre_prepare.message.ok_or_else(|| missing("PrePrepare.message"))?;
+        let non_deterministic = pre_prepare
+            .non_deterministic
+            .ok_or_else(|| missing("PrePrepare.non_deterministic"))?;
+        // The digest is kept as received: it is checked against the request by `validate_digest`
+        Ok(PrePrepare::from_parts(
+            pre_prepare.view,
+            pre_prepare.sequence_number,
+            pre_prepare.digest,
+            ClientRequest::try_from(message)?,
+            NonDeterministicInputs::new(non_deterministic.timestamp, non_deterministic.seed),
+        ))
+    }
+}
+
+impl From<&Prepare> for v2::Prepare {
+    fn from(prepare: &Prepare) -> Self {
+        Self {
+            view: prepare.view(),
+            sequence_number: prepare.sequence_number(),
+            digest: prepare.digest().clone(),
+        }
+    }
+}
+
+impl From<v2::Prepare> for Prepare {
+    fn from(prepare: v2::Prepare) -> Self {
+        Prepare::new(prepare.view, prepare.sequence_number, prepare.digest)
+    }
+}
+
+impl From<&Commit> for v2::Commit {
+    fn from(commit: &Commit) -> Self {
+        Self {
+            view: commit.view(),
+            sequence_number: commit.sequence_number(),
+            digest: commit.digest().clone(),
+        }
+    }
+}
+
+impl From<v2::Commit> for Commit {
+    fn from(commit: v2::Commit) -> Self {
+        Commit::new(commit.view, commit.sequence_number, commit.digest)
+    }
+}
+
+impl From<&Acknowledgement> for v2::Acknowledgement {
+    fn from(ack: &Acknowledgement) -> Self {
+        let result = match ack {
+            Acknowledgement::Ack => v2::acknowledgement::Result::Ack(v2::acknowledgement::Ack {}),
+            Acknowledgement::Nack(reason) => v2::acknowledgement::Result::Nack(reason.into()),
+        };
+        Self { result: Some(result) }
+    }
+}
+
+impl TryFrom<v2::Acknowledgement> for Acknowledgement {
+    type Error = DecodeError;
+
+    fn try_from(ack: v2::Acknowledgement) -> Result<Self, DecodeError> {
+        Ok(match ack.result.ok_or_else(|| missing("Acknowledgement.result"))? {
+            v2::acknowledgement::Result::Ack(_) => Acknowledgement::Ack,
+            v2::acknowledgement::Result::Nack(reason) => Acknowledgement::Nack(RejectReason::try_from(reason)?),
+        })
+    }
+}
+
+impl From<&RejectReason> for v2::RejectReason {
+    fn from(reason: &RejectReason) -> Self {
+        use v2::reject_reason::Reason;
+
+        let reason = match reason {
+            RejectReason::WrongView { current_view } => Reason::WrongView(v2::reject_reason::WrongView {
+                current_view: *current_view,
+            }),
+            RejectReason::BadDigest => Reason::BadDigest(v2::reject_reason::BadDigest {}),
+            RejectReason::OutOfWindow { low, high } => Reason::OutOfWindow(v2::reject_reason::OutOfWindow {
+                low: *low,
+                high: *high,
+            }),
+            RejectReason::BadSignature => Reason::BadSignature(v2::reject_reason::BadSignature {}),
+            RejectReason::UnknownSequence => Reason::UnknownSequence(v2::reject_reason::UnknownSequence {}),
+            RejectReason::NotPrimary => Reason::NotPrimary(v2::reject_reason::NotPrimary {}),
+            RejectReason::BadNonDeterministicInputs => {
+                Reason::BadNonDeterministicInputs(v2::reject_reason::BadNonDeterministicInputs {})
+            }
+            RejectReason::Unavailable => Reason::Unavailable(v2::reject_reason::Unavailable {}),
+        };
+        Self { reason: Some(reason) }
+    }
+}
+
+impl TryFrom<v2::RejectReason> for RejectReason {
+    type Error = DecodeError;
+
+    fn try_from(reason: v2::RejectReason) -> Result<Self, DecodeError> {
+        use v2::reject_reason::Reason;
+
+        Ok(match reason.reason.ok_or_else(|| missing("RejectReason.reason"))? {
+            Reason::WrongView(r) => RejectReason::WrongView { current_view: r.current_view },
+            Reason::BadDigest(_) => RejectReason::BadDigest,
+            Reason::OutOfWindow(r) => RejectReason::OutOfWindow { low: r.low, high: r.high },
+            Reason::BadSignature(_) => RejectReason::BadSignature,
+            Reason::UnknownSequence(_) => RejectReason::UnknownSequence,
+            Reason::NotPrimary(_) => RejectReason::NotPrimary,
+            Reason::BadNonDeterministicInputs(_) => RejectReason::BadNonDeterministicInputs,
+            Reason::Unavailable(_) => RejectReason::Unavailable,
+        })
+    }
+}
@@ -1,129 +1,412 @@
-use crate::message::Message;
-use bytes::BytesMut;
+use crate::message::{DecodeError, Frame, LegacyFrame, Message};
+use crate::proto;
+use asynchronous_codec::{Decoder, Encoder, Framed};
+use bytes::{Bytes, BytesMut};
 use futures::future;
-use futures::{Sink, Stream};
+use futures::io::{AsyncRead, AsyncWrite};
 use libp2p::core::ProtocolName;
-use libp2p::core::{Negotiated, UpgradeInfo};
+use libp2p::core::UpgradeInfo;
 use libp2p::{InboundUpgrade, OutboundUpgrade};
+use serde::{Deserialize, Serialize};
+use unsigned_varint::codec::UviBytes;
 
-use tokio_util;
-use tokio_util::codec::Framed;
+/// A wire format, advertised under its own protocol id. The one both ends support and the dialer prefers
+/// is picked during the protocol upgrade, so a new encoding can be rolled out across a running cluster.
+///
+/// The legacy formats answer requests with a raw "OK" instead of an `Acknowledgement`. They are kept so that
+/// replicas can be upgraded one at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Name {
+    /// JSON, as produced by `serde_json`
+    JsonV3,
+    /// Protocol Buffers, as described by the `pbft.v2` package of `proto/pbft.proto`
+    ProtobufV3,
+    /// Legacy JSON
+    JsonV1,
+    /// Legacy Protocol Buffers, as described by `proto/pbft_v1.proto`
+    ProtobufV2,
+}
 
-use future::FutureResult; // Import the type or trait
+impl Name {
+    /// Whether a message can be sent in this wire format. The legacy formats predate state transfer.
+    pub fn carries(&self, message: &Message) -> bool {
+        match self {
+            Name::JsonV3 | Name::ProtobufV3 => true,
+            Name::JsonV1 | Name::ProtobufV2 => !matches!(message, Message::StateRequest(_) | Message::StateReply(_)),
+        }
+    }
+}
 
+impl ProtocolName for Name {
+    fn protocol_name(&self) -> &[u8] {
+        match self {
+            Name::JsonV3 => b"/ackintosh/pbft/json/3.0.0",
+            Name::ProtobufV3 => b"/ackintosh/pbft/3.0.0",
+            Name::JsonV1 => b"/ackintosh/pbft/1.0.0",
+            Name::ProtobufV2 => b"/ackintosh/pbft/2.0.0",
+        }
+    }
+}
 
-use tokio::prelude::{AsyncRead, AsyncWrite};
+/// Upper bound on the size of a frame, in bytes. Longer frames are rejected without being buffered.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
 
-use unsigned_varint::codec::UviBytes;
+#[derive(Clone, Debug)]
+pub struct PbftProtocolConfig {
+    // The supported wire formats, in order of preference
+    versions: Vec<Name>,
+    max_frame_size: usize,
+}
 
-#[derive(Clone)]
-pub struct Name;
+impl PbftProtocolConfig {
+    pub fn new(versions: Vec<Name>) -> Self {
+        assert!(!versions.is_empty(), "at least one wire format must be supported");
+        Self { versions, max_frame_size: MAX_FRAME_SIZE }
+    }
 
-impl ProtocolName for Name {
-    fn protocol_name(&self) -> &[u8] {
-        b"/ackintosh/pbft/1.0.0"
+    pub fn versions(&self) -> &[Name] {
+        &self.versions
+    }
+
+    /// Tells whether an error read from a `PbftStream` is caused by the bytes the peer sent, as opposed to
+    /// the connection itself failing.
+    pub fn decode_error(&self, error: &std::io::Error) -> Option<DecodeError> {
+        if let Some(e) = error.get_ref().and_then(|e| e.downcast_ref::<DecodeError>()) {
+            return Some(e.clone());
+        }
+        match error.kind() {
+            // `UviBytes` reports a length prefix above the maximum this way
+            std::io::ErrorKind::PermissionDenied => Some(DecodeError::FrameTooLarge { max: self.max_frame_size }),
+            _ => None,
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct PbftProtocolConfig;
+impl Default for PbftProtocolConfig {
+    fn default() -> Self {
+        Self::new(vec![Name::ProtobufV3, Name::JsonV3, Name::ProtobufV2, Name::JsonV1])
+    }
+}
 
 impl UpgradeInfo for PbftProtocolConfig {
     type Info = Name;
-    type InfoIter = std::iter::Once<Self::Info>;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        println!("Pbft::protocol_info()");
-        std::iter::once(Name {})
+        self.versions.clone().into_iter()
     }
 }
 
+// Both ends of a substream speak the same framing: requests one way, responses the other, each tagged with
+// the id of the request so that they can be pipelined.
 impl<TSubstream> InboundUpgrade<TSubstream> for PbftProtocolConfig
 where
-    TSubstream: AsyncRead + AsyncWrite,
+    TSubstream: AsyncRead + AsyncWrite + Unpin,
 {
-    type Output = PbftInStreamSink<Negotiated<TSubstream>>;
+    type Output = PbftStream<TSubstream>;
     type Error = std::io::Error;
-    type Future = FutureResult<Self::Output, std::io::Error>;
-
-    fn upgrade_inbound(self, socket: Negotiated<TSubstream>, _info: Self::Info) -> Self::Future {
-        println!("PbftProtocolConfig::upgrade_inbound");
-        let codec = UviBytes::default();
-
-        // TODO: Protocol Buffers
-        futures::future::ok(
-            Framed::new(socket, codec)
-                .from_err()
-                .with::<_, fn(_) -> _, _>(|response| {
-                    println!(
-                        "[PbftProtocolConfig::upgrade_inbound] [with] response: {:?}",
-                        response
-                    );
-                    Ok(response)
-                })
-                .and_then::<fn(_) -> _, _>(|bytes| {
-                    println!("[PbftProtocolConfig::upgrade_inbound] [and_then]");
-                    Ok(bytes_to_message(&bytes))
-                }),
-        )
+    type Future = future::Ready<Result<Self::Output, std::io::Error>>;
+
+    fn upgrade_inbound(self, socket: TSubstream, info: Self::Info) -> Self::Future {
+        future::ok(framed(socket, info, self.max_frame_size))
     }
 }
 
 impl<TSubstream> OutboundUpgrade<TSubstream> for PbftProtocolConfig
 where
-    TSubstream: AsyncRead + AsyncWrite,
+    TSubstream: AsyncRead + AsyncWrite + Unpin,
 {
-    type Output = PbftOutStreamSink<Negotiated<TSubstream>>;
-    type Error = <Self as InboundUpgrade<TSubstream>>::Error;
-    type Future = FutureResult<Self::Output, std::io::Error>;
-
-    fn upgrade_outbound(self, socket: Negotiated<TSubstream>, _info: Self::Info) -> Self::Future {
-        println!("[PbftProtocolConfig::upgrade_outbound]");
-        let codec = UviBytes::default();
-
-        // TODO: Protocol Buffers
-        futures::future::ok(
-            Framed::new(socket, codec)
-                .from_err()
-                .with::<_, fn(_) -> _, _>(|outbound_message| {
-                    println!(
-                        "[PbftProtocolConfig::upgrade_outbound] [with] outbound_message : {:?}",
-                        outbound_message
-                    );
-                    Ok(message_to_json(&outbound_message).into_bytes())
-                })
-                .and_then::<fn(_) -> _, _>(|bytes| {
-                    println!("[PbftProtocolConfig::upgrade_outbound] [and_then]");
-                    Ok(bytes.to_vec())
-                }),
-        )
-    }
-}
-
-pub type PbftInStreamSink<S> = PbftStreamSink<S, Vec<u8>, Message>;
-
-pub type PbftOutStreamSink<S> = PbftStreamSink<S, Message, Vec<u8>>;
-
-pub type PbftStreamSink<S, A, B> = futures::stream::AndThen<
-    futures::sink::With<
-        <Framed<S, UviBytes<Vec<u8>>> as Example>::err_into::std::io::Error>::A,
-        fn(A) -> Result<Vec<u8>, std::io::Error>,
-        Result<Vec<u8>, std::io::Error>, 
-    >;
-    BytesMut :: Result::B::std::io::Error::
-    Result::B::std::io::Error;
-
-fn message_to_json(message: &Message) -> String {fvh;
-    let json = match message {
-        Message::PrePrepare(_) | Message::Prepare(_) | Message::Commit(_) => message.to_string(),
-        Message::ClientRequest(_) => unreachable!(),
-    };
-    println!("[protocol_config::message_to_json] json: {:?}", json);
-    return json;
+    type Output = PbftStream<TSubstream>;
+    type Error = std::io::Error;
+    type Future = future::Ready<Result<Self::Output, std::io::Error>>;
+
+    fn upgrade_outbound(self, socket: TSubstream, info: Self::Info) -> Self::Future {
+        future::ok(framed(socket, info, self.max_frame_size))
+    }
+}
+
+fn framed<S>(socket: S, name: Name, max_frame_size: usize) -> PbftStream<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let mut length_prefix = UviBytes::default();
+    length_prefix.set_max_len(max_frame_size);
+    Framed::new(socket, PbftCodec { name, length_prefix })
+}
+
+/// A long-lived substream carrying `Frame`s in both directions.
+pub type PbftStream<S> = Framed<S, PbftCodec>;
+
+/// Length-prefixed frames, each holding one `Frame` in the wire format negotiated for the substream.
+pub struct PbftCodec {
+    name: Name,
+    length_prefix: UviBytes<Bytes>,
+}
+
+impl PbftCodec {
+    /// The wire format negotiated for the substream.
+    pub fn name(&self) -> Name {
+        self.name
+    }
+}
+
+impl Encoder for PbftCodec {
+    type Item = Frame;
+    type Error = std::io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if let Frame::Request { message, .. } = &frame {
+            if !self.name.carries(message) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{:?} can't carry the message: {:?}", self.name, message),
+                ));
+            }
+        }
+        let bytes = match self.name {
+            Name::JsonV3 => encode_json(&frame)?,
+            Name::ProtobufV3 => proto::encode_frame(&frame),
+            Name::JsonV1 => encode_json(&LegacyFrame::from(frame))?,
+            Name::ProtobufV2 => proto::encode_legacy_frame(&LegacyFrame::from(frame)),
+        };
+        self.length_prefix.encode(Bytes::from(bytes), dst)
+    }
 }
 
-fn bytes_to_message(bytes: &BytesMut) -> Message {
-    let message = bytes.to_vec().into();
-    println!("[protocol_config::bytes_to_message] message: {:?}", message);
-    return message;
+impl Decoder for PbftCodec {
+    type Item = Frame;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Self::Error> {
+        let bytes = match self.length_prefix.decode(src)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        let frame = match self.name {
+            Name::JsonV3 => decode_json(&bytes)?,
+            Name::ProtobufV3 => proto::decode_frame(&bytes).map_err(invalid_data)?,
+            Name::JsonV1 => decode_json::<LegacyFrame>(&bytes)?.into(),
+            Name::ProtobufV2 => proto::decode_legacy_frame(&bytes).map_err(invalid_data)?.into(),
+        };
+        Ok(Some(frame))
+    }
+}
+
+fn encode_json<T: Serialize>(frame: &T) -> Result<Vec<u8>, std::io::Error> {
+    serde_json::to_vec(frame).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+fn decode_json<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, std::io::Error> {
+    serde_json::from_slice(bytes).map_err(|e| invalid_data(DecodeError::Json(e.to_string())))
+}
+
+fn invalid_data(error: DecodeError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{
+        Acknowledgement, ClientRequest, Commit, NonDeterministicInputs, PrePrepare, Prepare, RejectReason, StateReply,
+        StateRequest,
+    };
+    use futures::{SinkExt, StreamExt};
+    use libp2p::core::transport::{ListenerEvent, MemoryTransport, Transport};
+    use libp2p::core::upgrade::{apply_inbound, apply_outbound, Version};
+
+    const NAMES: [Name; 4] = [Name::JsonV3, Name::ProtobufV3, Name::JsonV1, Name::ProtobufV2];
+    const LEGACY_NAMES: [Name; 2] = [Name::JsonV1, Name::ProtobufV2];
+
+    fn codec(name: Name) -> PbftCodec {
+        let mut length_prefix = UviBytes::default();
+        length_prefix.set_max_len(MAX_FRAME_SIZE);
+        PbftCodec { name, length_prefix }
+    }
+
+    fn frames() -> Vec<Frame> {
+        let client_request = ClientRequest::new("PUT x 1".into(), 1, "127.0.0.1:9000".parse().unwrap());
+        let non_deterministic = NonDeterministicInputs::propose(1_700_000_000_000, 0, 1, &client_request);
+        let pre_prepare = PrePrepare::from(0, 1, client_request.clone(), non_deterministic);
+        let prepare = Prepare::from(&pre_prepare);
+        let commit: Commit = prepare.clone().into();
+
+        let mut frames: Vec<Frame> = vec![
+            Message::ClientRequest(client_request),
+            Message::PrePrepare(pre_prepare.clone()),
+            Message::Prepare(prepare),
+            Message::Commit(commit),
+            Message::StateRequest(StateRequest::new(1)),
+            Message::StateReply(StateReply::new(vec![pre_prepare])),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(id, message)| Frame::Request { id: id as u64, message })
+        .collect();
+
+        let acks = vec![
+            Acknowledgement::Ack,
+            Acknowledgement::Nack(RejectReason::WrongView { current_view: 2 }),
+            Acknowledgement::Nack(RejectReason::BadDigest),
+            Acknowledgement::Nack(RejectReason::OutOfWindow { low: 1, high: 200 }),
+            Acknowledgement::Nack(RejectReason::BadSignature),
+            Acknowledgement::Nack(RejectReason::UnknownSequence),
+            Acknowledgement::Nack(RejectReason::NotPrimary),
+            Acknowledgement::Nack(RejectReason::BadNonDeterministicInputs),
+            Acknowledgement::Nack(RejectReason::Unavailable),
+        ];
+        frames.extend(acks.into_iter().enumerate().map(|(id, ack)| Frame::Response { id: id as u64, ack }));
+        frames
+    }
+
+    fn decode_error(name: Name, bytes: &[u8]) -> Option<DecodeError> {
+        let mut src = BytesMut::new();
+        UviBytes::<Bytes>::default().encode(Bytes::copy_from_slice(bytes), &mut src).unwrap();
+        let error = codec(name).decode(&mut src).unwrap_err();
+        PbftProtocolConfig::default().decode_error(&error)
+    }
+
+    // Runs the protocol upgrade between a dialer and a listener that support `dialer` and `listener`, then
+    // sends a request and its answer over the negotiated substream. Returns the wire format both ends picked.
+    fn negotiate(dialer: Vec<Name>, listener: Vec<Name>) -> Option<Name> {
+        futures::executor::block_on(async {
+            let mut listening = MemoryTransport.listen_on("/memory/0".parse().unwrap()).unwrap();
+            let address = match listening.next().await {
+                Some(Ok(ListenerEvent::NewAddress(address))) => address,
+                _ => panic!("the listener should report its address"),
+            };
+            let accept = async {
+                match listening.next().await {
+                    Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) => upgrade.await.unwrap(),
+                    _ => panic!("the listener should accept the connection"),
+                }
+            };
+            let (outbound, inbound) = futures::join!(MemoryTransport.dial(address).unwrap(), accept);
+
+            let outbound = async {
+                let mut substream = apply_outbound(outbound.unwrap(), PbftProtocolConfig::new(dialer), Version::V1)
+                    .await
+                    .ok()?;
+                substream.send(frames().remove(0)).await.unwrap();
+                // The dialer may settle on a protocol before the listener confirms it. Reading the answer
+                // completes the negotiation.
+                let response = substream.next().await?.unwrap();
+                assert!(matches!(response, Frame::Response { id: 0, ack: Acknowledgement::Ack }));
+                Some(substream.codec().name())
+            };
+            let inbound = async {
+                let mut substream = apply_inbound(inbound, PbftProtocolConfig::new(listener)).await.ok()?;
+                let request = substream.next().await?.unwrap();
+                assert!(matches!(request, Frame::Request { id: 0, .. }));
+                substream.send(Frame::Response { id: 0, ack: Acknowledgement::Ack }).await.unwrap();
+                Some(substream.codec().name())
+            };
+            let (outbound, inbound) = futures::join!(outbound, inbound);
+            assert_eq!(outbound, inbound);
+            outbound
+        })
+    }
+
+    #[test]
+    fn negotiation() {
+        let upgraded = PbftProtocolConfig::default().versions().to_vec();
+        let legacy = vec![Name::ProtobufV2, Name::JsonV1];
+
+        assert_eq!(negotiate(upgraded.clone(), upgraded.clone()), Some(Name::ProtobufV3));
+        // A rolling upgrade: upgraded and legacy replicas fall back to the legacy format in both directions
+        assert_eq!(negotiate(upgraded.clone(), legacy.clone()), Some(Name::ProtobufV2));
+        assert_eq!(negotiate(legacy.clone(), upgraded), Some(Name::ProtobufV2));
+        assert_eq!(negotiate(vec![Name::JsonV1], legacy), Some(Name::JsonV1));
+        // The dialer's preference wins
+        assert_eq!(
+            negotiate(vec![Name::JsonV3, Name::ProtobufV3], vec![Name::ProtobufV3, Name::JsonV3]),
+            Some(Name::JsonV3)
+        );
+        assert_eq!(negotiate(vec![Name::ProtobufV3], vec![Name::JsonV3, Name::ProtobufV2]), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        for name in [Name::JsonV3, Name::ProtobufV3] {
+            let mut codec = codec(name);
+            for frame in frames() {
+                let mut bytes = BytesMut::new();
+                codec.encode(frame.clone(), &mut bytes).unwrap();
+                let decoded = codec.decode(&mut bytes).unwrap().expect("a whole frame");
+                assert_eq!(format!("{:?}", decoded), format!("{:?}", frame), "{:?}", name);
+                assert!(bytes.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_round_trip() {
+        for name in LEGACY_NAMES {
+            let mut codec = codec(name);
+            for frame in frames() {
+                let mut bytes = BytesMut::new();
+                match &frame {
+                    Frame::Request { message, .. } if !name.carries(message) => {
+                        let error = codec.encode(frame, &mut bytes).unwrap_err();
+                        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+                        assert!(bytes.is_empty());
+                        continue;
+                    }
+                    _ => codec.encode(frame.clone(), &mut bytes).unwrap(),
+                }
+                let decoded = codec.decode(&mut bytes).unwrap().expect("a whole frame");
+                // Legacy replicas only tell "OK" from anything else
+                let expected = Frame::from(LegacyFrame::from(frame));
+                assert_eq!(format!("{:?}", decoded), format!("{:?}", expected), "{:?}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_responses() {
+        let ok = LegacyFrame::Response { id: 1, response: b"OK".to_vec() };
+        assert!(matches!(Frame::from(ok), Frame::Response { id: 1, ack: Acknowledgement::Ack }));
+        let other = LegacyFrame::Response { id: 2, response: b"error".to_vec() };
+        assert!(matches!(
+            Frame::from(other),
+            Frame::Response { id: 2, ack: Acknowledgement::Nack(RejectReason::Unavailable) }
+        ));
+    }
+
+    #[test]
+    fn partial_frame() {
+        for name in NAMES {
+            let mut codec = codec(name);
+            let mut bytes = BytesMut::new();
+            codec.encode(frames().remove(0), &mut bytes).unwrap();
+            let mut partial = bytes.split_to(bytes.len() - 1);
+            assert!(codec.decode(&mut partial).unwrap().is_none());
+            partial.unsplit(bytes);
+            assert!(codec.decode(&mut partial).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn frame_too_large() {
+        for name in NAMES {
+            let mut src = BytesMut::new();
+            let mut length_prefix = unsigned_varint::encode::usize_buffer();
+            src.extend_from_slice(unsigned_varint::encode::usize(MAX_FRAME_SIZE + 1, &mut length_prefix));
+            let error = codec(name).decode(&mut src).unwrap_err();
+            assert_eq!(
+                PbftProtocolConfig::default().decode_error(&error),
+                Some(DecodeError::FrameTooLarge { max: MAX_FRAME_SIZE })
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_frames() {
+        assert!(matches!(decode_error(Name::JsonV3, b"{\"Request\": "), Some(DecodeError::Json(_))));
+        assert!(matches!(decode_error(Name::JsonV3, &[0xff, 0xfe]), Some(DecodeError::Json(_))));
+        assert!(matches!(decode_error(Name::JsonV1, b"\"Request\""), Some(DecodeError::Json(_))));
+        assert!(matches!(decode_error(Name::ProtobufV3, &[0xff, 0xff, 0xff]), Some(DecodeError::Protobuf(_))));
+        assert!(matches!(decode_error(Name::ProtobufV2, &[0xff, 0xff, 0xff]), Some(DecodeError::Protobuf(_))));
+    }
 }
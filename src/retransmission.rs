@@ -0,0 +1,150 @@
+use crate::message::Message;
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+// How many times a message is sent before the peer is given up on. A peer that still hasn't acknowledged it
+// is unreachable or behind, and catches up with a state transfer instead.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Identifies a consensus message for acknowledgement purposes. A replica sends at most one message of
+/// each kind per (view, sequence number) to a peer.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MessageKey {
+    kind: MessageKind,
+    view: u64,
+    sequence_number: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum MessageKind {
+    PrePrepare,
+    Prepare,
+    Commit,
+}
+
+impl MessageKey {
+    /// Returns `None` for messages that are not retransmitted.
+    pub fn of(message: &Message) -> Option<Self> {
+        let (kind, view, sequence_number) = match message {
+            Message::PrePrepare(m) => (MessageKind::PrePrepare, m.view(), m.sequence_number()),
+            Message::Prepare(m) => (MessageKind::Prepare, m.view(), m.sequence_number()),
+            Message::Commit(m) => (MessageKind::Commit, m.view(), m.sequence_number()),
+            Message::ClientRequest(_) | Message::StateRequest(_) | Message::StateReply(_) => return None,
+        };
+        Some(Self { kind, view, sequence_number })
+    }
+}
+
+struct Pending {
+    message: Message,
+    // When the message was last handed to the network, in milliseconds. `None` resends it on the next poll.
+    sent_at: Option<u64>,
+    attempts: u32,
+}
+
+/// Remembers the consensus messages each peer hasn't acknowledged yet, and decides when to send them again.
+pub struct Retransmission {
+    unacked: HashMap<PeerId, HashMap<MessageKey, Pending>>,
+    // Milliseconds to wait for an acknowledgement before resending
+    timeout: u64,
+    max_attempts: u32,
+}
+
+impl Retransmission {
+    pub fn new(timeout: u64) -> Self {
+        Self {
+            unacked: HashMap::new(),
+            timeout,
+            max_attempts: MAX_ATTEMPTS,
+        }
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    pub fn sent(&mut self, peer_id: &PeerId, message: &Message, now: u64) {
+        let key = match MessageKey::of(message) {
+            Some(key) => key,
+            None => return,
+        };
+        let pending = self
+            .unacked
+            .entry(*peer_id)
+            .or_default()
+            .entry(key)
+            .or_insert(Pending {
+                message: message.clone(),
+                sent_at: None,
+                attempts: 0,
+            });
+        pending.sent_at = Some(now);
+        pending.attempts += 1;
+    }
+
+    pub fn acked(&mut self, peer_id: &PeerId, message: &Message) {
+        if let (Some(key), Some(pending)) = (MessageKey::of(message), self.unacked.get_mut(peer_id)) {
+            if pending.remove(&key).is_some() {
+                println!("[Retransmission::acked] peer_id: {:?}, key: {:?}", peer_id, key);
+            }
+        }
+    }
+
+    /// Sending the message failed. It is resent on the next poll instead of waiting for the timeout.
+    pub fn failed(&mut self, peer_id: &PeerId, message: &Message) {
+        if let (Some(key), Some(pending)) = (MessageKey::of(message), self.unacked.get_mut(peer_id)) {
+            if let Some(p) = pending.get_mut(&key) {
+                p.sent_at = None;
+            }
+        }
+    }
+
+    /// The unacknowledged messages of `peer_id` whose timeout has elapsed. Messages sent `max_attempts` times
+    /// already are dropped instead.
+    pub fn due(&mut self, peer_id: &PeerId, now: u64) -> Vec<Message> {
+        let pending = match self.unacked.get_mut(peer_id) {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+        let timeout = self.timeout;
+        let max_attempts = self.max_attempts;
+        let is_due = |p: &Pending| p.sent_at.is_none_or(|sent_at| sent_at + timeout <= now);
+
+        pending.retain(|key, p| {
+            if is_due(p) && p.attempts >= max_attempts {
+                eprintln!(
+                    "[Retransmission::due] Gave up after {} attempts. peer_id: {:?}, key: {:?}",
+                    p.attempts, peer_id, key
+                );
+                return false;
+            }
+            true
+        });
+        pending
+            .values()
+            .filter(|p| is_due(p))
+            .map(|p| {
+                println!(
+                    "[Retransmission::due] peer_id: {:?}, attempts: {}, message: {:?}",
+                    peer_id, p.attempts, p.message
+                );
+                p.message.clone()
+            })
+            .collect()
+    }
+
+    /// Every unacknowledged message of `peer_id`, e.g. to resend them after a reconnect.
+    pub fn all(&self, peer_id: &PeerId) -> Vec<Message> {
+        match self.unacked.get(peer_id) {
+            Some(pending) => pending.values().map(|p| p.message.clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Stops retransmitting messages for sequence numbers up to and including `sequence_number`.
+    pub fn garbage_collect(&mut self, sequence_number: u64) {
+        for pending in self.unacked.values_mut() {
+            pending.retain(|key, _| key.sequence_number > sequence_number);
+        }
+    }
+}
@@ -0,0 +1,637 @@
+use crate::behavior::{Pbft, PbftEvent};
+use crate::clock::Clock;
+use crate::handler::{message_to_handler_event, ConnectionId, PbftHandlerEvent, PbftHandlerIn};
+use crate::message::{Acknowledgement, ClientReply, ClientRequest, Message, NonDeterministicInputs, PrePrepare};
+use libp2p::identity::{ed25519, Keypair};
+use libp2p::swarm::NetworkBehaviourAction;
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// The address of the client that sends every submitted request.
+pub const CLIENT: &str = "127.0.0.1:9000";
+
+// How often, in virtual milliseconds, `run_until` lets the replicas act on their timers, e.g. to retransmit
+const TIMER_RESOLUTION: u64 = 100;
+
+/// Runs `replicas` instances of `Pbft` in a single process over a simulated network.
+///
+/// Every source of non-determinism (message ordering, delays, drops) is drawn from a single RNG seeded
+/// with `SimConfig::seed`, and time is a virtual clock that only advances when a message is delivered,
+/// so a run can be reproduced exactly from its configuration.
+pub struct Simulator {
+    config: SimConfig,
+    rng: SimRng,
+    // The virtual clock in milliseconds, shared with the replicas
+    now: u64,
+    clock: VirtualClock,
+    nodes: Vec<SimNode>,
+    in_flight: Vec<InFlight>,
+    next_message_id: u64,
+    // Partition group per replica. Messages between different groups are dropped on delivery.
+    partition: Option<Vec<usize>>,
+    crashed: HashSet<usize>,
+    // Replicas that propose a different request to the upper half of the cluster than to the lower half
+    equivocating: HashSet<usize>,
+    // Ids of messages that will be lost in transit, set by fault injection
+    dropped: HashSet<u64>,
+    replies: Vec<DeliveredReply>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub replicas: usize,
+    // The number of faulty replicas tolerated, the most `replicas` allows by default
+    pub f: usize,
+    pub seed: u64,
+    pub min_delay: u64,
+    pub max_delay: u64,
+    pub drop_probability: f64,
+}
+
+impl SimConfig {
+    pub fn new(replicas: usize, seed: u64) -> Self {
+        Self {
+            replicas,
+            f: replicas.saturating_sub(1) / 3,
+            seed,
+            min_delay: 1,
+            max_delay: 10,
+            drop_probability: 0.0,
+        }
+    }
+}
+
+struct SimNode {
+    pbft: Pbft,
+    // Kept to restart the replica with the same identity
+    keypair: Keypair,
+    peer_id: PeerId,
+    client_replies: Arc<RwLock<VecDeque<ClientReply>>>,
+    next_connection_id: ConnectionId,
+    // The replica which sent the request identified by the connection id, and the request itself, to route
+    // the response back.
+    pending_responses: HashMap<ConnectionId, (usize, Message)>,
+}
+
+struct InFlight {
+    id: u64,
+    deliver_at: u64,
+    from: Option<usize>, // `None` for messages sent by the client
+    to: usize,
+    payload: Payload,
+}
+
+#[derive(Debug)]
+enum Payload {
+    ClientRequest(ClientRequest),
+    Request(Message),
+    Response(Message, Acknowledgement),
+}
+
+#[derive(Debug)]
+pub struct DeliveredReply {
+    pub at: u64,
+    pub replica: usize,
+    pub reply: ClientReply,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let mut rng = SimRng::new(config.seed);
+        let clock = VirtualClock::new();
+        let keypairs: Vec<Keypair> = (0..config.replicas).map(|_| keypair_from_seed(rng.next_u64())).collect();
+        let members: Vec<PeerId> = keypairs.iter().map(|k| PeerId::from_public_key(&k.public())).collect();
+        let nodes: Vec<SimNode> = keypairs
+            .into_iter()
+            .map(|keypair| SimNode::new(keypair, config.f, &members, &clock))
+            .collect();
+
+        let mut simulator = Self {
+            config,
+            rng,
+            now: 0,
+            clock,
+            nodes,
+            in_flight: Vec::new(),
+            next_message_id: 0,
+            partition: None,
+            crashed: HashSet::new(),
+            equivocating: HashSet::new(),
+            dropped: HashSet::new(),
+            replies: Vec::new(),
+        };
+
+        // Start with a fully connected cluster
+        for i in 0..simulator.nodes.len() {
+            for j in 0..simulator.nodes.len() {
+                if i != j {
+                    let peer_id = simulator.nodes[j].peer_id;
+                    simulator.nodes[i].pbft.peer_connected(peer_id);
+                }
+            }
+        }
+        simulator
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn replica(&self, replica: usize) -> &Pbft {
+        &self.nodes[replica].pbft
+    }
+
+    /// The replica that should receive the next client request, according to the first live replica.
+    pub fn primary(&self) -> usize {
+        let observer = (0..self.nodes.len())
+            .find(|i| !self.crashed.contains(i))
+            .unwrap_or(0);
+        let primary = self.nodes[observer].pbft.next_primary();
+        self.nodes
+            .iter()
+            .position(|n| n.peer_id == primary)
+            .expect("the primary is a member of the simulated cluster")
+    }
+
+    pub fn replies(&self) -> &[DeliveredReply] {
+        &self.replies
+    }
+
+    /// Sends a client request to `replica`. The request is subject to the same delays as replica traffic.
+    pub fn submit(&mut self, replica: usize, operation: &str, timestamp: u64) {
+        let client: SocketAddr = CLIENT.parse().unwrap();
+        let request = ClientRequest::new(operation.to_owned(), timestamp, client);
+        self.send(None, replica, Payload::ClientRequest(request));
+    }
+
+    /// Splits the cluster so that only replicas in the same group can talk to each other.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let mut partition = vec![usize::MAX; self.nodes.len()];
+        for (group, replicas) in groups.iter().enumerate() {
+            for replica in replicas.iter() {
+                partition[*replica] = group;
+            }
+        }
+        println!("[Simulator::partition] at: {}, partition: {:?}", self.now, partition);
+        self.partition = Some(partition);
+    }
+
+    pub fn heal(&mut self) {
+        println!("[Simulator::heal] at: {}", self.now);
+        self.partition = None;
+    }
+
+    /// A crashed replica neither receives nor sends messages until it is restarted.
+    pub fn crash(&mut self, replica: usize) {
+        println!("[Simulator::crash] at: {}, replica: {}", self.now, replica);
+        self.crashed.insert(replica);
+    }
+
+    /// Turns `replica` Byzantine: the PrePrepares it sends to the upper half of the cluster carry a different
+    /// request, with a valid digest, than the ones it sends to the lower half and keeps itself.
+    pub fn equivocate(&mut self, replica: usize) {
+        println!("[Simulator::equivocate] at: {}, replica: {}", self.now, replica);
+        self.equivocating.insert(replica);
+    }
+
+    /// Brings a crashed replica back with its identity but none of its volatile state: its log, view, state
+    /// machine and pending messages are gone. The other replicas reconnect to it and resend what it never
+    /// acknowledged.
+    pub fn restart(&mut self, replica: usize) {
+        println!("[Simulator::restart] at: {}, replica: {}", self.now, replica);
+        self.crashed.remove(&replica);
+
+        let keypair = self.nodes[replica].keypair.clone();
+        let members: Vec<PeerId> = self.nodes.iter().map(|n| n.peer_id).collect();
+        self.nodes[replica] = SimNode::new(keypair, self.config.f, &members, &self.clock);
+        for other in 0..self.nodes.len() {
+            if other != replica {
+                let peer_id = self.nodes[other].peer_id;
+                self.nodes[replica].pbft.peer_connected(peer_id);
+                let peer_id = self.nodes[replica].peer_id;
+                self.nodes[other].pbft.peer_connected(peer_id);
+            }
+        }
+        for other in 0..self.nodes.len() {
+            if !self.crashed.contains(&other) {
+                self.collect_outputs(other);
+            }
+        }
+    }
+
+    /// Loses the message with the given id, whether it has already been sent or not.
+    pub fn drop_message(&mut self, id: u64) {
+        self.dropped.insert(id);
+    }
+
+    /// The delivery time of the next message, if any.
+    pub fn next_delivery(&self) -> Option<u64> {
+        self.in_flight.iter().map(|m| m.deliver_at).min()
+    }
+
+    /// Delivers the next message. Returns `false` when there is nothing left in flight.
+    pub fn step(&mut self) -> bool {
+        let next = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, m)| (m.deliver_at, m.id))
+            .map(|(pos, _)| pos);
+
+        let message = match next {
+            Some(pos) => self.in_flight.swap_remove(pos),
+            None => return false,
+        };
+        self.advance_to(message.deliver_at);
+
+        if let Some(reason) = self.drop_reason(&message) {
+            println!("[Simulator::step] at: {}, dropped id: {}, reason: {}", self.now, message.id, reason);
+            return true;
+        }
+        println!("[Simulator::step] at: {}, delivered id: {}, to: {}", self.now, message.id, message.to);
+        self.deliver(message);
+        true
+    }
+
+    /// Delivers every message scheduled up to (and including) `time`, then moves the clock to `time`. The live
+    /// replicas act on their timers every `TIMER_RESOLUTION` milliseconds on the way.
+    pub fn run_until(&mut self, time: u64) {
+        loop {
+            let tick = std::cmp::min(time, (self.now / TIMER_RESOLUTION + 1) * TIMER_RESOLUTION);
+            while self.in_flight.iter().any(|m| m.deliver_at <= tick) {
+                self.step();
+            }
+            if tick <= self.now {
+                break;
+            }
+            self.advance_to(tick);
+            for replica in 0..self.nodes.len() {
+                if !self.crashed.contains(&replica) {
+                    self.collect_outputs(replica);
+                }
+            }
+        }
+    }
+
+    fn advance_to(&mut self, time: u64) {
+        self.now = time;
+        self.clock.set(time);
+    }
+
+    fn drop_reason(&self, message: &InFlight) -> Option<&'static str> {
+        if self.dropped.contains(&message.id) {
+            return Some("injected");
+        }
+        if self.crashed.contains(&message.to) {
+            return Some("receiver crashed");
+        }
+        if let (Some(from), Some(partition)) = (message.from, &self.partition) {
+            if partition[from] != partition[message.to] {
+                return Some("partitioned");
+            }
+        }
+        None
+    }
+
+    fn deliver(&mut self, message: InFlight) {
+        let to = message.to;
+        match message.payload {
+            Payload::ClientRequest(request) => {
+                self.nodes[to].pbft.add_client_request(request);
+            }
+            Payload::Request(request) => {
+                let from = message.from.expect("replica requests always have a sender");
+                let node = &mut self.nodes[to];
+                let connection_id = node.next_connection_id.next_id();
+                let event = message_to_handler_event(request.clone(), connection_id.clone());
+                node.pending_responses.insert(connection_id, (from, request));
+                let peer_id = self.nodes[from].peer_id;
+                self.nodes[to].pbft.process_handler_event(peer_id, event);
+            }
+            Payload::Response(request, response) => {
+                let from = message.from.expect("responses always have a sender");
+                let peer_id = self.nodes[from].peer_id;
+                self.nodes[to]
+                    .pbft
+                    .process_handler_event(peer_id, PbftHandlerEvent::Response { request, response });
+            }
+        }
+        self.collect_outputs(to);
+    }
+
+    fn collect_outputs(&mut self, replica: usize) {
+        let mut outgoing = Vec::new();
+        while let Some(action) = self.nodes[replica].pbft.pop_queued_event() {
+            match action {
+                NetworkBehaviourAction::NotifyHandler { peer_id, event, .. } => {
+                    match self.nodes.iter().position(|n| n.peer_id == peer_id) {
+                        Some(to) => outgoing.push((to, event)),
+                        None => eprintln!("[Simulator::collect_outputs] unknown peer: {:?}", peer_id),
+                    }
+                }
+                NetworkBehaviourAction::GenerateEvent(event) => {
+                    // As `NetworkBehaviourComposer` does
+                    if let PbftEvent::StateTransferNeeded { .. } = event {
+                        self.nodes[replica].pbft.start_state_transfer();
+                    }
+                    println!("[Simulator::collect_outputs] at: {}, replica: {}, event: {:?}", self.now, replica, event);
+                }
+                // The simulated cluster is fully connected from the start.
+                _ => {}
+            }
+        }
+
+        // `Pbft` iterates over a `HashSet` of peers, so the order of its outputs is not stable across
+        // processes. Sort them before drawing from the RNG to keep runs reproducible.
+        outgoing.sort_by_key(|(to, _)| *to);
+        for (to, event) in outgoing {
+            let payload = match event {
+                PbftHandlerIn::PrePrepareResponse(response, connection_id)
+                | PbftHandlerIn::PrepareResponse(response, connection_id)
+                | PbftHandlerIn::CommitResponse(response, connection_id)
+                | PbftHandlerIn::ForwardedResponse(response, connection_id)
+                | PbftHandlerIn::StateResponse(response, connection_id) => {
+                    match self.nodes[replica].pending_responses.remove(&connection_id) {
+                        Some((requester, request)) => {
+                            debug_assert_eq!(requester, to);
+                            Payload::Response(request, response)
+                        }
+                        None => continue,
+                    }
+                }
+                PbftHandlerIn::PrePrepareRequest(request)
+                    if self.equivocating.contains(&replica) && to >= self.nodes.len() / 2 =>
+                {
+                    Payload::Request(Message::PrePrepare(conflicting(&request)))
+                }
+                PbftHandlerIn::PrePrepareRequest(request) => Payload::Request(Message::PrePrepare(request)),
+                PbftHandlerIn::PrepareRequest(request) => Payload::Request(Message::Prepare(request)),
+                PbftHandlerIn::CommitRequest(request) => Payload::Request(Message::Commit(request)),
+                PbftHandlerIn::ForwardedRequest(request) => Payload::Request(Message::ClientRequest(request)),
+                PbftHandlerIn::StateRequest(request) => Payload::Request(Message::StateRequest(request)),
+                PbftHandlerIn::StateReply(request) => Payload::Request(Message::StateReply(request)),
+            };
+            self.send(Some(replica), to, payload);
+        }
+
+        for reply in self.nodes[replica].client_replies.write().unwrap().drain(..) {
+            self.replies.push(DeliveredReply { at: self.now, replica, reply });
+        }
+    }
+
+    fn send(&mut self, from: Option<usize>, to: usize, payload: Payload) {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+
+        if from.is_some_and(|f| self.crashed.contains(&f)) {
+            println!("[Simulator::send] at: {}, dropped id: {}, reason: sender crashed", self.now, id);
+            return;
+        }
+        if self.rng.gen_bool(self.config.drop_probability) {
+            println!("[Simulator::send] at: {}, dropped id: {}, reason: lost", self.now, id);
+            return;
+        }
+
+        let deliver_at = self.now + self.rng.gen_range(self.config.min_delay, self.config.max_delay + 1);
+        println!("[Simulator::send] at: {}, id: {}, from: {:?}, to: {}, deliver_at: {}", self.now, id, from, to, deliver_at);
+        self.in_flight.push(InFlight { id, deliver_at, from, to, payload });
+    }
+}
+
+impl SimNode {
+    fn new(keypair: Keypair, f: usize, members: &[PeerId], clock: &VirtualClock) -> Self {
+        let peer_id = PeerId::from_public_key(&keypair.public());
+        let client_replies = Arc::new(RwLock::new(VecDeque::new()));
+        Self {
+            pbft: Pbft::new(keypair.clone(), f, client_replies.clone())
+                .with_replicas(members.iter().copied())
+                .with_clock(Box::new(clock.clone())),
+            keypair,
+            peer_id,
+            client_replies,
+            next_connection_id: ConnectionId::new(),
+            pending_responses: HashMap::new(),
+        }
+    }
+}
+
+/// A clock that only moves when the simulator advances it.
+#[derive(Clone)]
+struct VirtualClock(Arc<AtomicU64>);
+
+impl VirtualClock {
+    fn new() -> Self {
+        Self(Arc::new(AtomicU64::new(0)))
+    }
+
+    fn set(&self, time: u64) {
+        self.0.store(time, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// A PrePrepare for the same view and sequence number as `pre_prepare`, but for another request
+fn conflicting(pre_prepare: &PrePrepare) -> PrePrepare {
+    let original = pre_prepare.client_reqeust();
+    let client_request = ClientRequest::new(
+        format!("PUT equivocated {}", pre_prepare.sequence_number()),
+        original.timestamp(),
+        original.client(),
+    );
+    let non_deterministic = NonDeterministicInputs::propose(
+        pre_prepare.non_deterministic().timestamp(),
+        pre_prepare.view(),
+        pre_prepare.sequence_number(),
+        &client_request,
+    );
+    PrePrepare::from(pre_prepare.view(), pre_prepare.sequence_number(), client_request, non_deterministic)
+}
+
+fn keypair_from_seed(seed: u64) -> Keypair {
+    let mut rng = SimRng::new(seed);
+    let mut bytes = [0u8; 32];
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&rng.next_u64().to_le_bytes());
+    }
+    let secret = ed25519::SecretKey::from_bytes(&mut bytes).expect("32 bytes is a valid secret key");
+    Keypair::Ed25519(ed25519::Keypair::from(secret))
+}
+
+/// SplitMix64. Small, fast and good enough for scheduling decisions; not for cryptography.
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `[low, high)`.
+    pub fn gen_range(&mut self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low;
+        }
+        low + self.next_u64() % (high - low)
+    }
+
+    pub fn gen_bool(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        (self.next_u64() as f64 / u64::MAX as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behavior::NonDeterminismBounds;
+
+    fn executed(simulator: &Simulator, replica: usize) -> Vec<u64> {
+        simulator.replica(replica).executed().iter().map(|(n, _)| *n).collect()
+    }
+
+    #[test]
+    fn normal_case() {
+        let mut simulator = Simulator::new(SimConfig::new(4, 1));
+        let primary = simulator.primary();
+        simulator.submit(primary, "SET a 1", 1);
+        simulator.run_until(1_000);
+
+        for replica in 0..simulator.replicas() {
+            assert_eq!(executed(&simulator, replica), vec![1], "replica {}", replica);
+        }
+        let mut repliers: Vec<usize> = simulator.replies().iter().map(|r| r.replica).collect();
+        repliers.sort_unstable();
+        assert_eq!(repliers, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn request_sent_to_backups() {
+        let mut simulator = Simulator::new(SimConfig::new(4, 5));
+        let primary = simulator.primary();
+        // A client broadcasting its request after a timeout: every backup forwards it to the primary
+        for backup in (0..simulator.replicas()).filter(|r| *r != primary) {
+            simulator.submit(backup, "SET a 1", 1);
+        }
+        simulator.run_until(1_000);
+
+        // Proposed once
+        for replica in 0..simulator.replicas() {
+            assert_eq!(executed(&simulator, replica), vec![1], "replica {}", replica);
+        }
+        assert_eq!(simulator.replies().len(), simulator.replicas());
+    }
+
+    #[test]
+    fn partitioned_primary() {
+        let mut simulator = Simulator::new(SimConfig::new(4, 2));
+        let primary = simulator.primary();
+        let backups: Vec<usize> = (0..simulator.replicas()).filter(|r| *r != primary).collect();
+        simulator.partition(&[&[primary], &backups]);
+        simulator.submit(primary, "SET a 1", 1);
+        simulator.run_until(1_000);
+
+        // Cut off from the backups, the primary can't gather a quorum
+        for replica in 0..simulator.replicas() {
+            assert!(executed(&simulator, replica).is_empty(), "replica {}", replica);
+        }
+        assert!(simulator.replies().is_empty());
+
+        // The unacknowledged messages are retransmitted once the partition heals
+        simulator.heal();
+        simulator.run_until(5_000);
+        for replica in 0..simulator.replicas() {
+            assert_eq!(executed(&simulator, replica), vec![1], "replica {}", replica);
+        }
+    }
+
+    #[test]
+    fn equivocating_primary() {
+        let mut simulator = Simulator::new(SimConfig::new(4, 4));
+        let primary = simulator.primary();
+        simulator.equivocate(primary);
+        simulator.submit(primary, "SET a 1", 1);
+        simulator.run_until(3_000);
+
+        // Two backups prepare the conflicting request and commit it, but the primary and the remaining backup
+        // reject those Commits. Neither request gathers 2f + 1 matching commits, so nothing is executed.
+        for replica in 0..simulator.replicas() {
+            assert!(executed(&simulator, replica).is_empty(), "replica {}", replica);
+        }
+        assert!(simulator.replies().is_empty());
+    }
+
+    #[test]
+    fn partition_longer_than_clock_skew() {
+        let mut simulator = Simulator::new(SimConfig::new(4, 6));
+        let primary = simulator.primary();
+        let backups: Vec<usize> = (0..simulator.replicas()).filter(|r| *r != primary).collect();
+        simulator.partition(&[&[primary], &backups]);
+        simulator.submit(primary, "SET a 1", 1);
+
+        // By the time the PrePrepare gets through, its timestamp is further behind the backups' clocks than
+        // `max_clock_skew`. It was only delayed, so they still accept it.
+        let max_clock_skew = NonDeterminismBounds::default().max_clock_skew;
+        simulator.run_until(max_clock_skew + 1_000);
+        simulator.heal();
+        simulator.run_until(max_clock_skew + 5_000);
+        for replica in 0..simulator.replicas() {
+            assert_eq!(executed(&simulator, replica), vec![1], "replica {}", replica);
+        }
+    }
+
+    #[test]
+    fn crashed_then_restarted_backup() {
+        let mut simulator = Simulator::new(SimConfig::new(4, 3));
+        let primary = simulator.primary();
+        let backup = (primary + 1) % simulator.replicas();
+        let initial_view = simulator.replica(backup).current_view();
+
+        // A Prepare that overtakes its PrePrepare is only counted once it is resent
+        simulator.submit(primary, "SET a 1", 1);
+        simulator.run_until(2_000);
+        assert_eq!(executed(&simulator, backup), vec![1]);
+
+        simulator.crash(backup);
+        simulator.submit(primary, "SET a 2", 2);
+        simulator.run_until(4_000);
+        for replica in (0..simulator.replicas()).filter(|r| *r != backup) {
+            assert_eq!(executed(&simulator, replica), vec![1, 2], "replica {}", replica);
+        }
+        assert_eq!(executed(&simulator, backup), vec![1]);
+
+        // Nothing survives the restart but the identity
+        simulator.restart(backup);
+        assert!(executed(&simulator, backup).is_empty());
+        assert_eq!(simulator.replica(backup).current_view(), initial_view);
+
+        // The messages of the second request are resent, but requests are executed in order and the first
+        // one is lost with the restart. Instead of skipping it, the backup fetches both from its peers.
+        simulator.run_until(8_000);
+        for replica in 0..simulator.replicas() {
+            assert_eq!(executed(&simulator, replica), vec![1, 2], "replica {}", replica);
+        }
+    }
+}
@@ -1,35 +1,52 @@
 use std::sync::{RwLock, Arc};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use crate::view::View;
-use crate::message::{PrePrepare, Prepare, Commit};
+use crate::message::{ClientRequest, PrePrepare, Prepare, Commit};
 use libp2p::PeerId;
+use std::net::SocketAddr;
+
+// The number of executed requests whose PrePrepare is kept to serve state transfers. A replica that falls
+// further behind can't catch up by state transfer.
+const EXECUTED_WINDOW: u64 = 4_096;
 
 pub struct State {
     current_view: Arc<RwLock<View>>,
-    pre_prepares: HashMap<PrePrepareKey, PrePrepare>,
-    prepares: HashMap<PrepareKey, HashMap<PeerId, Prepare>>,
-    commits: HashMap<CommitKey, HashMap<PeerId, Commit>>,
-    // The timestamp in the last reply this node sent to the client
-    last_timestamp: u64,
+    // The logs only hold the requests that are not executed yet. Everything up to the last executed sequence
+    // number is dropped as it executes.
+    pre_prepares: BTreeMap<PrePrepareKey, PrePrepare>,
+    prepares: BTreeMap<PrepareKey, HashMap<PeerId, Prepare>>,
+    commits: BTreeMap<CommitKey, HashMap<PeerId, Commit>>,
+    // The number of PrePrepares in the log for each request, by (client, timestamp)
+    pending: HashMap<(SocketAddr, u64), usize>,
+    // The timestamp in the last reply this node sent to each client
+    last_timestamps: HashMap<SocketAddr, u64>,
+    // The PrePrepares of the last `EXECUTED_WINDOW` requests executed by this node, by sequence number
+    executed: BTreeMap<u64, PrePrepare>,
+    executed_count: usize,
+    last_executed: u64,
 }
 
-#[derive(PartialEq, Eq, Hash)]
-struct PrePrepareKey(u64, u64); // (view, sequence_number)
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct PrePrepareKey(u64, u64); // (sequence_number, view)
 
-#[derive(PartialEq, Debug, Eq, Hash)]
-struct PrepareKey(u64, u64);// (view, sequence_number)
+#[derive(PartialEq, Debug, Eq, Hash, PartialOrd, Ord)]
+struct PrepareKey(u64, u64);// (sequence_number, view)
 
-#[derive(PartialEq, Eq, Hash)]
-struct CommitKey(u64); // view
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct CommitKey(u64, u64); // (sequence_number, view)
 
 impl State {
     pub fn new() -> Self {
         Self {
             current_view: Arc::new(RwLock::new(View::new())),
-            pre_prepares: HashMap::new(),
-            prepares: HashMap::new(),
-            commits: HashMap::new(),
-            last_timestamp: 0,
+            pre_prepares: BTreeMap::new(),
+            prepares: BTreeMap::new(),
+            commits: BTreeMap::new(),
+            pending: HashMap::new(),
+            last_timestamps: HashMap::new(),
+            executed: BTreeMap::new(),
+            executed_count: 0,
+            last_executed: 0,
         }
     }
 
@@ -40,54 +57,192 @@ impl State {
     pub fn insert_pre_prepare(&mut self, pre_prepare: PrePrepare) {
         println!("[State::insert_pre_prepare] The PrePrepare message has been stored into logs: {}", pre_prepare);
 
-        self.pre_prepares.insert(
-            PrePrepareKey(pre_prepare.view(), pre_prepare.sequence_number()),
+        let request = pending_key(&pre_prepare);
+        let replaced = self.pre_prepares.insert(
+            PrePrepareKey(pre_prepare.sequence_number(), pre_prepare.view()),
             pre_prepare
         );
+        *self.pending.entry(request).or_insert(0) += 1;
+        if let Some(replaced) = replaced {
+            self.forget_pending(&replaced);
+        }
     }
 
     pub fn insert_prepare(&mut self, peer_id: PeerId, prepare: Prepare) {
         println!("[State::insert_prepare] The Prepare message has been stored into logs: {}", prepare);
 
-        let key = PrepareKey(prepare.view(), prepare.sequence_number());
+        let key = PrepareKey(prepare.sequence_number(), prepare.view());
         let p = self.prepares
             .entry(key)
-            .or_insert(HashMap::new());
+            .or_default();
         p.insert(peer_id, prepare);
     }
 
     pub fn insert_commit(&mut self, peer_id: PeerId, commit: Commit) {
         println!("[State::insert_commit] The Commit message has been stored into logs: {}", commit);
 
-        let key = CommitKey(commit.view());
+        let key = CommitKey(commit.sequence_number(), commit.view());
         let c = self.commits
             .entry(key)
-            .or_insert(HashMap::new());
+            .or_default();
         c.insert(peer_id, commit);
     }
 
-    pub fn prepare_len(&self, view: u64, sequence_number: u64) -> usize {
-        self.prepares.get(&PrepareKey(view, sequence_number)).unwrap().len()
+    /// The number of Prepares for (view, sequence_number) sent by replicas other than `primary`.
+    pub fn prepare_len(&self, view: u64, sequence_number: u64, primary: &PeerId) -> usize {
+        self.prepares
+            .get(&PrepareKey(sequence_number, view))
+            .map_or(0, |p| p.keys().filter(|peer_id| *peer_id != primary).count())
     }
 
-    pub fn commit_len(&self, view: u64) -> usize {
-        self.commits.get(&CommitKey(view)).unwrap().len()
+    /// The number of Commits for (view, sequence_number) that carry `digest`.
+    pub fn commit_len(&self, view: u64, sequence_number: u64, digest: &str) -> usize {
+        self.commits
+            .get(&CommitKey(sequence_number, view))
+            .map_or(0, |c| c.values().filter(|commit| commit.digest() == digest).count())
+    }
+
+    pub fn has_commit(&self, peer_id: &PeerId, view: u64, sequence_number: u64) -> bool {
+        self.commits.get(&CommitKey(sequence_number, view)).is_some_and(|c| c.contains_key(peer_id))
     }
 
     pub fn get_pre_prepare(&self, pre_prepare: &PrePrepare) -> Option<&PrePrepare> {
-        self.pre_prepares.get(&PrePrepareKey(pre_prepare.view(), pre_prepare.sequence_number()))
+        self.pre_prepares.get(&PrePrepareKey(pre_prepare.sequence_number(), pre_prepare.view()))
     }
 
     pub fn get_pre_prepare_by_key(&self, view: u64, sequence_number: u64) -> Option<&PrePrepare> {
-        self.pre_prepares.get(&PrePrepareKey(view, sequence_number))
+        self.pre_prepares.get(&PrePrepareKey(sequence_number, view))
+    }
+
+    pub fn last_timestamp(&self, client: &SocketAddr) -> u64 {
+        self.last_timestamps.get(client).copied().unwrap_or(0)
+    }
+
+    /// (sequence_number, digest) of the requests executed by this node that are still kept, in execution order.
+    #[cfg(test)]
+    pub fn executed(&self) -> Vec<(u64, String)> {
+        self.executed.iter().map(|(n, p)| (*n, p.digest().clone())).collect()
+    }
+
+    /// The number of requests executed by this node.
+    pub fn executed_count(&self) -> usize {
+        self.executed_count
+    }
+
+    pub fn last_executed(&self) -> u64 {
+        self.last_executed
+    }
+
+    /// The PrePrepares of the requests executed after `after`, in execution order, at most `limit` of them.
+    pub fn executed_pre_prepares(&self, after: u64, limit: usize) -> Vec<PrePrepare> {
+        self.executed
+            .range(after + 1..)
+            .take(limit)
+            .map(|(_, pre_prepare)| pre_prepare.clone())
+            .collect()
+    }
+
+    /// Moves the request out of the logs, along with everything else up to its sequence number.
+    pub fn record_execution(&mut self, pre_prepare: PrePrepare) {
+        let sequence_number = pre_prepare.sequence_number();
+        println!("[State::record_execution] sequence_number: {}, digest: {}", sequence_number, pre_prepare.digest());
+
+        let pre_prepares = self.pre_prepares.split_off(&PrePrepareKey(sequence_number + 1, 0));
+        for (_, dropped) in std::mem::replace(&mut self.pre_prepares, pre_prepares) {
+            self.forget_pending(&dropped);
+        }
+        self.prepares = self.prepares.split_off(&PrepareKey(sequence_number + 1, 0));
+        self.commits = self.commits.split_off(&CommitKey(sequence_number + 1, 0));
+
+        self.executed.insert(sequence_number, pre_prepare);
+        if sequence_number > EXECUTED_WINDOW {
+            self.executed = self.executed.split_off(&(sequence_number - EXECUTED_WINDOW + 1));
+        }
+        self.executed_count += 1;
+        self.last_executed = sequence_number;
     }
 
-    pub fn last_timestamp(&self) -> u64 {
-        self.last_timestamp
+    pub fn update_last_timestamp(&mut self, client: SocketAddr, timestamp: u64) {
+        let last_timestamp = self.last_timestamps.entry(client).or_insert(0);
+        println!("[State::update_last_timestamp] updated the timestamp of {} from {:?} to {:?}", client, last_timestamp, timestamp);
+        *last_timestamp = timestamp;
     }
 
-    pub fn update_last_timestamp(&mut self, timestamp: u64) {
-        println!("[State::update_last_timestamp] updated the timestamp from {:?} to {:?}", self.last_timestamp, timestamp);
-        self.last_timestamp = timestamp;
+    /// Whether the request has been pre-prepared and is not executed yet.
+    pub fn is_pending(&self, client_request: &ClientRequest) -> bool {
+        self.pending.contains_key(&(client_request.client(), client_request.timestamp()))
     }
-}
\ No newline at end of file
+
+    fn forget_pending(&mut self, pre_prepare: &PrePrepare) {
+        let request = pending_key(pre_prepare);
+        if let Some(count) = self.pending.get_mut(&request) {
+            *count -= 1;
+            if *count == 0 {
+                self.pending.remove(&request);
+            }
+        }
+    }
+}
+
+fn pending_key(pre_prepare: &PrePrepare) -> (SocketAddr, u64) {
+    let client_request = pre_prepare.client_reqeust();
+    (client_request.client(), client_request.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::NonDeterministicInputs;
+
+    const VIEW: u64 = 1;
+
+    fn pre_prepare(sequence_number: u64) -> PrePrepare {
+        let client_request = ClientRequest::new(format!("PUT x {}", sequence_number), sequence_number, "127.0.0.1:9000".parse().unwrap());
+        let non_deterministic = NonDeterministicInputs::propose(0, VIEW, sequence_number, &client_request);
+        PrePrepare::from(VIEW, sequence_number, client_request, non_deterministic)
+    }
+
+    #[test]
+    fn execution_prunes_the_logs() {
+        let mut state = State::new();
+        let peer_id = PeerId::random();
+        let primary = PeerId::random();
+        let pre_prepares: Vec<PrePrepare> = (1..=3).map(pre_prepare).collect();
+        for p in pre_prepares.iter() {
+            state.insert_pre_prepare(p.clone());
+            state.insert_prepare(peer_id, Prepare::from(p));
+            state.insert_commit(peer_id, Prepare::from(p).into());
+        }
+
+        state.record_execution(pre_prepares[0].clone());
+        state.record_execution(pre_prepares[1].clone());
+
+        for n in 1..=2 {
+            assert!(state.get_pre_prepare_by_key(VIEW, n).is_none());
+            assert_eq!(state.prepare_len(VIEW, n, &primary), 0);
+            assert!(!state.has_commit(&peer_id, VIEW, n));
+            assert!(!state.is_pending(pre_prepares[n as usize - 1].client_reqeust()));
+        }
+        assert!(state.get_pre_prepare_by_key(VIEW, 3).is_some());
+        assert_eq!(state.prepare_len(VIEW, 3, &primary), 1);
+        assert!(state.has_commit(&peer_id, VIEW, 3));
+        assert!(state.is_pending(pre_prepares[2].client_reqeust()));
+
+        let executed: Vec<u64> = state.executed_pre_prepares(0, 10).iter().map(|p| p.sequence_number()).collect();
+        assert_eq!(executed, vec![1, 2]);
+        assert_eq!(state.last_executed(), 2);
+    }
+
+    #[test]
+    fn executed_window() {
+        let mut state = State::new();
+        for n in 1..=EXECUTED_WINDOW + 2 {
+            state.record_execution(pre_prepare(n));
+        }
+        let executed = state.executed_pre_prepares(0, usize::MAX);
+        assert_eq!(executed.len() as u64, EXECUTED_WINDOW);
+        assert_eq!(executed[0].sequence_number(), 3);
+        assert_eq!(state.executed_count() as u64, EXECUTED_WINDOW + 2);
+        assert_eq!(state.last_executed(), EXECUTED_WINDOW + 2);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::message::NonDeterministicInputs;
+use std::collections::HashMap;
+
+/// The replicated service. Replicas execute committed operations in sequence number order, so `execute`
+/// must be deterministic. Anything else an operation needs, such as the time or a random seed, has to come
+/// from the values the replicas agreed on in the `PrePrepare`.
+pub trait StateMachine {
+    fn execute(&mut self, operation: &str, non_deterministic: &NonDeterministicInputs) -> String;
+}
+
+/// An operation on the key-value store: `GET <key>`, `PUT <key> <value>` or `DELETE <key>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KvCommand {
+    Get(String),
+    Put(String, String),
+    Delete(String),
+}
+
+impl KvCommand {
+    pub fn parse(operation: &str) -> Result<Self, String> {
+        let mut parts = operation.trim().splitn(3, ' ');
+        let command = parts.next().unwrap_or("");
+        let key = parts.next();
+        let value = parts.next();
+
+        match (command.to_uppercase().as_str(), key, value) {
+            ("GET", Some(key), None) => Ok(KvCommand::Get(key.to_owned())),
+            ("PUT", Some(key), Some(value)) => Ok(KvCommand::Put(key.to_owned(), value.to_owned())),
+            ("DELETE", Some(key), None) => Ok(KvCommand::Delete(key.to_owned())),
+            _ => Err(format!("invalid operation: {:?}", operation)),
+        }
+    }
+
+    pub fn key(&self) -> &String {
+        match self {
+            KvCommand::Get(key) | KvCommand::Put(key, _) | KvCommand::Delete(key) => key,
+        }
+    }
+
+    /// Applies the command to the value currently stored under its key. Returns the result sent to the client.
+    pub fn apply(&self, value: &mut Option<String>) -> String {
+        match self {
+            KvCommand::Get(_) => value.clone().unwrap_or_else(|| NOT_FOUND.to_owned()),
+            KvCommand::Put(_, new_value) => {
+                *value = Some(new_value.clone());
+                OK.to_owned()
+            }
+            KvCommand::Delete(_) => {
+                *value = None;
+                OK.to_owned()
+            }
+        }
+    }
+}
+
+pub const OK: &str = "OK";
+pub const NOT_FOUND: &str = "NOT_FOUND";
+
+#[derive(Default)]
+pub struct KeyValueStore {
+    data: HashMap<String, String>,
+}
+
+impl KeyValueStore {
+    pub fn new() -> Self {
+        Self { data: HashMap::new() }
+    }
+}
+
+impl StateMachine for KeyValueStore {
+    fn execute(&mut self, operation: &str, _non_deterministic: &NonDeterministicInputs) -> String {
+        let command = match KvCommand::parse(operation) {
+            Ok(command) => command,
+            Err(e) => return format!("ERROR: {}", e),
+        };
+
+        let mut value = self.data.get(command.key()).cloned();
+        let result = command.apply(&mut value);
+        match value {
+            Some(value) => self.data.insert(command.key().clone(), value),
+            None => self.data.remove(command.key()),
+        };
+        println!("[KeyValueStore::execute] operation: {:?}, result: {:?}", operation, result);
+        result
+    }
+}
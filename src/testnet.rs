@@ -0,0 +1,112 @@
+use crate::config::{
+    BatchingConfig, ClientConfig, ClusterConfig, ClusterNode, LoggingConfig, NodeConfig, NonDeterminismConfig, PeerConfig,
+    PrimarySelectionConfig, ProtocolConfig, TimeoutsConfig, TransportConfig,
+};
+use crate::keystore;
+use std::path::{Path, PathBuf};
+
+pub struct TestnetOptions {
+    pub replicas: usize,
+    pub faults: usize,
+    pub out: PathBuf,
+    pub host: String,
+    // Replica _i_ listens on `base_port + i` for replicas and on `base_client_port + i` for clients
+    pub base_port: u16,
+    pub base_client_port: u16,
+}
+
+impl TestnetOptions {
+    pub fn new(replicas: usize, faults: usize, out: PathBuf) -> Self {
+        Self {
+            replicas,
+            faults,
+            out,
+            host: "127.0.0.1".to_owned(),
+            base_port: 7000,
+            base_client_port: 8000,
+        }
+    }
+}
+
+/// Writes everything needed to run a local cluster into `options.out`:
+///
+/// - `cluster.json`, the membership of the cluster,
+/// - `node-<i>/identity.key`, the identity of replica _i_,
+/// - `node-<i>/node.toml`, the configuration of replica _i_.
+pub fn generate(options: &TestnetOptions, passphrase: Option<&str>) -> Result<ClusterConfig, Box<dyn std::error::Error>> {
+    if options.replicas < 3 * options.faults + 1 {
+        return Err(format!(
+            "{} replicas can't tolerate {} faults, at least {} are needed",
+            options.replicas,
+            options.faults,
+            3 * options.faults + 1
+        )
+        .into());
+    }
+    let last_port = options.base_port as usize + options.replicas - 1;
+    let last_client_port = options.base_client_port as usize + options.replicas - 1;
+    if last_port > u16::MAX as usize || last_client_port > u16::MAX as usize {
+        return Err("the ports of the replicas don't fit in the port range".into());
+    }
+
+    std::fs::create_dir_all(&options.out)?;
+
+    let mut nodes = Vec::with_capacity(options.replicas);
+    for index in 0..options.replicas {
+        let dir = node_dir(&options.out, index);
+        std::fs::create_dir_all(&dir)?;
+        let keypair = keystore::generate(&dir.join("identity.key"), passphrase)?;
+
+        nodes.push(ClusterNode {
+            index,
+            peer_id: keystore::peer_id(&keypair).to_string(),
+            public_key: keystore::public_key_hex(&keypair),
+            address: format!("/ip4/{}/tcp/{}", options.host, options.base_port as usize + index),
+            client_port: (options.base_client_port as usize + index) as u16,
+        });
+    }
+    let cluster = ClusterConfig {
+        f: options.faults,
+        nodes,
+        primary_selection: PrimarySelectionConfig::default(),
+    };
+    std::fs::write(options.out.join("cluster.json"), serde_json::to_string_pretty(&cluster)?)?;
+
+    for node in cluster.nodes.iter() {
+        let config = NodeConfig {
+            index: node.index,
+            // Relative to the config file, so that the directory can be moved around
+            key_file: PathBuf::from("identity.key"),
+            listen: vec![node.address.clone()],
+            client_port: node.client_port,
+            f: cluster.f,
+            peers: cluster
+                .nodes
+                .iter()
+                .filter(|peer| peer.index != node.index)
+                .map(|peer| PeerConfig {
+                    peer_id: peer.peer_id.clone(),
+                    address: peer.address.clone(),
+                })
+                .collect(),
+            client: ClientConfig::default(),
+            timeouts: TimeoutsConfig::default(),
+            batching: BatchingConfig::default(),
+            non_determinism: NonDeterminismConfig::default(),
+            transport: TransportConfig::default(),
+            protocol: ProtocolConfig::default(),
+            logging: LoggingConfig {
+                execution_log: Some(PathBuf::from("execution.log")),
+            },
+            primary_selection: cluster.primary_selection.clone(),
+        };
+        std::fs::write(node_dir(&options.out, node.index).join("node.toml"), toml::to_string(&config)?)?;
+    }
+
+    println!("[testnet::generate] The testnet has been written to {:?}", options.out);
+    Ok(cluster)
+}
+
+pub fn node_dir(out: &Path, index: usize) -> PathBuf {
+    out.join(format!("node-{}", index))
+}
@@ -0,0 +1,121 @@
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::timeout::TransportTimeout;
+use libp2p::core::transport::Boxed;
+use libp2p::core::upgrade;
+use libp2p::dns::TokioDnsConfig;
+use libp2p::identity::Keypair;
+use libp2p::noise;
+use libp2p::tcp::TokioTcpConfig;
+use libp2p::yamux::YamuxConfig;
+use libp2p::{PeerId, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// The Diffie-Hellman keys used by the Noise handshake. Every replica of a cluster has to use the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoiseKeyType {
+    /// X25519 keys authenticated as per the libp2p Noise specification
+    #[default]
+    X25519Spec,
+    /// The legacy X25519 authentication of older libp2p versions
+    X25519,
+}
+
+#[derive(Clone, Debug)]
+pub struct TransportConfig {
+    pub noise: NoiseKeyType,
+    pub allow_list: AllowList,
+    pub timeout: Duration,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            noise: NoiseKeyType::default(),
+            allow_list: AllowList::any(),
+            timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+/// The replicas allowed to connect to this node, by PeerId. An ed25519 PeerId embeds the public key it was
+/// derived from, so an entry pins the replica's key as well. Listing public keys on their own is not supported.
+#[derive(Clone, Debug)]
+pub struct AllowList {
+    // `None` allows any peer, which is only meant for local development
+    peers: Option<HashSet<PeerId>>,
+}
+
+impl AllowList {
+    pub fn any() -> Self {
+        Self { peers: None }
+    }
+
+    pub fn peers(peers: impl IntoIterator<Item = PeerId>) -> Self {
+        Self { peers: Some(peers.into_iter().collect()) }
+    }
+
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        match &self.peers {
+            Some(peers) => peers.contains(peer_id),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NotAllowed(PeerId);
+
+impl std::fmt::Display for NotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the peer is not in the allow-list: {}", self.0)
+    }
+}
+
+impl std::error::Error for NotAllowed {}
+
+/// TCP, encrypted and authenticated with Noise, multiplexed with Yamux. Connections are dropped right after
+/// the handshake when the remote's PeerId is not in the allow-list, before any protocol runs on them.
+pub fn build(keypair: &Keypair, config: &TransportConfig) -> std::io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let tcp = TokioDnsConfig::system(TokioTcpConfig::new().nodelay(true))?;
+
+    let authenticated = match config.noise {
+        NoiseKeyType::X25519Spec => {
+            let keys = noise::Keypair::<noise::X25519Spec>::new()
+                .into_authentic(keypair)
+                .map_err(std::io::Error::other)?;
+            tcp.upgrade(upgrade::Version::V1)
+                .authenticate(noise::NoiseConfig::xx(keys).into_authenticated())
+                .multiplex(YamuxConfig::default())
+                .boxed()
+        }
+        NoiseKeyType::X25519 => {
+            let keys = noise::Keypair::<noise::X25519>::new()
+                .into_authentic(keypair)
+                .map_err(std::io::Error::other)?;
+            tcp.upgrade(upgrade::Version::V1)
+                .authenticate(noise::NoiseConfig::xx(keys).into_authenticated())
+                .multiplex(YamuxConfig::default())
+                .boxed()
+        }
+    };
+
+    let allow_list = config.allow_list.clone();
+    let allowed = authenticated
+        .and_then(move |(peer_id, muxer), endpoint| {
+            let result = if allow_list.is_allowed(&peer_id) {
+                Ok((peer_id, muxer))
+            } else {
+                eprintln!(
+                    "[transport::build] Rejected a connection from a peer outside of the allow-list. peer_id: {:?}, endpoint: {:?}",
+                    peer_id, endpoint
+                );
+                Err(NotAllowed(peer_id))
+            };
+            futures::future::ready(result)
+        })
+        .boxed();
+    Ok(TransportTimeout::new(allowed, config.timeout).boxed())
+}